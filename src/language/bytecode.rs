@@ -0,0 +1,484 @@
+//! Compiled functions saved to `.tsbc` files, to be run later without the source.
+//!
+//! A file starts with a header: the magic bytes `TSBC`, the format version, the length of
+//! the rest of the file and its CRC-32. The rest is the table of every string the code
+//! refers to, followed by the script function. A function is its name, arity, flags and
+//! captures, then its chunk: the code, the constant pool and the position runs. Nested
+//! functions are written in place, as the constants of the function declaring them.
+//!
+//! Interned ids only mean something to the VM that handed them out, so names in the code
+//! are written as indices into the string table, and interned again when loading.
+//! Integers are written as variable-length integers, like operands in the code.
+
+use super::chunk::{Chunk, Position};
+use super::common::{intern, string, StrId};
+use super::object::{Capture, Function};
+use super::string::JsString;
+use super::value::Value;
+use super::verifier::{verify, VerifyError};
+use rustc_hash::FxHashMap;
+use std::{convert::TryFrom, fmt, rc::Rc};
+
+const MAGIC: &[u8; 4] = b"TSBC";
+pub const VERSION: u16 = 7;
+// Magic, version, payload length and checksum
+const HEADER_LEN: usize = 4 + 2 + 4 + 4;
+// Deeper than any function the compiler would get through
+const MAX_NESTING: usize = 1024;
+
+// Tags of the constants
+const NUMBER: u8 = 0;
+const STRING: u8 = 1;
+const FUNCTION: u8 = 2;
+
+// Bits of the function flags
+const HAS_REST: u8 = 1;
+const BINDS_THIS: u8 = 2;
+
+/// Why a file couldn't be loaded
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    Truncated,
+    ChecksumMismatch,
+    Corrupted(&'static str),
+    Invalid(VerifyError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "not a bytecode file"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "bytecode version {} is not supported, expected version {}",
+                version, VERSION
+            ),
+            LoadError::Truncated => write!(f, "file is truncated"),
+            LoadError::ChecksumMismatch => write!(f, "checksum mismatch, file is corrupted"),
+            LoadError::Corrupted(reason) => write!(f, "file is corrupted: {}", reason),
+            LoadError::Invalid(error) => write!(f, "invalid bytecode in {}", error),
+        }
+    }
+}
+
+/// The file holding `function`, which must be the script the compiler returned
+pub fn serialize(function: &Function) -> Vec<u8> {
+    let mut strings = StringTable::default();
+    let mut body = Vec::new();
+    write_function(&mut body, &mut strings, function);
+
+    let mut payload = Vec::new();
+    write_varint(&mut payload, strings.strings.len());
+    for string in strings.strings.iter() {
+        write_varint(&mut payload, string.len());
+        payload.extend_from_slice(string.as_bytes());
+    }
+    payload.extend(body);
+
+    let mut file = Vec::with_capacity(HEADER_LEN + payload.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&VERSION.to_le_bytes());
+    file.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    file.extend_from_slice(&checksum(&payload).to_le_bytes());
+    file.extend(payload);
+    file
+}
+
+/// The script function saved in `bytes`, with its names interned in the current interner.
+/// The code is verified before it's handed out, as the file may not come from this compiler
+pub fn load(bytes: &[u8]) -> Result<Rc<Function>, LoadError> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(LoadError::NotBytecode);
+    }
+    let mut header = Reader::new(&bytes[MAGIC.len()..]);
+    let version = u16::from_le_bytes([header.byte()?, header.byte()?]);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let len = header.u32()? as usize;
+    let expected = header.u32()?;
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() < len {
+        return Err(LoadError::Truncated);
+    }
+    if payload.len() > len {
+        return Err(LoadError::Corrupted("unexpected bytes after the end"));
+    }
+    if checksum(payload) != expected {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader::new(payload);
+    let mut ids = Vec::new();
+    for _ in 0..reader.varint()? {
+        let len = reader.varint()?;
+        let text = std::str::from_utf8(reader.bytes(len)?)
+            .map_err(|_| LoadError::Corrupted("string is not valid UTF-8"))?;
+        ids.push(intern(text));
+    }
+    let function = reader.function(&ids, 0)?;
+    if !reader.is_at_end() {
+        return Err(LoadError::Corrupted("unexpected bytes after the script"));
+    }
+    // The script is run as a closure of its own, without arguments or upvalues
+    let is_script = function.name.is_none()
+        && function.arity == 0
+        && !function.has_rest
+        && !function.binds_this
+        && function.captures.is_empty();
+    if !is_script {
+        return Err(LoadError::Corrupted(
+            "the outermost function is not a script",
+        ));
+    }
+    verify(&function).map_err(LoadError::Invalid)?;
+    Ok(Rc::new(function))
+}
+
+// Strings in the order they were first written, each only once
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<JsString>,
+    indices: FxHashMap<JsString, usize>,
+}
+
+impl StringTable {
+    fn index(&mut self, string: JsString) -> usize {
+        if let Some(index) = self.indices.get(&string) {
+            return *index;
+        }
+        self.strings.push(string.clone());
+        self.indices.insert(string, self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+}
+
+fn write_function(out: &mut Vec<u8>, strings: &mut StringTable, function: &Function) {
+    let name = function
+        .name
+        .map_or(0, |name| strings.index(string(name)) + 1);
+    write_varint(out, name);
+    write_varint(out, function.arity);
+    let mut flags = 0;
+    if function.has_rest {
+        flags |= HAS_REST;
+    }
+    if function.binds_this {
+        flags |= BINDS_THIS;
+    }
+    out.push(flags);
+    write_varint(out, function.captures.len());
+    for capture in function.captures.iter() {
+        out.push(capture.is_local as u8);
+        write_varint(out, capture.index);
+    }
+    write_chunk(out, strings, &function.chunk);
+}
+
+fn write_chunk(out: &mut Vec<u8>, strings: &mut StringTable, chunk: &Chunk) {
+    let code = chunk
+        .map_names(|name| Some(StrId::from_u32(strings.index(string(name)) as u32)))
+        .expect("compiled code always decodes");
+    write_varint(out, code.code.len());
+    out.extend_from_slice(&code.code);
+
+    write_varint(out, chunk.constants.len());
+    for constant in chunk.constants.iter() {
+        match constant {
+            Value::ValNumber(number) => {
+                out.push(NUMBER);
+                out.extend_from_slice(&number.to_bits().to_le_bytes());
+            }
+            Value::String(text) => {
+                out.push(STRING);
+                write_varint(out, strings.index(text.clone()));
+            }
+            Value::Function(function) => {
+                out.push(FUNCTION);
+                write_function(out, strings, function);
+            }
+            _ => unreachable!("only numbers, strings and functions are compiled to constants"),
+        }
+    }
+
+    let runs: Vec<_> = code.positions.runs().collect();
+    write_varint(out, runs.len());
+    for (offset, position) in runs {
+        write_varint(out, offset);
+        write_varint(out, position.line as usize);
+        write_varint(out, position.column as usize);
+        write_varint(out, position.start as usize);
+        write_varint(out, position.length as usize);
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// CRC-32, as used by zip and PNG
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, offset: 0 }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.offset == self.bytes.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.offset.checked_add(len).ok_or(LoadError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or(LoadError::Truncated)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn varint(&mut self) -> Result<usize, LoadError> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+            if shift >= usize::BITS {
+                return Err(LoadError::Corrupted("integer out of range"));
+            }
+        }
+    }
+
+    fn u32_varint(&mut self) -> Result<u32, LoadError> {
+        u32::try_from(self.varint()?).map_err(|_| LoadError::Corrupted("integer out of range"))
+    }
+
+    fn string(&mut self, ids: &[StrId]) -> Result<StrId, LoadError> {
+        let index = self.varint()?;
+        ids.get(index)
+            .copied()
+            .ok_or(LoadError::Corrupted("string index out of range"))
+    }
+
+    fn function(&mut self, ids: &[StrId], depth: usize) -> Result<Function, LoadError> {
+        if depth > MAX_NESTING {
+            return Err(LoadError::Corrupted("functions are nested too deep"));
+        }
+        let name = match self.varint()? {
+            0 => None,
+            index => Some(
+                *ids.get(index - 1)
+                    .ok_or(LoadError::Corrupted("string index out of range"))?,
+            ),
+        };
+        let mut function = Function::new(name);
+        function.arity = self.varint()?;
+        let flags = self.byte()?;
+        if flags & !(HAS_REST | BINDS_THIS) != 0 {
+            return Err(LoadError::Corrupted("unknown function flags"));
+        }
+        function.has_rest = flags & HAS_REST != 0;
+        function.binds_this = flags & BINDS_THIS != 0;
+        for _ in 0..self.varint()? {
+            let is_local = match self.byte()? {
+                0 => false,
+                1 => true,
+                _ => return Err(LoadError::Corrupted("invalid capture")),
+            };
+            let index = self.varint()?;
+            function.captures.push(Capture { is_local, index });
+        }
+        function.chunk = self.chunk(ids, depth)?;
+        Ok(function)
+    }
+
+    fn chunk(&mut self, ids: &[StrId], depth: usize) -> Result<Chunk, LoadError> {
+        let mut chunk = Chunk::new();
+        let len = self.varint()?;
+        chunk.code = self.bytes(len)?.to_vec();
+
+        for _ in 0..self.varint()? {
+            let constant = match self.byte()? {
+                NUMBER => {
+                    let bytes = self.bytes(8)?;
+                    let mut bits = [0; 8];
+                    bits.copy_from_slice(bytes);
+                    Value::ValNumber(f64::from_bits(u64::from_le_bytes(bits)))
+                }
+                STRING => Value::String(string(self.string(ids)?)),
+                FUNCTION => Value::Function(Rc::new(self.function(ids, depth + 1)?)),
+                _ => return Err(LoadError::Corrupted("unknown constant")),
+            };
+            chunk.constants.push(constant);
+        }
+
+        let mut previous = None;
+        for _ in 0..self.varint()? {
+            let offset = self.varint()?;
+            let position = Position {
+                line: self.u32_varint()?,
+                column: self.u32_varint()?,
+                start: self.u32_varint()?,
+                length: self.u32_varint()?,
+            };
+            let in_order = match previous {
+                None => offset == 0,
+                Some(previous) => offset > previous,
+            };
+            if !in_order || offset >= chunk.code.len() {
+                return Err(LoadError::Corrupted("invalid source positions"));
+            }
+            chunk.positions.add(offset, position);
+            previous = Some(offset);
+        }
+        if previous.is_none() && !chunk.code.is_empty() {
+            return Err(LoadError::Corrupted("missing source positions"));
+        }
+
+        chunk
+            .map_names(|index| ids.get(index.to_u32() as usize).copied())
+            .ok_or(LoadError::Corrupted("invalid instructions"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::vm::VM;
+
+    fn compiled(vm: &mut VM) -> Vec<u8> {
+        let function = vm.compile("let a = [1, 2]; print a.join();").unwrap();
+        vm.serialize(&function)
+    }
+
+    // A file around `payload`, with a header that matches it
+    fn sealed(payload: &[u8]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&VERSION.to_le_bytes());
+        file.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        file.extend_from_slice(&checksum(payload).to_le_bytes());
+        file.extend_from_slice(payload);
+        file
+    }
+
+    #[test]
+    fn loads_what_it_saved() {
+        let mut vm = VM::new();
+        let file = compiled(&mut vm);
+        let function = vm.load(&file).unwrap();
+        assert!(vm.execute(function).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut vm = VM::new();
+        assert_eq!(vm.load(b"").err(), Some(LoadError::NotBytecode));
+        assert_eq!(vm.load(b"print 1;").err(), Some(LoadError::NotBytecode));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut vm = VM::new();
+        let mut file = compiled(&mut vm);
+        file[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            vm.load(&file).err(),
+            Some(LoadError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let mut vm = VM::new();
+        let file = compiled(&mut vm);
+        for len in [MAGIC.len(), HEADER_LEN - 1, HEADER_LEN, file.len() - 1] {
+            assert_eq!(vm.load(&file[..len]).err(), Some(LoadError::Truncated));
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_files() {
+        let mut vm = VM::new();
+        let file = compiled(&mut vm);
+
+        let mut flipped = file.clone();
+        *flipped.last_mut().unwrap() ^= 0xff;
+        assert_eq!(vm.load(&flipped).err(), Some(LoadError::ChecksumMismatch));
+
+        let mut extended = file.clone();
+        extended.push(0);
+        assert_eq!(
+            vm.load(&extended).err(),
+            Some(LoadError::Corrupted("unexpected bytes after the end"))
+        );
+
+        // Damage that the checksum doesn't catch, as it was written along with it
+        let mut payload = file[HEADER_LEN..].to_vec();
+        payload.push(0);
+        assert_eq!(
+            vm.load(&sealed(&payload)).err(),
+            Some(LoadError::Corrupted("unexpected bytes after the script"))
+        );
+        assert_eq!(
+            vm.load(&sealed(&[1, 1, 0xff])).err(),
+            Some(LoadError::Corrupted("string is not valid UTF-8"))
+        );
+        assert_eq!(
+            vm.load(&sealed(&[1, 5, b'a'])).err(),
+            Some(LoadError::Truncated)
+        );
+    }
+
+    #[test]
+    fn rejects_outermost_functions_other_than_scripts() {
+        let mut vm = VM::new();
+        let script = vm.compile("print 1;").unwrap();
+        let mut function = Rc::try_unwrap(script).ok().unwrap();
+        // Nothing encloses the script for it to capture from
+        function.captures.push(Capture {
+            is_local: true,
+            index: 0,
+        });
+        let file = vm.serialize(&function);
+        assert_eq!(
+            vm.load(&file).err(),
+            Some(LoadError::Corrupted(
+                "the outermost function is not a script"
+            ))
+        );
+    }
+}
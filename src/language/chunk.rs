@@ -1,23 +1,592 @@
-use super::common::OpCode;
+//! Bytecode: each instruction is a byte, followed by its operand if it has one.
+//!
+//! Constants, names and counts are written as variable-length integers, seven bits per
+//! byte with the high bit set on all but the last, so small ones take a single byte.
+//! Jump offsets take a fixed two bytes, so jumps can be patched once their target is
+//! known. Numbers and strings go to the constant pool, each distinct value only once,
+//! and are loaded with `CONSTANT`, or `CONSTANT_LONG` past the first 256.
+//!
+//! Source positions are kept apart from the code, as runs of instructions that came from
+//! the same token, so looking one up doesn't cost memory on every byte.
 
+use super::common::{OpCode, StrId};
+use super::string::JsString;
+use super::value::Value;
+use rustc_hash::FxHashMap;
+use std::{convert::TryFrom, fmt, iter};
+
+pub const MAX_CONSTANTS: usize = 1 << 24;
+pub const MAX_JUMP: usize = u16::MAX as usize;
+
+// Byte values of the instructions
+pub mod op {
+    pub const NULL: u8 = 0;
+    pub const UNDEFINED: u8 = 1;
+    pub const CONSTANT: u8 = 2;
+    pub const CONSTANT_LONG: u8 = 3;
+    pub const ADD: u8 = 4;
+    pub const SUBTRACT: u8 = 5;
+    pub const MULTIPLY: u8 = 6;
+    pub const DIVIDE: u8 = 7;
+    pub const NEGATE: u8 = 8;
+    pub const RETURN: u8 = 9;
+    pub const TRUE: u8 = 10;
+    pub const FALSE: u8 = 11;
+    pub const NOT: u8 = 12;
+    pub const POP: u8 = 13;
+    pub const EQUAL: u8 = 14;
+    pub const GREATER: u8 = 15;
+    pub const LESS: u8 = 16;
+    pub const PRINT: u8 = 17;
+    pub const DEFINE_GLOBAL: u8 = 18;
+    pub const GET_GLOBAL: u8 = 19;
+    pub const SET_GLOBAL: u8 = 20;
+    pub const GET_LOCAL: u8 = 21;
+    pub const SET_LOCAL: u8 = 22;
+    pub const JUMP: u8 = 23;
+    pub const JUMP_IF_FALSE: u8 = 24;
+    pub const JUMP_IF_NOT_NULLISH: u8 = 25;
+    pub const LOOP: u8 = 26;
+    pub const CALL: u8 = 27;
+    pub const CALL_SPREAD: u8 = 28;
+    pub const CLOSURE: u8 = 29;
+    pub const GET_UPVALUE: u8 = 30;
+    pub const SET_UPVALUE: u8 = 31;
+    pub const CLOSE_UPVALUE: u8 = 32;
+    pub const ARRAY: u8 = 33;
+    pub const ARRAY_PUSH: u8 = 34;
+    pub const ARRAY_SPREAD: u8 = 35;
+    pub const ARRAY_HOLE: u8 = 36;
+    pub const CLASS: u8 = 37;
+    pub const METHOD: u8 = 38;
+    pub const GETTER: u8 = 39;
+    pub const SETTER: u8 = 40;
+    pub const STATIC_METHOD: u8 = 41;
+    pub const STATIC_GETTER: u8 = 42;
+    pub const STATIC_SETTER: u8 = 43;
+    pub const CONSTRUCTOR: u8 = 44;
+    pub const FIELDS: u8 = 45;
+    pub const INIT_FIELDS: u8 = 46;
+    pub const STATIC_INIT: u8 = 47;
+    pub const GET_PROPERTY: u8 = 48;
+    pub const SET_PROPERTY: u8 = 49;
+    pub const GET_PRIVATE: u8 = 50;
+    pub const SET_PRIVATE: u8 = 51;
+    pub const NEW: u8 = 52;
+    pub const NEW_SPREAD: u8 = 53;
+    pub const INHERIT: u8 = 54;
+    pub const GET_SUPER: u8 = 55;
+    pub const SUPER_CALL: u8 = 56;
+    pub const SUPER_CALL_SPREAD: u8 = 57;
+    pub const OBJECT: u8 = 58;
+    pub const INIT_PROPERTY: u8 = 59;
+    pub const OBJECT_SPREAD: u8 = 60;
+    pub const PROTOTYPE: u8 = 61;
+    pub const GET_INDEX: u8 = 62;
+    pub const SET_INDEX: u8 = 63;
+    pub const DEFINE_CONST: u8 = 64;
+    pub const CALLEE: u8 = 65;
+    pub const INVOKE: u8 = 66;
+    pub const INVOKE_SPREAD: u8 = 67;
+    pub const GET_METHOD: u8 = 68;
+    pub const GET_INDEX_METHOD: u8 = 69;
+    pub const DUPLICATE: u8 = 70;
+    pub const POP_BELOW: u8 = 71;
+}
+
+// What a constant is recognized by, to only add it to the pool once
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    // Compared bitwise, so -0 and 0 stay apart and NaN finds itself
+    Number(u64),
+    String(JsString),
+}
+
+/// Where in the source an instruction came from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+    // Bytes of the token the instruction was compiled from
+    pub start: u32,
+    pub length: u32,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// The position of the bytes from `offset` up to where the next run starts
+struct PositionRun {
+    offset: u32,
+    position: Position,
+}
+
+/// Source positions of the code, one entry for each run of instructions from the same place
+#[derive(Default)]
+pub struct Positions {
+    runs: Vec<PositionRun>,
+}
+
+impl Positions {
+    /// Start a run at `offset`, unless the previous one is already at `position`
+    pub fn add(&mut self, offset: usize, position: Position) {
+        if self.runs.last().map(|run| run.position) != Some(position) {
+            self.runs.push(PositionRun {
+                offset: offset as u32,
+                position,
+            });
+        }
+    }
+
+    /// Position of the instruction that covers the byte at `offset`
+    pub fn get(&self, offset: usize) -> Position {
+        let run = self
+            .runs
+            .partition_point(|run| run.offset as usize <= offset);
+        self.runs[run - 1].position
+    }
+
+    /// Where each run starts, and the position of its instructions
+    pub fn runs(&self) -> impl Iterator<Item = (usize, Position)> + '_ {
+        self.runs
+            .iter()
+            .map(|run| (run.offset as usize, run.position))
+    }
+}
+
+#[derive(Default)]
 pub struct Chunk {
-    pub count: usize,
-    pub code: Vec<OpCode>,
-    pub lines: Vec<isize>,
+    pub code: Vec<u8>,
+    pub positions: Positions,
+    pub constants: Vec<Value>,
+    constant_indices: FxHashMap<ConstantKey, usize>,
 }
 
 impl Chunk {
     pub fn new() -> Chunk {
-        Chunk {
-            count: 0,
-            code: Vec::<OpCode>::new(),
-            lines: Vec::<isize>::new(),
+        Chunk::default()
+    }
+
+    pub fn add_op_code(&mut self, op_code: OpCode, position: Position) {
+        self.positions.add(self.code.len(), position);
+        match op_code {
+            OpCode::CONSTANT(index) if index <= u8::MAX as usize => {
+                self.write_byte(op::CONSTANT);
+                self.write_byte(index as u8);
+            }
+            OpCode::CONSTANT(index) => {
+                self.write_byte(op::CONSTANT_LONG);
+                for byte in &(index as u32).to_le_bytes()[..3] {
+                    self.write_byte(*byte);
+                }
+            }
+            OpCode::NULL => self.write_byte(op::NULL),
+            OpCode::UNDEFINED => self.write_byte(op::UNDEFINED),
+            OpCode::ADD => self.write_byte(op::ADD),
+            OpCode::SUBTRACT => self.write_byte(op::SUBTRACT),
+            OpCode::MULTIPLY => self.write_byte(op::MULTIPLY),
+            OpCode::DIVIDE => self.write_byte(op::DIVIDE),
+            OpCode::NEGATE => self.write_byte(op::NEGATE),
+            OpCode::RETURN => self.write_byte(op::RETURN),
+            OpCode::TRUE => self.write_byte(op::TRUE),
+            OpCode::FALSE => self.write_byte(op::FALSE),
+            OpCode::NOT => self.write_byte(op::NOT),
+            OpCode::POP => self.write_byte(op::POP),
+            OpCode::EQUAL => self.write_byte(op::EQUAL),
+            OpCode::GREATER => self.write_byte(op::GREATER),
+            OpCode::LESS => self.write_byte(op::LESS),
+            OpCode::PRINT => self.write_byte(op::PRINT),
+            OpCode::DEFINE_GLOBAL(name) => {
+                self.write_byte(op::DEFINE_GLOBAL);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::DEFINE_CONST(name) => {
+                self.write_byte(op::DEFINE_CONST);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::GET_GLOBAL(name) => {
+                self.write_byte(op::GET_GLOBAL);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::SET_GLOBAL(name) => {
+                self.write_byte(op::SET_GLOBAL);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::GET_LOCAL(operand) => {
+                self.write_byte(op::GET_LOCAL);
+                self.write_varint(operand);
+            }
+            OpCode::SET_LOCAL(operand) => {
+                self.write_byte(op::SET_LOCAL);
+                self.write_varint(operand);
+            }
+            OpCode::JUMP(offset) => {
+                self.write_byte(op::JUMP);
+                self.write_u16(offset);
+            }
+            OpCode::JUMP_IF_FALSE(offset) => {
+                self.write_byte(op::JUMP_IF_FALSE);
+                self.write_u16(offset);
+            }
+            OpCode::JUMP_IF_NOT_NULLISH(offset) => {
+                self.write_byte(op::JUMP_IF_NOT_NULLISH);
+                self.write_u16(offset);
+            }
+            OpCode::LOOP(offset) => {
+                self.write_byte(op::LOOP);
+                self.write_u16(offset);
+            }
+            OpCode::CALL(operand) => {
+                self.write_byte(op::CALL);
+                self.write_varint(operand);
+            }
+            OpCode::CALL_SPREAD => self.write_byte(op::CALL_SPREAD),
+            OpCode::DUPLICATE(operand) => {
+                self.write_byte(op::DUPLICATE);
+                self.write_varint(operand);
+            }
+            OpCode::POP_BELOW(operand) => {
+                self.write_byte(op::POP_BELOW);
+                self.write_varint(operand);
+            }
+            OpCode::INVOKE(operand) => {
+                self.write_byte(op::INVOKE);
+                self.write_varint(operand);
+            }
+            OpCode::INVOKE_SPREAD => self.write_byte(op::INVOKE_SPREAD),
+            OpCode::CLOSURE(operand) => {
+                self.write_byte(op::CLOSURE);
+                self.write_varint(operand);
+            }
+            OpCode::GET_UPVALUE(operand) => {
+                self.write_byte(op::GET_UPVALUE);
+                self.write_varint(operand);
+            }
+            OpCode::SET_UPVALUE(operand) => {
+                self.write_byte(op::SET_UPVALUE);
+                self.write_varint(operand);
+            }
+            OpCode::CLOSE_UPVALUE => self.write_byte(op::CLOSE_UPVALUE),
+            OpCode::CALLEE => self.write_byte(op::CALLEE),
+            OpCode::ARRAY(operand) => {
+                self.write_byte(op::ARRAY);
+                self.write_varint(operand);
+            }
+            OpCode::ARRAY_PUSH => self.write_byte(op::ARRAY_PUSH),
+            OpCode::ARRAY_SPREAD => self.write_byte(op::ARRAY_SPREAD),
+            OpCode::ARRAY_HOLE => self.write_byte(op::ARRAY_HOLE),
+            OpCode::CLASS(name) => {
+                self.write_byte(op::CLASS);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::METHOD(name) => {
+                self.write_byte(op::METHOD);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::GETTER(name) => {
+                self.write_byte(op::GETTER);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::SETTER(name) => {
+                self.write_byte(op::SETTER);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::STATIC_METHOD(name) => {
+                self.write_byte(op::STATIC_METHOD);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::STATIC_GETTER(name) => {
+                self.write_byte(op::STATIC_GETTER);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::STATIC_SETTER(name) => {
+                self.write_byte(op::STATIC_SETTER);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::CONSTRUCTOR => self.write_byte(op::CONSTRUCTOR),
+            OpCode::FIELDS => self.write_byte(op::FIELDS),
+            OpCode::INIT_FIELDS => self.write_byte(op::INIT_FIELDS),
+            OpCode::STATIC_INIT => self.write_byte(op::STATIC_INIT),
+            OpCode::GET_PROPERTY(name) => {
+                self.write_byte(op::GET_PROPERTY);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::SET_PROPERTY(name) => {
+                self.write_byte(op::SET_PROPERTY);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::GET_METHOD(name) => {
+                self.write_byte(op::GET_METHOD);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::GET_PRIVATE(name) => {
+                self.write_byte(op::GET_PRIVATE);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::SET_PRIVATE(name) => {
+                self.write_byte(op::SET_PRIVATE);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::NEW(operand) => {
+                self.write_byte(op::NEW);
+                self.write_varint(operand);
+            }
+            OpCode::NEW_SPREAD => self.write_byte(op::NEW_SPREAD),
+            OpCode::INHERIT => self.write_byte(op::INHERIT),
+            OpCode::GET_SUPER(name) => {
+                self.write_byte(op::GET_SUPER);
+                self.write_varint(name.to_u32() as usize);
+            }
+            OpCode::SUPER_CALL(operand) => {
+                self.write_byte(op::SUPER_CALL);
+                self.write_varint(operand);
+            }
+            OpCode::SUPER_CALL_SPREAD => self.write_byte(op::SUPER_CALL_SPREAD),
+            OpCode::OBJECT => self.write_byte(op::OBJECT),
+            OpCode::INIT_PROPERTY => self.write_byte(op::INIT_PROPERTY),
+            OpCode::OBJECT_SPREAD => self.write_byte(op::OBJECT_SPREAD),
+            OpCode::PROTOTYPE => self.write_byte(op::PROTOTYPE),
+            OpCode::GET_INDEX => self.write_byte(op::GET_INDEX),
+            OpCode::SET_INDEX => self.write_byte(op::SET_INDEX),
+            OpCode::GET_INDEX_METHOD => self.write_byte(op::GET_INDEX_METHOD),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn write_varint(&mut self, mut value: usize) {
+        while value >= 0x80 {
+            self.write_byte(value as u8 | 0x80);
+            value >>= 7;
         }
+        self.write_byte(value as u8);
+    }
+
+    fn write_u16(&mut self, value: usize) {
+        debug_assert!(value <= MAX_JUMP, "jump offset out of range");
+        for byte in (value as u16).to_le_bytes() {
+            self.write_byte(byte);
+        }
+    }
+
+    /// Index of `value` in the constant pool, adding it unless an equal number or string is there
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = match &value {
+            Value::ValNumber(number) => Some(ConstantKey::Number(number.to_bits())),
+            Value::String(string) => Some(ConstantKey::String(string.clone())),
+            _ => None,
+        };
+        if let Some(index) = key.as_ref().and_then(|key| self.constant_indices.get(key)) {
+            return *index;
+        }
+        self.constants.push(value);
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        index
+    }
+
+    /// Point the jump at `offset` to the next instruction to be emitted
+    pub fn patch_jump(&mut self, offset: usize) -> Result<(), &'static str> {
+        match self.code[offset] {
+            op::JUMP | op::JUMP_IF_FALSE | op::JUMP_IF_NOT_NULLISH => {}
+            _ => return Err("Tried to patch an instruction that is not a jump."),
+        }
+        let jump = self.code.len() - offset - 3;
+        if jump > MAX_JUMP {
+            return Err("Too much code to jump over.");
+        }
+        self.code[offset + 1..offset + 3].copy_from_slice(&(jump as u16).to_le_bytes());
+        Ok(())
+    }
+
+    /// Decode the instruction at `offset`, along with the offset of the one after it
+    #[inline(always)]
+    pub fn read(&self, offset: usize) -> (OpCode, usize) {
+        self.try_read(offset).expect("invalid bytecode")
+    }
+
+    /// Decode the instruction at `offset`, if there's a whole valid one there
+    #[inline(always)]
+    pub fn try_read(&self, offset: usize) -> Option<(OpCode, usize)> {
+        let mut next = offset + 1;
+        let instruction = match *self.code.get(offset)? {
+            op::CONSTANT => OpCode::CONSTANT(self.read_constant(&mut next, false)?),
+            op::CONSTANT_LONG => OpCode::CONSTANT(self.read_constant(&mut next, true)?),
+            op::NULL => OpCode::NULL,
+            op::UNDEFINED => OpCode::UNDEFINED,
+            op::ADD => OpCode::ADD,
+            op::SUBTRACT => OpCode::SUBTRACT,
+            op::MULTIPLY => OpCode::MULTIPLY,
+            op::DIVIDE => OpCode::DIVIDE,
+            op::NEGATE => OpCode::NEGATE,
+            op::RETURN => OpCode::RETURN,
+            op::TRUE => OpCode::TRUE,
+            op::FALSE => OpCode::FALSE,
+            op::NOT => OpCode::NOT,
+            op::POP => OpCode::POP,
+            op::EQUAL => OpCode::EQUAL,
+            op::GREATER => OpCode::GREATER,
+            op::LESS => OpCode::LESS,
+            op::PRINT => OpCode::PRINT,
+            op::DEFINE_GLOBAL => OpCode::DEFINE_GLOBAL(self.read_name(&mut next)?),
+            op::DEFINE_CONST => OpCode::DEFINE_CONST(self.read_name(&mut next)?),
+            op::GET_GLOBAL => OpCode::GET_GLOBAL(self.read_name(&mut next)?),
+            op::SET_GLOBAL => OpCode::SET_GLOBAL(self.read_name(&mut next)?),
+            op::GET_LOCAL => OpCode::GET_LOCAL(self.read_varint(&mut next)?),
+            op::SET_LOCAL => OpCode::SET_LOCAL(self.read_varint(&mut next)?),
+            op::JUMP => OpCode::JUMP(self.read_u16(&mut next)?),
+            op::JUMP_IF_FALSE => OpCode::JUMP_IF_FALSE(self.read_u16(&mut next)?),
+            op::JUMP_IF_NOT_NULLISH => OpCode::JUMP_IF_NOT_NULLISH(self.read_u16(&mut next)?),
+            op::LOOP => OpCode::LOOP(self.read_u16(&mut next)?),
+            op::CALL => OpCode::CALL(self.read_varint(&mut next)?),
+            op::CALL_SPREAD => OpCode::CALL_SPREAD,
+            op::DUPLICATE => OpCode::DUPLICATE(self.read_varint(&mut next)?),
+            op::POP_BELOW => OpCode::POP_BELOW(self.read_varint(&mut next)?),
+            op::INVOKE => OpCode::INVOKE(self.read_varint(&mut next)?),
+            op::INVOKE_SPREAD => OpCode::INVOKE_SPREAD,
+            op::CLOSURE => OpCode::CLOSURE(self.read_varint(&mut next)?),
+            op::GET_UPVALUE => OpCode::GET_UPVALUE(self.read_varint(&mut next)?),
+            op::SET_UPVALUE => OpCode::SET_UPVALUE(self.read_varint(&mut next)?),
+            op::CLOSE_UPVALUE => OpCode::CLOSE_UPVALUE,
+            op::CALLEE => OpCode::CALLEE,
+            op::ARRAY => OpCode::ARRAY(self.read_varint(&mut next)?),
+            op::ARRAY_PUSH => OpCode::ARRAY_PUSH,
+            op::ARRAY_SPREAD => OpCode::ARRAY_SPREAD,
+            op::ARRAY_HOLE => OpCode::ARRAY_HOLE,
+            op::CLASS => OpCode::CLASS(self.read_name(&mut next)?),
+            op::METHOD => OpCode::METHOD(self.read_name(&mut next)?),
+            op::GETTER => OpCode::GETTER(self.read_name(&mut next)?),
+            op::SETTER => OpCode::SETTER(self.read_name(&mut next)?),
+            op::STATIC_METHOD => OpCode::STATIC_METHOD(self.read_name(&mut next)?),
+            op::STATIC_GETTER => OpCode::STATIC_GETTER(self.read_name(&mut next)?),
+            op::STATIC_SETTER => OpCode::STATIC_SETTER(self.read_name(&mut next)?),
+            op::CONSTRUCTOR => OpCode::CONSTRUCTOR,
+            op::FIELDS => OpCode::FIELDS,
+            op::INIT_FIELDS => OpCode::INIT_FIELDS,
+            op::STATIC_INIT => OpCode::STATIC_INIT,
+            op::GET_PROPERTY => OpCode::GET_PROPERTY(self.read_name(&mut next)?),
+            op::GET_METHOD => OpCode::GET_METHOD(self.read_name(&mut next)?),
+            op::SET_PROPERTY => OpCode::SET_PROPERTY(self.read_name(&mut next)?),
+            op::GET_PRIVATE => OpCode::GET_PRIVATE(self.read_name(&mut next)?),
+            op::SET_PRIVATE => OpCode::SET_PRIVATE(self.read_name(&mut next)?),
+            op::NEW => OpCode::NEW(self.read_varint(&mut next)?),
+            op::NEW_SPREAD => OpCode::NEW_SPREAD,
+            op::INHERIT => OpCode::INHERIT,
+            op::GET_SUPER => OpCode::GET_SUPER(self.read_name(&mut next)?),
+            op::SUPER_CALL => OpCode::SUPER_CALL(self.read_varint(&mut next)?),
+            op::SUPER_CALL_SPREAD => OpCode::SUPER_CALL_SPREAD,
+            op::OBJECT => OpCode::OBJECT,
+            op::INIT_PROPERTY => OpCode::INIT_PROPERTY,
+            op::OBJECT_SPREAD => OpCode::OBJECT_SPREAD,
+            op::PROTOTYPE => OpCode::PROTOTYPE,
+            op::GET_INDEX => OpCode::GET_INDEX,
+            op::SET_INDEX => OpCode::SET_INDEX,
+            op::GET_INDEX_METHOD => OpCode::GET_INDEX_METHOD,
+            _ => return None,
+        };
+        Some((instruction, next))
+    }
+
+    /// The instructions in order, decoded
+    pub fn instructions(&self) -> impl Iterator<Item = OpCode> + '_ {
+        let mut offset = 0;
+        iter::from_fn(move || {
+            (offset < self.code.len()).then(|| {
+                let (instruction, next) = self.read(offset);
+                offset = next;
+                instruction
+            })
+        })
+    }
+
+    /// A copy of the code with every name replaced by `rename`, or `None` if the code doesn't
+    /// decode, `rename` refuses a name or a jump lands between instructions. Names can take
+    /// more or fewer bytes than before, so the jumps are worked out again for the new code
+    pub fn map_names(&self, mut rename: impl FnMut(StrId) -> Option<StrId>) -> Option<Chunk> {
+        let mut chunk = Chunk::new();
+        // New offset of each instruction, and of the end of the code
+        let mut offsets = FxHashMap::default();
+        let mut jumps = Vec::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let (instruction, next) = self.try_read(offset)?;
+            offsets.insert(offset, chunk.code.len());
+            match instruction {
+                OpCode::JUMP(jump)
+                | OpCode::JUMP_IF_FALSE(jump)
+                | OpCode::JUMP_IF_NOT_NULLISH(jump) => {
+                    jumps.push((chunk.code.len(), next + jump, false))
+                }
+                OpCode::LOOP(jump) => jumps.push((chunk.code.len(), next.checked_sub(jump)?, true)),
+                _ => {}
+            }
+            let instruction = instruction.map_str_id(&mut rename)?;
+            chunk.add_op_code(instruction, self.positions.get(offset));
+            offset = next;
+        }
+        offsets.insert(offset, chunk.code.len());
+        for (at, target, backward) in jumps {
+            let target = *offsets.get(&target)?;
+            let jump = match backward {
+                false => target.checked_sub(at + 3)?,
+                true => (at + 3).checked_sub(target)?,
+            };
+            if jump > MAX_JUMP {
+                return None;
+            }
+            chunk.code[at + 1..at + 3].copy_from_slice(&(jump as u16).to_le_bytes());
+        }
+        chunk.constants = self.constants.clone();
+        Some(chunk)
+    }
+
+    // Operands of the instruction being decoded, each moving `next` past it
+
+    #[inline(always)]
+    pub fn read_varint(&self, next: &mut usize) -> Option<usize> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *self.code.get(*next)?;
+            *next += 1;
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+            shift += 7;
+            if shift >= usize::BITS {
+                return None;
+            }
+        }
+    }
+
+    pub fn read_name(&self, next: &mut usize) -> Option<StrId> {
+        let id = self.read_varint(next)?;
+        Some(StrId::from_u32(u32::try_from(id).ok()?))
+    }
+
+    #[inline(always)]
+    pub fn read_u16(&self, next: &mut usize) -> Option<usize> {
+        let bytes = self.code.get(*next..*next + 2)?;
+        *next += 2;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
     }
 
-    pub fn add_op_code(&mut self, op_code: OpCode, line: isize) {
-        self.code.push(op_code);
-        self.lines.push(line);
-        self.count += 1;
+    /// A constant index, which takes three bytes after `CONSTANT_LONG` and one otherwise
+    #[inline(always)]
+    pub fn read_constant(&self, next: &mut usize, long: bool) -> Option<usize> {
+        let length = if long { 3 } else { 1 };
+        let bytes = self.code.get(*next..*next + length)?;
+        *next += length;
+        let mut index = [0; 4];
+        index[..length].copy_from_slice(bytes);
+        Some(u32::from_le_bytes(index) as usize)
     }
 }
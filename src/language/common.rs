@@ -1,33 +1,40 @@
-use rustc_hash::FxHasher;
+use super::interner;
+use super::string::JsString;
 use smol_str::SmolStr;
 use std::fmt;
-use std::{cell::RefCell, hash::BuildHasherDefault, rc::Rc};
-use string_interner::{backend::BucketBackend, symbol::SymbolU32, StringInterner};
+use std::{cell::RefCell, rc::Rc};
 
-pub type MutRc<T> = Rc<RefCell<T>>;
-pub type StrId = SymbolU32;
+pub use super::interner::StrId;
 
-thread_local! {
-    static INTERN: RefCell<StringInterner::<StrId, BucketBackend<StrId>, BuildHasherDefault<FxHasher>>> = RefCell::new(StringInterner::with_capacity(500));
-}
+pub type MutRc<T> = Rc<RefCell<T>>;
 
 /// Return interned variant of given string
 pub fn intern<T: AsRef<str>>(of: T) -> StrId {
-    INTERN.with(|i| i.borrow_mut().get_or_intern(of))
+    interner::with_current(|i| i.intern(of.as_ref()))
+}
+
+/// Return interned id of a string made at runtime
+pub fn intern_string(of: &JsString) -> StrId {
+    interner::with_current(|i| i.intern_string(of))
 }
 
 /// Return string of interned id
 pub fn to_str(of: StrId) -> SmolStr {
-    INTERN.with(|i| SmolStr::new(i.borrow().resolve(of).unwrap()))
+    interner::with_current(|i| SmolStr::new(i.resolve(of)))
 }
 
+/// Return the string value of interned id
+pub fn string(of: StrId) -> JsString {
+    interner::with_current(|i| i.string(of).clone())
+}
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Copy, Clone)]
 pub enum OpCode {
     NULL,
-    // Const opcodes - push the given value the stack
-    BOOL(bool),
-    NUMBER(f64),
-    STRING(StrId),
+    UNDEFINED,
+    // Push the given constant from the chunk's pool
+    CONSTANT(usize),
     // Operations
     ADD,
     SUBTRACT,
@@ -41,21 +48,152 @@ pub enum OpCode {
 
     NOT,
     POP,
+    // Push a copy of the value the given number of slots below the top
+    DUPLICATE(usize),
+    // Drop the given number of values from under the top one
+    POP_BELOW(usize),
 
     EQUAL,
     GREATER,
     LESS,
 
     PRINT,
+
+    // Globals - resolved by name at runtime
+    DEFINE_GLOBAL(StrId),
+    // A global that can't be assigned to afterwards
+    DEFINE_CONST(StrId),
+    GET_GLOBAL(StrId),
+    SET_GLOBAL(StrId),
+    // Locals - stack slot resolved at compile time
+    GET_LOCAL(usize),
+    SET_LOCAL(usize),
+    // Control flow - forward offsets relative to the next instruction
+    JUMP(usize),
+    JUMP_IF_FALSE(usize),
+    JUMP_IF_NOT_NULLISH(usize),
+    // Backward offset relative to the next instruction
+    LOOP(usize),
+
+    // Functions
+    CALL(usize),
+    // Call with the arguments collected in the array on top of the stack
+    CALL_SPREAD,
+    // Call the function below the arguments with the value below it as `this`
+    INVOKE(usize),
+    INVOKE_SPREAD,
+    // Closures - wrap the function constant and capture its upvalues
+    CLOSURE(usize),
+    GET_UPVALUE(usize),
+    SET_UPVALUE(usize),
+    CLOSE_UPVALUE,
+    // Push the closure being run, for a named function expression to refer to itself by
+    CALLEE,
+
+    // Arrays - collect the given number of values, then append to the array below the top
+    ARRAY(usize),
+    ARRAY_PUSH,
+    ARRAY_SPREAD,
+    ARRAY_HOLE,
+
+    // Classes - members are attached to the class just below the top of the stack
+    CLASS(StrId),
+    METHOD(StrId),
+    GETTER(StrId),
+    SETTER(StrId),
+    STATIC_METHOD(StrId),
+    STATIC_GETTER(StrId),
+    STATIC_SETTER(StrId),
+    CONSTRUCTOR,
+    FIELDS,
+    // Run the field initializers of the class below `this` against it
+    INIT_FIELDS,
+    // Run the static initializer on top of the stack with the class below it as `this`
+    STATIC_INIT,
+    GET_PROPERTY(StrId),
+    SET_PROPERTY(StrId),
+    // Property access that keeps the object below the value, for `INVOKE` to call it on
+    GET_METHOD(StrId),
+    // Property access that first checks the object was set up by the declaring class,
+    // found on top of the stack
+    GET_PRIVATE(StrId),
+    SET_PRIVATE(StrId),
+    NEW(usize),
+    NEW_SPREAD,
+    // Inheritance - the superclass sits below the class
+    INHERIT,
+    GET_SUPER(StrId),
+    SUPER_CALL(usize),
+    SUPER_CALL_SPREAD,
+
+    // Objects - properties are defined on the object below the top of the stack
+    OBJECT,
+    INIT_PROPERTY,
+    OBJECT_SPREAD,
+    PROTOTYPE,
+    // Computed property access, with the key on top of the object
+    GET_INDEX,
+    SET_INDEX,
+    GET_INDEX_METHOD,
+}
+
+impl OpCode {
+    /// The interned string this instruction refers to, if any
+    pub fn str_id(&self) -> Option<StrId> {
+        match *self {
+            OpCode::DEFINE_GLOBAL(id)
+            | OpCode::DEFINE_CONST(id)
+            | OpCode::GET_GLOBAL(id)
+            | OpCode::SET_GLOBAL(id)
+            | OpCode::CLASS(id)
+            | OpCode::METHOD(id)
+            | OpCode::GETTER(id)
+            | OpCode::SETTER(id)
+            | OpCode::STATIC_METHOD(id)
+            | OpCode::STATIC_GETTER(id)
+            | OpCode::STATIC_SETTER(id)
+            | OpCode::GET_PROPERTY(id)
+            | OpCode::SET_PROPERTY(id)
+            | OpCode::GET_METHOD(id)
+            | OpCode::GET_PRIVATE(id)
+            | OpCode::SET_PRIVATE(id)
+            | OpCode::GET_SUPER(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// The same instruction with the string it refers to replaced by `rename`, which
+    /// may refuse it
+    pub fn map_str_id(self, rename: impl FnOnce(StrId) -> Option<StrId>) -> Option<OpCode> {
+        Some(match self {
+            OpCode::DEFINE_GLOBAL(id) => OpCode::DEFINE_GLOBAL(rename(id)?),
+            OpCode::DEFINE_CONST(id) => OpCode::DEFINE_CONST(rename(id)?),
+            OpCode::GET_GLOBAL(id) => OpCode::GET_GLOBAL(rename(id)?),
+            OpCode::SET_GLOBAL(id) => OpCode::SET_GLOBAL(rename(id)?),
+            OpCode::CLASS(id) => OpCode::CLASS(rename(id)?),
+            OpCode::METHOD(id) => OpCode::METHOD(rename(id)?),
+            OpCode::GETTER(id) => OpCode::GETTER(rename(id)?),
+            OpCode::SETTER(id) => OpCode::SETTER(rename(id)?),
+            OpCode::STATIC_METHOD(id) => OpCode::STATIC_METHOD(rename(id)?),
+            OpCode::STATIC_GETTER(id) => OpCode::STATIC_GETTER(rename(id)?),
+            OpCode::STATIC_SETTER(id) => OpCode::STATIC_SETTER(rename(id)?),
+            OpCode::GET_PROPERTY(id) => OpCode::GET_PROPERTY(rename(id)?),
+            OpCode::SET_PROPERTY(id) => OpCode::SET_PROPERTY(rename(id)?),
+            OpCode::GET_METHOD(id) => OpCode::GET_METHOD(rename(id)?),
+            OpCode::GET_PRIVATE(id) => OpCode::GET_PRIVATE(rename(id)?),
+            OpCode::SET_PRIVATE(id) => OpCode::SET_PRIVATE(rename(id)?),
+            OpCode::GET_SUPER(id) => OpCode::GET_SUPER(rename(id)?),
+            op_code => op_code,
+        })
+    }
 }
 
 impl fmt::Display for OpCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             OpCode::NULL => write!(f, "OP_NULL"),
-            OpCode::BOOL(bool) => write!(f, "OP_BOOL:{}", bool),
-            OpCode::NUMBER(f64) => write!(f, "OP_NUMBER:{}", f64),
-            OpCode::STRING(StrId) => write!(f, "OP_STRING:{}", to_str(StrId)),
+            OpCode::UNDEFINED => write!(f, "OP_UNDEFINED"),
+            OpCode::CONSTANT(index) => write!(f, "OP_CONSTANT:{}", index),
             OpCode::ADD => write!(f, "OP_ADD"),
             OpCode::SUBTRACT => write!(f, "OP_SUBTRACT"),
             OpCode::MULTIPLY => write!(f, "OP_MULTIPLY"),
@@ -70,6 +208,60 @@ impl fmt::Display for OpCode {
             OpCode::GREATER => write!(f, "OP_GREATER"),
             OpCode::LESS => write!(f, "OP_LESS"),
             OpCode::PRINT => write!(f, "OP_PRINT"),
+            OpCode::DEFINE_GLOBAL(name) => write!(f, "OP_DEFINE_GLOBAL:{}", to_str(name)),
+            OpCode::DEFINE_CONST(name) => write!(f, "OP_DEFINE_CONST:{}", to_str(name)),
+            OpCode::GET_GLOBAL(name) => write!(f, "OP_GET_GLOBAL:{}", to_str(name)),
+            OpCode::SET_GLOBAL(name) => write!(f, "OP_SET_GLOBAL:{}", to_str(name)),
+            OpCode::GET_LOCAL(slot) => write!(f, "OP_GET_LOCAL:{}", slot),
+            OpCode::SET_LOCAL(slot) => write!(f, "OP_SET_LOCAL:{}", slot),
+            OpCode::JUMP(offset) => write!(f, "OP_JUMP:{}", offset),
+            OpCode::JUMP_IF_FALSE(offset) => write!(f, "OP_JUMP_IF_FALSE:{}", offset),
+            OpCode::JUMP_IF_NOT_NULLISH(offset) => write!(f, "OP_JUMP_IF_NOT_NULLISH:{}", offset),
+            OpCode::LOOP(offset) => write!(f, "OP_LOOP:{}", offset),
+            OpCode::DUPLICATE(depth) => write!(f, "OP_DUPLICATE:{}", depth),
+            OpCode::POP_BELOW(count) => write!(f, "OP_POP_BELOW:{}", count),
+            OpCode::CALL(arg_count) => write!(f, "OP_CALL:{}", arg_count),
+            OpCode::CALL_SPREAD => write!(f, "OP_CALL_SPREAD"),
+            OpCode::INVOKE(arg_count) => write!(f, "OP_INVOKE:{}", arg_count),
+            OpCode::INVOKE_SPREAD => write!(f, "OP_INVOKE_SPREAD"),
+            OpCode::CLOSURE(index) => write!(f, "OP_CLOSURE:{}", index),
+            OpCode::GET_UPVALUE(index) => write!(f, "OP_GET_UPVALUE:{}", index),
+            OpCode::SET_UPVALUE(index) => write!(f, "OP_SET_UPVALUE:{}", index),
+            OpCode::CLOSE_UPVALUE => write!(f, "OP_CLOSE_UPVALUE"),
+            OpCode::CALLEE => write!(f, "OP_CALLEE"),
+            OpCode::ARRAY(count) => write!(f, "OP_ARRAY:{}", count),
+            OpCode::ARRAY_PUSH => write!(f, "OP_ARRAY_PUSH"),
+            OpCode::ARRAY_SPREAD => write!(f, "OP_ARRAY_SPREAD"),
+            OpCode::ARRAY_HOLE => write!(f, "OP_ARRAY_HOLE"),
+            OpCode::CLASS(name) => write!(f, "OP_CLASS:{}", to_str(name)),
+            OpCode::METHOD(name) => write!(f, "OP_METHOD:{}", to_str(name)),
+            OpCode::GETTER(name) => write!(f, "OP_GETTER:{}", to_str(name)),
+            OpCode::SETTER(name) => write!(f, "OP_SETTER:{}", to_str(name)),
+            OpCode::STATIC_METHOD(name) => write!(f, "OP_STATIC_METHOD:{}", to_str(name)),
+            OpCode::STATIC_GETTER(name) => write!(f, "OP_STATIC_GETTER:{}", to_str(name)),
+            OpCode::STATIC_SETTER(name) => write!(f, "OP_STATIC_SETTER:{}", to_str(name)),
+            OpCode::CONSTRUCTOR => write!(f, "OP_CONSTRUCTOR"),
+            OpCode::FIELDS => write!(f, "OP_FIELDS"),
+            OpCode::INIT_FIELDS => write!(f, "OP_INIT_FIELDS"),
+            OpCode::STATIC_INIT => write!(f, "OP_STATIC_INIT"),
+            OpCode::GET_PROPERTY(name) => write!(f, "OP_GET_PROPERTY:{}", to_str(name)),
+            OpCode::GET_METHOD(name) => write!(f, "OP_GET_METHOD:{}", to_str(name)),
+            OpCode::SET_PROPERTY(name) => write!(f, "OP_SET_PROPERTY:{}", to_str(name)),
+            OpCode::GET_PRIVATE(name) => write!(f, "OP_GET_PRIVATE:{}", to_str(name)),
+            OpCode::SET_PRIVATE(name) => write!(f, "OP_SET_PRIVATE:{}", to_str(name)),
+            OpCode::NEW(arg_count) => write!(f, "OP_NEW:{}", arg_count),
+            OpCode::NEW_SPREAD => write!(f, "OP_NEW_SPREAD"),
+            OpCode::INHERIT => write!(f, "OP_INHERIT"),
+            OpCode::GET_SUPER(name) => write!(f, "OP_GET_SUPER:{}", to_str(name)),
+            OpCode::SUPER_CALL(arg_count) => write!(f, "OP_SUPER_CALL:{}", arg_count),
+            OpCode::SUPER_CALL_SPREAD => write!(f, "OP_SUPER_CALL_SPREAD"),
+            OpCode::OBJECT => write!(f, "OP_OBJECT"),
+            OpCode::INIT_PROPERTY => write!(f, "OP_INIT_PROPERTY"),
+            OpCode::OBJECT_SPREAD => write!(f, "OP_OBJECT_SPREAD"),
+            OpCode::PROTOTYPE => write!(f, "OP_PROTOTYPE"),
+            OpCode::GET_INDEX => write!(f, "OP_GET_INDEX"),
+            OpCode::SET_INDEX => write!(f, "OP_SET_INDEX"),
+            OpCode::GET_INDEX_METHOD => write!(f, "OP_GET_INDEX_METHOD"),
         }
     }
 }
@@ -1,18 +1,26 @@
 use plain_enum::{plain_enum_mod, TPlainEnum};
+use rustc_hash::FxHashMap;
+use std::{mem, rc::Rc};
 
-use super::chunk::Chunk;
+use super::chunk::{Chunk, Position, MAX_CONSTANTS, MAX_JUMP};
 #[cfg(feature = "log_level_debug")]
 use crate::language::debug::Debug;
 // use super::common::MutRc;
-use super::common::{intern, OpCode};
-use super::parser::Parser;
+use super::common::{intern, string, to_str, OpCode, StrId};
+use super::diagnostic::{Diagnostic, ErrorCode, Span};
+use super::object::{Capture, Function};
+use super::parser::{Checkpoint, Parser};
 #[cfg(feature = "log_level_debug")]
 use super::scanner::Scanner;
 use super::token::{Token, TokenType};
+use super::value::Value;
+use super::verifier::verify;
 
 plain_enum_mod! {this,Precedence {
     None,
     Assignment, // =
+    Ternary,    // ?:
+    Nullish,    // ??
     Or,         // or
     And,        // and
     Equality,   // == !=
@@ -61,34 +69,45 @@ impl ParseRule {
 }
 
 // @implNote: it has to match the same number & position as TokenType
-static RULES: [ParseRule; 45] = [
+static RULES: [ParseRule; 62] = [
     ParseRule::new_both(
         |compiler, _| compiler.grouping(),
         Some(|compiler, _| compiler.call()),
         Precedence::Call,
     ), // LEFT_PAREN
     ParseRule::new(Precedence::None), // RIGHT_PAREN
-    ParseRule::new(Precedence::None), // LEFT_BRACE
+    ParseRule::new_both(|compiler, _| compiler.object(), None, Precedence::None), // LEFT_BRACE
     ParseRule::new(Precedence::None), // RIGHT_BRACE
+    ParseRule::new_both(
+        |compiler, _| compiler.array(),
+        Some(|compiler, can_assign| compiler.index(can_assign)),
+        Precedence::Call,
+    ), // LEFT_BRACKET
+    ParseRule::new(Precedence::None), // RIGHT_BRACKET
     ParseRule::new(Precedence::None), // COMMA
     ParseRule::new_infix(
         |compiler, can_assign| compiler.dot(can_assign),
         Precedence::Call,
     ), // DOT
+    ParseRule::new(Precedence::None), // DOT_DOT_DOT
     ParseRule::new_both(
         |compiler, _| compiler.unary(),
         Some(|compiler, _| compiler.binary()),
         Precedence::Term,
     ), // MINUS
+    // 10
     ParseRule::new_infix(|compiler, _| compiler.binary(), Precedence::Term), // PLUS
     ParseRule::new(Precedence::None), // SEMICOLON
     ParseRule::new_infix(|compiler, _| compiler.binary(), Precedence::Factor), // SLASH
-    // 10
     ParseRule::new_infix(|compiler, _| compiler.binary(), Precedence::Factor), // STAR
+    ParseRule::new_infix(|compiler, _| compiler.ternary(), Precedence::Ternary), // QUESTION
+    ParseRule::new(Precedence::None),                                          // COLON
     ParseRule::new_both(|compiler, _| compiler.unary(), None, Precedence::None), // BANG
     ParseRule::new_infix(|compiler, _| compiler.binary(), Precedence::Equality), // BANG_EQUAL
     ParseRule::new(Precedence::None),                                          // EQUAL
     ParseRule::new_infix(|compiler, _| compiler.binary(), Precedence::Equality), // EQUAL_EQUAL
+    // 20
+    ParseRule::new(Precedence::None),                                          // ARROW
     ParseRule::new_infix(|compiler, _| compiler.binary(), Precedence::Comparison), // GREATER
     ParseRule::new_infix(|compiler, _| compiler.binary(), Precedence::Comparison), // GREATER_EQUAL
     ParseRule::new_infix(|compiler, _| compiler.binary(), Precedence::Comparison), // LESS
@@ -98,114 +117,1248 @@ static RULES: [ParseRule; 45] = [
         None,
         Precedence::None,
     ), // IDENTIFIER
-    // 20
-    ParseRule::new_both(|compiler, _| compiler.string(), None, Precedence::Term), // STRING
+    ParseRule::new_both(|compiler, _| compiler.string(), None, Precedence::Term),  // STRING
     ParseRule::new_both(|compiler, _| compiler.literal(), None, Precedence::None), // NUMBER
-    ParseRule::new_infix(|compiler, _| compiler.and(), Precedence::And),          // AND
-    ParseRule::new_infix(|compiler, _| compiler.or(), Precedence::Or),            // OR
-    ParseRule::new(Precedence::None),                                             // BITWISE AND
-    ParseRule::new(Precedence::None),                                             // BITWISE OR
-    ParseRule::new(Precedence::None),                                             // BITWISE XOR
-    ParseRule::new(Precedence::None),                                             // BITWISE NOT
-    ParseRule::new(Precedence::None),                                             // CLASS
-    ParseRule::new(Precedence::None),                                             // ELSE
+    ParseRule::new_infix(|compiler, _| compiler.and(), Precedence::And),           // AND
+    ParseRule::new_infix(|compiler, _| compiler.or(), Precedence::Or),             // OR
     // 30
+    ParseRule::new_infix(|compiler, _| compiler.nullish(), Precedence::Nullish), // QUESTION_QUESTION
+    ParseRule::new(Precedence::None),                                            // AND_EQUAL
+    ParseRule::new(Precedence::None),                                            // OR_EQUAL
+    ParseRule::new(Precedence::None), // QUESTION_QUESTION_EQUAL
+    ParseRule::new(Precedence::None), // BITWISE AND
+    ParseRule::new(Precedence::None), // BITWISE OR
+    ParseRule::new(Precedence::None), // BITWISE XOR
+    ParseRule::new(Precedence::None), // BITWISE NOT
+    ParseRule::new(Precedence::None), // BREAK
+    ParseRule::new(Precedence::None), // CLASS
+    // 40
+    ParseRule::new(Precedence::None), // CONST
+    ParseRule::new(Precedence::None), // CONTINUE
+    ParseRule::new(Precedence::None), // DO
+    ParseRule::new(Precedence::None), // ELSE
     ParseRule::new(Precedence::None), // EXTENDS
     ParseRule::new_both(|compiler, _| compiler.literal(), None, Precedence::None), // FALSE
     ParseRule::new(Precedence::None), // FOR
-    ParseRule::new(Precedence::None), // FUNCTION
+    ParseRule::new_both(
+        |compiler, _| compiler.function_expression(),
+        None,
+        Precedence::None,
+    ), // FUNCTION
     ParseRule::new(Precedence::None), // IF
+    ParseRule::new(Precedence::None), // LET
+    // 50
+    ParseRule::new_both(|compiler, _| compiler.new_(), None, Precedence::None), // NEW
     ParseRule::new_both(|compiler, _| compiler.literal(), None, Precedence::None), // NULL
     ParseRule::new(Precedence::None), // PRINT
     ParseRule::new(Precedence::None), // RETURN
     ParseRule::new_both(|compiler, _| compiler.super_(), None, Precedence::None), // SUPER
-    ParseRule::new_both(|compiler, _| compiler.this(), None, Precedence::None), // THIS
-    // 40
+    ParseRule::new_both(|compiler, _| compiler.this(), None, Precedence::None),   // THIS
     ParseRule::new_both(|compiler, _| compiler.literal(), None, Precedence::None), // TRUE
-    ParseRule::new(Precedence::None),                                              // VAR
-    ParseRule::new(Precedence::None),                                              // WHILE
-    ParseRule::new(Precedence::None),                                              // ERROR
-    ParseRule::new(Precedence::None),                                              // EOF
+    ParseRule::new_both(|compiler, _| compiler.literal(), None, Precedence::None), // UNDEFINED
+    ParseRule::new(Precedence::None),                                             // VAR
+    ParseRule::new(Precedence::None),                                             // WHILE
+    // 60
+    ParseRule::new(Precedence::None),                                             // ERROR
+    ParseRule::new(Precedence::None),                                             // EOF
 ];
 
-pub struct Compiler<'a> {
+#[derive(Copy, Clone, PartialEq)]
+pub enum VarKind {
+    Var,
+    Let,
+    Const,
+    Function,
+}
+
+// A statement `break` (and for loops, `continue`) can jump out of
+struct Loop {
+    labels: Vec<StrId>,
+    // Labelled blocks can be broken out of, but not continued
+    is_iteration: bool,
+    scope_depth: usize,
+    // Deeper than `scope_depth` when each iteration gets its own bindings
+    continue_depth: usize,
+    // Known up front when the continue target precedes the body
+    continue_target: Option<usize>,
+    continue_jumps: Vec<usize>,
+    break_jumps: Vec<usize>,
+}
+
+// Private names declared as a field or method use both bits, accessors one each
+const PRIVATE_GETTER: u8 = 1;
+const PRIVATE_SETTER: u8 = 2;
+
+struct ClassState {
+    name: StrId,
+    // Classes this one is nested in
+    depth: usize,
+    // Private names in the body, with what the members declared so far made of them
+    private_names: FxHashMap<StrId, u8>,
+}
+
+impl ClassState {
+    /// The hidden local holding the class, which private names are looked up on at runtime
+    fn binding(&self) -> StrId {
+        intern(format!("#class{}", self.depth))
+    }
+}
+
+/// What a class body has collected so far
+struct ClassBody {
+    name: StrId,
+    is_derived: bool,
+    has_constructor: bool,
+    fields: Option<Compiler>,
+    static_fields: Option<Compiler>,
+}
+
+struct ConstructorState {
+    // Upvalue holding the class being constructed
+    class: usize,
+    is_derived: bool,
+    super_called: bool,
+}
+
+struct Upvalue {
+    capture: Capture,
+    kind: VarKind,
+}
+
+struct Local {
+    name: StrId,
+    depth: usize,
+    // False until the initializer has been compiled
+    initialized: bool,
+    // Declared when its block began, the declaration assigning it rather than pushing it
+    hoisted: bool,
+    kind: VarKind,
+    // Captured locals are moved to the heap instead of popped when their scope ends
+    is_captured: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
+    // Arrow functions have no `this` of their own
+    Arrow,
+    Method,
+    // Constructors and field initializers return `this` implicitly
+    Constructor,
+}
+
+pub struct Compiler {
     parser: Parser,
-    pub compiling_chunk: Option<&'a mut Chunk>,
-    // function: Function,
-    // function_type: FunctionType,
+    globals: FxHashMap<StrId, VarKind>,
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    // Names read or assigned so far, with the scope depth they were used at and where,
+    // used to report block-scoped variables used before their declaration
+    reads: Vec<(StrId, usize, Span)>,
+    loops: Vec<Loop>,
+    // Labels waiting for the statement they belong to
+    pending_labels: Vec<StrId>,
+    // Function declarations compiled when their block began, by where they start,
+    // with where to carry on from once the declaration is reached
+    hoisted_functions: FxHashMap<usize, Checkpoint>,
+    upvalues: Vec<Upvalue>,
+    enclosing: Option<Box<Compiler>>,
+    constructor: Option<ConstructorState>,
+    // The class whose body is being compiled
+    class: Option<ClassState>,
+    // class_stack: MutRc<Vec<ClassCompile>>,
+}
+
+impl Compiler {
+    /// A compiler for a script run after others that declared `globals`
+    pub fn new(code: &str, globals: FxHashMap<StrId, VarKind>) -> Compiler {
+        Compiler::new_function(Parser::new(code), globals, FunctionType::Script, None)
+    }
+
+    /// The globals declared so far, including the ones the compiled script declares
+    pub fn into_globals(self) -> FxHashMap<StrId, VarKind> {
+        self.globals
+    }
+
+    fn new_function(
+        parser: Parser,
+        globals: FxHashMap<StrId, VarKind>,
+        function_type: FunctionType,
+        name: Option<StrId>,
+    ) -> Compiler {
+        let mut function = Function::new(name);
+        function.binds_this = matches!(function_type, FunctionType::Function | FunctionType::Method);
+        Compiler {
+            parser,
+            globals,
+            function,
+            function_type,
+            // The first slot holds `this`, or where there's none, the function being called
+            locals: vec![Local {
+                name: match function_type {
+                    FunctionType::Function | FunctionType::Method | FunctionType::Constructor => {
+                        intern("this")
+                    }
+                    _ => name.unwrap_or_else(|| intern("")),
+                },
+                depth: 0,
+                initialized: true,
+                hoisted: false,
+                kind: VarKind::Const,
+                is_captured: false,
+            }],
+            scope_depth: 0,
+            reads: Vec::new(),
+            loops: Vec::new(),
+            pending_labels: Vec::new(),
+            hoisted_functions: FxHashMap::default(),
+            upvalues: Vec::new(),
+            enclosing: None,
+            constructor: None,
+            class: None,
+        }
+    }
+
+    /// Compile the whole source, or return every error found in it
+    pub fn compile(&mut self) -> Result<Rc<Function>, Vec<Diagnostic>> {
+        // #[cfg(feature = "log_level_debug")]
+        // Compiler::debug_scanner(self.parser.scanner.source.clone());
+        self.parser.advance();
+        let line = self.get_line();
+        println!("[scanner][line {}] line start", line);
+        self.hoist_declarations(false);
+
+        while !self.parser.match_next(TokenType::EOF) {
+            let line = self.get_line();
+            println!("[compiler][line {}] compile::(while !EOF)", line);
+            self.declaration();
+        }
+        let line = self.get_line();
+        println!("[compiler][line {}] compile::out of (while !EOF)", line);
+        let function = self.end_compiliation();
+        if self.parser.had_error {
+            let mut diagnostics = mem::take(&mut self.parser.diagnostics);
+            // Hoisted functions are compiled ahead of the code before them
+            diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+            return Err(diagnostics);
+        }
+        // Code compiled from a valid source must pass the checks loaded code goes through
+        if cfg!(debug_assertions) {
+            if let Err(error) = verify(&function) {
+                panic!("Compiled invalid bytecode: {}", error);
+            }
+        }
+        Ok(function)
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn declaration(&mut self) {
+        match () {
+            _ if self.parser.match_next(TokenType::Class) => self.class_declaration(),
+            _ if self.parser.match_next(TokenType::Var) => self.var_declaration(VarKind::Var),
+            _ if self.parser.match_next(TokenType::Let) => self.var_declaration(VarKind::Let),
+            _ if self.parser.match_next(TokenType::Const) => self.var_declaration(VarKind::Const),
+            _ if self.parser.match_next(TokenType::Function) => self.fun_declaration(),
+            _ => self.statement(),
+        }
+
+        self.parser.synchronize();
+    }
+
+    fn var_declaration(&mut self, kind: VarKind) {
+        loop {
+            let declared = kind == VarKind::Var
+                && self.parser.check(TokenType::Identifier)
+                && self.is_declared_var(intern(self.current().lexeme));
+            let name = self.parse_variable(kind, "Expected variable name.");
+
+            if self.parser.match_next(TokenType::Equal) {
+                self.expression();
+                self.define_variable(name, kind);
+            } else if declared {
+                // A `var` declared again without a value keeps the one it has
+            } else {
+                if kind == VarKind::Const {
+                    self.error_with_help(
+                        self.previous().span(),
+                        "'const' declarations must be initialized.",
+                        "give it a value with '=', or declare it with 'let'",
+                    );
+                }
+                self.emit_byte(OpCode::UNDEFINED);
+                self.define_variable(name, kind);
+            }
+
+            if !self.parser.match_next(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expected ';' after variable declaration.",
+        );
+    }
+
+    fn fun_declaration(&mut self) {
+        // Compiled already when the block began
+        if let Some(end) = self.hoisted_functions.remove(&self.previous().start) {
+            self.parser.rewind(end);
+            return;
+        }
+        let name = self.parse_variable(VarKind::Function, "Expected function name.");
+        // Initialized straight away, so the body can refer to it recursively
+        if self.is_local(VarKind::Function) && self.hoisted_local(name, VarKind::Function).is_none()
+        {
+            self.mark_initialized();
+        }
+        self.begin_function(FunctionType::Function, Some(name));
+        self.function_body(None);
+        self.define_variable(name, VarKind::Function);
+    }
+
+    fn function_expression(&mut self) {
+        let name = if self.parser.match_next(TokenType::Identifier) {
+            Some(intern(self.previous().lexeme))
+        } else {
+            None
+        };
+        self.begin_function(FunctionType::Function, name);
+        self.function_body(name);
+    }
+
+    /// Compile a parameter list and body into the function begun, leaving it on the stack.
+    /// A named function expression refers to itself by `own_name`, which only it declares
+    fn function_body(&mut self, own_name: Option<StrId>) {
+        self.consume(TokenType::LeftParen, "Expected '(' after function name.");
+        self.parameters();
+        if let Some(name) = own_name {
+            // The slot that would hold the function holds `this`, so it keeps itself in a local
+            self.emit_byte(OpCode::CALLEE);
+            self.locals.push(Local {
+                name,
+                depth: self.scope_depth,
+                initialized: true,
+                hoisted: false,
+                kind: VarKind::Const,
+                is_captured: false,
+            });
+        }
+        self.consume(TokenType::LeftBrace, "Expected '{' before function body.");
+        self.block();
+        self.end_function();
+    }
+
+    fn class_declaration(&mut self) {
+        let name = self.parse_variable(VarKind::Let, "Expected class name.");
+        self.emit_byte(OpCode::CLASS(name));
+        self.define_variable(name, VarKind::Let);
+        let load_class = if self.is_local(VarKind::Let) {
+            OpCode::GET_LOCAL(self.find_local(name).unwrap())
+        } else {
+            OpCode::GET_GLOBAL(name)
+        };
+
+        self.begin_scope();
+        // Methods reach the superclass through a `super` local, as they would any variable
+        let is_derived = self.parser.match_next(TokenType::Extends);
+        if is_derived {
+            if self.parser.check(TokenType::Identifier) && intern(self.current().lexeme) == name {
+                self.error_at(
+                    self.current().span(),
+                    &format!("Class '{}' used before its declaration.", to_str(name)),
+                );
+            }
+            self.expression();
+            self.declare_local(intern("super"), VarKind::Const);
+            self.mark_initialized();
+        }
+        // Inside its body the class refers to itself through a constant binding,
+        // which also keeps it on the stack while the members are attached
+        self.emit_byte(load_class);
+        self.declare_local(name, VarKind::Const);
+        self.mark_initialized();
+        if is_derived {
+            self.emit_byte(OpCode::INHERIT);
+        }
+
+        self.consume(TokenType::LeftBrace, "Expected '{' before class body.");
+        // Private names are keyed per evaluation of the class, so code using them needs the
+        // class at hand, where the name could be shadowed
+        let class = ClassState {
+            name,
+            depth: self.enclosing_class().map_or(0, |class| class.depth + 1),
+            private_names: self
+                .parser
+                .private_names()
+                .into_iter()
+                .map(|private| (intern(&private), 0))
+                .collect(),
+        };
+        self.emit_byte(load_class);
+        self.declare_local(class.binding(), VarKind::Const);
+        self.mark_initialized();
+        let enclosing_class = self.class.replace(class);
+
+        let mut body = ClassBody {
+            name,
+            is_derived,
+            has_constructor: false,
+            fields: None,
+            static_fields: None,
+        };
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::EOF) {
+            self.class_member(&mut body);
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after class body.");
+
+        if is_derived && !body.has_constructor {
+            self.implicit_constructor(name);
+        }
+        if let Some(fields) = body.fields {
+            self.resume_function(fields);
+            self.end_function();
+            self.emit_byte(OpCode::FIELDS);
+        }
+        // Static fields are initialized once the class is complete
+        if let Some(static_fields) = body.static_fields {
+            self.resume_function(static_fields);
+            self.end_function();
+            self.emit_bytes(OpCode::STATIC_INIT, OpCode::POP);
+        }
+        self.class = enclosing_class;
+        self.end_scope();
+    }
+
+    fn class_member(&mut self, body: &mut ClassBody) {
+        if self.parser.match_next(TokenType::Semicolon) {
+            return;
+        }
+        self.consume(TokenType::Identifier, "Expected member name.");
+        let mut member = intern(self.previous().lexeme);
+        let is_static = self.is_modifier(member, "static");
+        if is_static {
+            self.parser.advance();
+            member = intern(self.previous().lexeme);
+        }
+        let accessor = match () {
+            _ if self.is_modifier(member, "get") => Some(false),
+            _ if self.is_modifier(member, "set") => Some(true),
+            _ => None,
+        };
+        if accessor.is_some() {
+            self.parser.advance();
+            member = intern(self.previous().lexeme);
+        }
+        if to_str(member).starts_with('#') {
+            self.declare_private(member, accessor);
+        }
+
+        if !self.parser.check(TokenType::LeftParen) {
+            if accessor.is_some() {
+                self.error("Expected '(' after accessor name.");
+            }
+            let (function_type, fields) = if is_static {
+                (FunctionType::Method, &mut body.static_fields)
+            } else {
+                (FunctionType::Constructor, &mut body.fields)
+            };
+            self.field(body.name, member, function_type, fields);
+            return;
+        }
+
+        if to_str(member) == "constructor" && !is_static && accessor.is_none() {
+            if body.has_constructor {
+                self.error("Multiple constructor implementations are not allowed.");
+            }
+            body.has_constructor = true;
+            self.begin_constructor(body.name, body.is_derived);
+            self.function_body(None);
+            self.emit_byte(OpCode::CONSTRUCTOR);
+            return;
+        }
+
+        self.begin_function(FunctionType::Method, Some(member));
+        match accessor {
+            Some(is_setter) => self.accessor_body(is_setter),
+            None => self.function_body(None),
+        }
+        // The VM stores private names under the key of the class being evaluated
+        self.emit_byte(match (is_static, accessor) {
+            (false, None) => OpCode::METHOD(member),
+            (false, Some(false)) => OpCode::GETTER(member),
+            (false, Some(true)) => OpCode::SETTER(member),
+            (true, None) => OpCode::STATIC_METHOD(member),
+            (true, Some(false)) => OpCode::STATIC_GETTER(member),
+            (true, Some(true)) => OpCode::STATIC_SETTER(member),
+        });
+    }
+
+    /// A getter and a setter may share a private name, anything else declares it only once
+    fn declare_private(&mut self, name: StrId, accessor: Option<bool>) {
+        let declares = match accessor {
+            Some(false) => PRIVATE_GETTER,
+            Some(true) => PRIVATE_SETTER,
+            None => PRIVATE_GETTER | PRIVATE_SETTER,
+        };
+        let class = self.class.as_mut().unwrap();
+        let declared = class.private_names.entry(name).or_insert(0);
+        let is_duplicate = *declared & declares != 0;
+        *declared |= declares;
+        if is_duplicate {
+            self.error(&format!("Duplicate identifier '{}'.", to_str(name)));
+        }
+    }
+
+    /// `static`, `get` and `set` only modify a member when another name follows them
+    fn is_modifier(&mut self, member: StrId, modifier: &str) -> bool {
+        self.parser.check(TokenType::Identifier) && to_str(member) == modifier
+    }
+
+    fn accessor_body(&mut self, is_setter: bool) {
+        self.consume(TokenType::LeftParen, "Expected '(' after accessor name.");
+        self.parameters();
+        let has_parameters = self.function.arity > 0 || self.function.has_rest;
+        if is_setter && (self.function.arity != 1 || self.function.has_rest) {
+            self.error("A 'set' accessor must have exactly one parameter.");
+        } else if !is_setter && has_parameters {
+            self.error("A 'get' accessor cannot have parameters.");
+        }
+        self.consume(TokenType::LeftBrace, "Expected '{' before accessor body.");
+        self.block();
+        self.end_function();
+    }
+
+    /// Field initializers from the whole class body are gathered into one function,
+    /// which is set aside while the methods in between are compiled
+    fn field(
+        &mut self,
+        class: StrId,
+        name: StrId,
+        function_type: FunctionType,
+        fields: &mut Option<Compiler>,
+    ) {
+        match fields.take() {
+            Some(fields) => self.resume_function(fields),
+            None => self.begin_function(function_type, Some(class)),
+        }
+        self.emit_byte(OpCode::GET_LOCAL(0));
+        if self.parser.match_next(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::UNDEFINED);
+        }
+        let (_, set_ops) = self.property_ops(name);
+        self.emit_ops(&set_ops);
+        self.emit_byte(OpCode::POP);
+        self.parser.match_next(TokenType::Semicolon);
+        *fields = Some(self.suspend_function());
+    }
+
+    /// Base class constructors start by running the field initializers of their own class,
+    /// derived ones do so once `super(...)` returns
+    fn begin_constructor(&mut self, class: StrId, is_derived: bool) {
+        self.begin_function(FunctionType::Constructor, Some(class));
+        let class = self.resolve_upvalue(class).unwrap();
+        self.constructor = Some(ConstructorState {
+            class,
+            is_derived,
+            super_called: false,
+        });
+        if !is_derived {
+            self.emit_bytes(OpCode::GET_UPVALUE(class), OpCode::GET_LOCAL(0));
+            self.emit_bytes(OpCode::INIT_FIELDS, OpCode::POP);
+        }
+    }
+
+    /// `constructor(...args) { super(...args); }`
+    fn implicit_constructor(&mut self, class: StrId) {
+        self.begin_constructor(class, true);
+        self.function.has_rest = true;
+        self.declare_local(intern("args"), VarKind::Let);
+        self.mark_initialized();
+
+        let class = self.super_call_target().unwrap();
+        self.emit_byte(OpCode::GET_UPVALUE(class));
+        self.load_super();
+        self.emit_bytes(OpCode::GET_LOCAL(0), OpCode::GET_LOCAL(1));
+        self.emit_bytes(OpCode::SUPER_CALL_SPREAD, OpCode::INIT_FIELDS);
+        self.emit_byte(OpCode::POP);
+        self.end_function();
+        self.emit_byte(OpCode::CONSTRUCTOR);
+    }
+
+    /// `(a, b) => ...` once the '(' is consumed, or `a => ...` once `a` is
+    fn arrow_function(&mut self, parameter: Option<StrId>) {
+        self.begin_function(FunctionType::Arrow, None);
+        match parameter {
+            Some(name) => {
+                self.function.arity += 1;
+                self.declare_local(name, VarKind::Let);
+                self.mark_initialized();
+            }
+            None => self.parameters(),
+        }
+        self.consume(TokenType::Arrow, "Expected '=>' after parameters.");
+
+        if self.parser.match_next(TokenType::LeftBrace) {
+            self.block();
+        } else {
+            // A concise body is returned implicitly
+            self.expression();
+            self.emit_byte(OpCode::RETURN);
+        }
+        self.end_function();
+    }
+
+    fn begin_function(&mut self, function_type: FunctionType, name: Option<StrId>) {
+        // The nested compiler borrows the parser and the known globals until it's done
+        let parser = mem::replace(&mut self.parser, Parser::new(""));
+        let globals = mem::take(&mut self.globals);
+        let inner = Compiler::new_function(parser, globals, function_type, name);
+        let enclosing = mem::replace(self, inner);
+        self.enclosing = Some(Box::new(enclosing));
+        self.begin_scope();
+    }
+
+    /// Hand the parser back to the enclosing compiler without finishing the function
+    fn suspend_function(&mut self) -> Compiler {
+        let enclosing = self.enclosing.take().unwrap();
+        let mut inner = mem::replace(self, *enclosing);
+        self.parser = mem::replace(&mut inner.parser, Parser::new(""));
+        self.globals = mem::take(&mut inner.globals);
+        inner
+    }
+
+    fn resume_function(&mut self, mut inner: Compiler) {
+        inner.parser = mem::replace(&mut self.parser, Parser::new(""));
+        inner.globals = mem::take(&mut self.globals);
+        let enclosing = mem::replace(self, inner);
+        self.enclosing = Some(Box::new(enclosing));
+    }
+
+    fn end_function(&mut self) {
+        if let Some(ConstructorState {
+            is_derived: true,
+            super_called: false,
+            ..
+        }) = self.constructor
+        {
+            self.error("Constructors for derived classes must contain a 'super' call.");
+        }
+        let function = self.end_compiliation();
+        let enclosing = self.enclosing.take().unwrap();
+        let inner = mem::replace(self, *enclosing);
+        self.parser = inner.parser;
+        self.globals = inner.globals;
+
+        let constant = self
+            .current_chunk_mut()
+            .add_constant(Value::Function(function));
+        self.emit_byte(OpCode::CLOSURE(constant));
+    }
+
+    /// Parse parameters up to and including the closing ')'
+    fn parameters(&mut self) {
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                if self.parser.match_next(TokenType::DotDotDot) {
+                    let parameter = self.parse_variable(VarKind::Let, "Expected parameter name.");
+                    self.define_variable(parameter, VarKind::Let);
+                    self.function.has_rest = true;
+                    if !self.parser.match_next(TokenType::Comma) {
+                        break;
+                    }
+                    // Keep parsing the remaining parameters so the error doesn't cascade
+                    self.error("A rest parameter must be last in a parameter list.");
+                    continue;
+                }
+
+                self.function.arity += 1;
+                let parameter = self.parse_variable(VarKind::Let, "Expected parameter name.");
+                self.define_variable(parameter, VarKind::Let);
+                // Missing arguments arrive as undefined, so `a?` needs nothing more
+                self.parser.match_next(TokenType::Question);
+                if self.parser.match_next(TokenType::Equal) {
+                    self.default_parameter(self.locals.len() - 1);
+                }
+                if !self.parser.match_next(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expected ')' after parameters.");
+    }
+
+    /// Replace an undefined argument in `slot` with the value of the default expression
+    fn default_parameter(&mut self, slot: usize) {
+        self.emit_byte(OpCode::GET_LOCAL(slot));
+        self.emit_byte(OpCode::UNDEFINED);
+        self.emit_byte(OpCode::EQUAL);
+        let skip_jump = self.emit_jump(OpCode::JUMP_IF_FALSE(0));
+        self.emit_byte(OpCode::POP);
+        self.expression();
+        self.emit_byte(OpCode::SET_LOCAL(slot));
+        // Both paths leave a single value behind
+        self.patch_jump(skip_jump);
+        self.emit_byte(OpCode::POP);
+    }
+
+    fn parse_variable(&mut self, kind: VarKind, message: &str) -> StrId {
+        self.consume(TokenType::Identifier, message);
+        let name = intern(self.previous().lexeme);
+        self.check_not_private(name);
+        if self.is_local(kind) {
+            if self.hoisted_local(name, kind).is_none() {
+                self.declare_local(name, kind);
+            }
+        } else {
+            self.declare_global(name, kind);
+        }
+        name
+    }
+
+    // `var` is function scoped, so in the script it always lands in the globals
+    fn is_local(&self, kind: VarKind) -> bool {
+        self.function_type != FunctionType::Script || (self.scope_depth > 0 && kind != VarKind::Var)
+    }
+
+    /// Declare the names a block declares before compiling it, so they refer to the same
+    /// variable throughout it, and compile its function declarations so they can be called
+    /// from before they appear
+    fn hoist_declarations(&mut self, function_body: bool) {
+        let declarations = self.parser.block_declarations(function_body);
+        for declaration in declarations.iter() {
+            let kind = match declaration.kind {
+                TokenType::Var => VarKind::Var,
+                TokenType::Const => VarKind::Const,
+                TokenType::Function => VarKind::Function,
+                _ => VarKind::Let,
+            };
+            let name = intern(&declaration.name);
+            // Names declared twice keep the one slot, and so does a `var` naming a parameter.
+            // Other names clashing with a parameter are reported where they're declared
+            let declared = match kind {
+                VarKind::Var => self.hoisted_local(name, kind).is_some(),
+                _ => self
+                    .locals
+                    .iter()
+                    .any(|local| local.name == name && local.depth == self.scope_depth),
+            };
+            if !self.is_local(kind) || declared {
+                continue;
+            }
+            self.emit_byte(OpCode::UNDEFINED);
+            self.locals.push(Local {
+                name,
+                depth: self.scope_depth,
+                // Only block-scoped variables can't be used before their declaration
+                initialized: matches!(kind, VarKind::Var | VarKind::Function),
+                hoisted: true,
+                kind,
+                is_captured: false,
+            });
+        }
+
+        let resume = self.parser.checkpoint();
+        for declaration in declarations {
+            if let Some((start, at)) = declaration.function {
+                self.parser.rewind(at);
+                self.fun_declaration();
+                self.parser.synchronize();
+                self.hoisted_functions.insert(start, self.parser.checkpoint());
+            }
+        }
+        self.parser.rewind(resume);
+    }
+
+    /// Whether a `var` named `name` is already declared, in the function or in the script
+    fn is_declared_var(&self, name: StrId) -> bool {
+        if self.is_local(VarKind::Var) {
+            self.hoisted_local(name, VarKind::Var).is_some()
+        } else {
+            self.globals.get(&name) == Some(&VarKind::Var)
+        }
+    }
+
+    /// The slot set aside for a variable when its block began, if it's still to be declared
+    fn hoisted_local(&self, name: StrId, kind: VarKind) -> Option<usize> {
+        // `var` belongs to the function's outermost scope, which it shares with the parameters
+        let depth = match kind {
+            VarKind::Var => 1,
+            _ => self.scope_depth,
+        };
+        let slot = self
+            .locals
+            .iter()
+            .rposition(|local| local.name == name && local.depth == depth)?;
+        let local = &self.locals[slot];
+        let hoisted = match kind {
+            // A parameter, or a `var` set aside, either way undefined at worst
+            VarKind::Var => local.initialized,
+            VarKind::Function => local.hoisted && local.kind == kind,
+            _ => local.hoisted && local.kind == kind && !local.initialized,
+        };
+        hoisted.then_some(slot)
+    }
+
+    fn declare_local(&mut self, name: StrId, kind: VarKind) {
+        let redeclared = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth >= self.scope_depth)
+            .any(|local| local.name == name);
+        if redeclared {
+            self.error(&format!(
+                "Cannot redeclare block-scoped variable '{}'.",
+                to_str(name)
+            ));
+        }
+        self.check_used_before_declaration(name);
+        self.locals.push(Local {
+            name,
+            depth: self.scope_depth,
+            initialized: false,
+            hoisted: false,
+            kind,
+            is_captured: false,
+        });
+    }
+
+    /// Report the first use of `name` before the declaration just parsed, at the use
+    fn check_used_before_declaration(&mut self, name: StrId) {
+        let scope_depth = self.scope_depth;
+        let read = self
+            .reads
+            .iter()
+            .find(|(read, depth, _)| *read == name && *depth >= scope_depth);
+        if let Some((_, _, span)) = read {
+            let diagnostic = Diagnostic::error(
+                ErrorCode::InvalidSyntax,
+                format!(
+                    "Block-scoped variable '{}' used before its declaration.",
+                    to_str(name)
+                ),
+                span.clone(),
+            )
+            .with_note(format!(
+                "'{}' is declared on line {}",
+                to_str(name),
+                self.previous().line
+            ));
+            self.parser.report(diagnostic);
+        }
+    }
+
+    fn declare_global(&mut self, name: StrId, kind: VarKind) {
+        // `var` may be redeclared by another `var`, block-scoped declarations never
+        if let Some(existing) = self.globals.get(&name) {
+            if kind != VarKind::Var || *existing != VarKind::Var {
+                self.error(&format!(
+                    "Cannot redeclare block-scoped variable '{}'.",
+                    to_str(name)
+                ));
+                return;
+            }
+        }
+        if kind != VarKind::Var {
+            self.check_used_before_declaration(name);
+        }
+        self.globals.insert(name, kind);
+    }
+
+    fn define_variable(&mut self, name: StrId, kind: VarKind) {
+        if !self.is_local(kind) {
+            match kind {
+                VarKind::Const => self.emit_byte(OpCode::DEFINE_CONST(name)),
+                _ => self.emit_byte(OpCode::DEFINE_GLOBAL(name)),
+            }
+        } else if let Some(slot) = self.hoisted_local(name, kind) {
+            // The value goes into the slot set aside when the block began
+            self.emit_bytes(OpCode::SET_LOCAL(slot), OpCode::POP);
+            self.locals[slot].initialized = true;
+        } else {
+            // The value is already in the local's stack slot
+            self.mark_initialized();
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        if let Some(local) = self.locals.last_mut() {
+            local.initialized = true;
+        }
+    }
+
+    fn statement(&mut self) {
+        match () {
+            _ if self.parser.match_next(TokenType::Print) => self.print_statement(),
+            _ if self.parser.match_next(TokenType::If) => self.if_statement(),
+            _ if self.parser.match_next(TokenType::While) => self.while_statement(),
+            _ if self.parser.match_next(TokenType::Do) => self.do_while_statement(),
+            _ if self.parser.match_next(TokenType::For) => self.for_statement(),
+            _ if self.parser.match_next(TokenType::Break) => self.break_statement(),
+            _ if self.parser.match_next(TokenType::Continue) => self.continue_statement(),
+            _ if self.parser.check(TokenType::Identifier)
+                && self.parser.check_next(TokenType::Colon) =>
+            {
+                self.labelled_statement()
+            }
+            _ if self.parser.match_next(TokenType::Return) => self.return_statement(),
+            _ if self.parser.match_next(TokenType::LeftBrace) => {
+                self.begin_scope();
+                self.block();
+                self.end_scope();
+            }
+            _ => self.expression_statement(),
+        };
+    }
+
+    fn block(&mut self) {
+        // A function's outermost block shares depth 1 with its parameters
+        let function_body = self.function_type != FunctionType::Script && self.scope_depth == 1;
+        self.hoist_declarations(function_body);
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::EOF) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+
+        let scope_depth = self.scope_depth;
+        self.discard_locals(scope_depth);
+        while let Some(local) = self.locals.last() {
+            if local.depth <= scope_depth {
+                break;
+            }
+            self.locals.pop();
+        }
+
+        // Reads inside the block count as reads of the enclosing scope
+        for (_, depth, _) in self.reads.iter_mut() {
+            if *depth > scope_depth {
+                *depth = scope_depth;
+            }
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expected '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expected ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JUMP_IF_FALSE(0));
+        self.emit_byte(OpCode::POP);
+        self.statement();
+        let else_jump = self.emit_jump(OpCode::JUMP(0));
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::POP);
+        // `else if` is just an `if` statement as the else branch
+        if self.parser.match_next(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    /// Emit the pops for every local deeper than `depth`, leaving the compiler's view untouched
+    fn discard_locals(&mut self, depth: usize) {
+        let discarded: Vec<bool> = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .map(|local| local.is_captured)
+            .collect();
+        for is_captured in discarded {
+            if is_captured {
+                self.emit_byte(OpCode::CLOSE_UPVALUE);
+            } else {
+                self.emit_byte(OpCode::POP);
+            }
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk_mut().code.len();
+        self.consume(TokenType::LeftParen, "Expected '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expected ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JUMP_IF_FALSE(0));
+        self.emit_byte(OpCode::POP);
+        self.begin_loop(true, Some(loop_start));
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::POP);
+        self.end_loop();
+    }
+
+    fn do_while_statement(&mut self) {
+        let loop_start = self.current_chunk_mut().code.len();
+        self.begin_loop(true, None);
+        self.statement();
+
+        // The condition comes after the body, so pending continues jump forward to it
+        self.patch_continues();
+        self.consume(
+            TokenType::While,
+            "Expected 'while' after do statement body.",
+        );
+        self.consume(TokenType::LeftParen, "Expected '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expected ')' after condition.");
+        self.parser.match_next(TokenType::Semicolon);
+
+        let exit_jump = self.emit_jump(OpCode::JUMP_IF_FALSE(0));
+        self.emit_byte(OpCode::POP);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::POP);
+        self.end_loop();
+    }
+
+    fn for_statement(&mut self) {
+        // The loop variable is scoped to the loop
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expected '(' after 'for'.");
+        let first_local = self.locals.len();
+        let mut per_iteration = false;
+        match () {
+            _ if self.parser.match_next(TokenType::Semicolon) => {}
+            _ if self.parser.match_next(TokenType::Var) => self.var_declaration(VarKind::Var),
+            _ if self.parser.match_next(TokenType::Let) => {
+                self.var_declaration(VarKind::Let);
+                per_iteration = true;
+            }
+            _ if self.parser.match_next(TokenType::Const) => {
+                self.var_declaration(VarKind::Const);
+                per_iteration = true;
+            }
+            _ => self.expression_statement(),
+        }
+        let loop_variables: Vec<usize> = if per_iteration {
+            (first_local..self.locals.len()).collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut loop_start = self.current_chunk_mut().code.len();
+        let mut exit_jump = None;
+        if !self.parser.match_next(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expected ';' after loop condition.");
+            exit_jump = Some(self.emit_jump(OpCode::JUMP_IF_FALSE(0)));
+            self.emit_byte(OpCode::POP);
+        }
+
+        if !self.parser.match_next(TokenType::RightParen) {
+            // The increment runs after the body, so jump over it on the way in
+            let body_jump = self.emit_jump(OpCode::JUMP(0));
+            let increment_start = self.current_chunk_mut().code.len();
+            self.expression();
+            self.emit_byte(OpCode::POP);
+            self.consume(TokenType::RightParen, "Expected ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        if loop_variables.is_empty() {
+            self.begin_loop(true, Some(loop_start));
+            self.statement();
+        } else {
+            self.per_iteration_body(&loop_variables);
+        }
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::POP);
+        }
+        self.end_loop();
+        self.end_scope();
+    }
+
+    /// Compile a `for` body against fresh copies of the `let` loop variables, copied back
+    /// once the iteration is done, so closures created in each iteration see their own binding
+    fn per_iteration_body(&mut self, loop_variables: &[usize]) {
+        let loop_depth = self.scope_depth;
+        self.begin_scope();
+        let first_copy = self.locals.len();
+        for &slot in loop_variables {
+            self.emit_byte(OpCode::GET_LOCAL(slot));
+            let (name, kind) = (self.locals[slot].name, self.locals[slot].kind);
+            self.locals.push(Local {
+                name,
+                depth: self.scope_depth,
+                initialized: true,
+                hoisted: false,
+                kind,
+                is_captured: false,
+            });
+        }
+
+        self.begin_loop(true, None);
+        // Breaking out also has to discard the copies
+        self.loops.last_mut().unwrap().scope_depth = loop_depth;
+        self.statement();
+
+        self.patch_continues();
+        for (copy, &slot) in loop_variables.iter().enumerate() {
+            self.emit_byte(OpCode::GET_LOCAL(first_copy + copy));
+            self.emit_byte(OpCode::SET_LOCAL(slot));
+            self.emit_byte(OpCode::POP);
+        }
+        self.end_scope();
+    }
+
+    fn labelled_statement(&mut self) {
+        self.parser.advance();
+        let label = intern(self.previous().lexeme);
+        self.check_not_private(label);
+        self.consume(TokenType::Colon, "Expected ':' after label.");
+        self.pending_labels.push(label);
 
-    // locals: Vec<Local>,
-    // scope_depth: usize,
-    // upvalues: SmallVec<[Upvalue; 3]>,
+        // Loops pick up the pending labels themselves, and so do chained labels
+        let is_loop = matches!(
+            self.current().t_type,
+            TokenType::While | TokenType::Do | TokenType::For
+        );
+        let is_label =
+            self.parser.check(TokenType::Identifier) && self.parser.check_next(TokenType::Colon);
+        if is_loop || is_label {
+            self.statement();
+        } else {
+            self.begin_loop(false, None);
+            self.statement();
+            self.end_loop();
+        }
+    }
 
-    // enclosing: Option<Box<Compiler>>,
-    // class_stack: MutRc<Vec<ClassCompile>>,
-}
+    fn break_statement(&mut self) {
+        let keyword = self.previous().span();
+        let label = self.jump_label();
+        let target = self.loops.iter().rposition(|target| match label {
+            Some(label) => target.labels.contains(&label),
+            None => target.is_iteration,
+        });
 
-impl<'a> Compiler<'a> {
-    pub fn new(code: &str) -> Compiler<'a> {
-        Compiler {
-            parser: Parser::new(code),
-            compiling_chunk: None, // temporary
+        match (target, label) {
+            (Some(target), _) => {
+                let depth = self.loops[target].scope_depth;
+                self.discard_locals(depth);
+                let jump = self.emit_jump(OpCode::JUMP(0));
+                self.loops[target].break_jumps.push(jump);
+            }
+            (None, Some(_)) => self.error_at(
+                keyword,
+                "A 'break' statement can only jump to a label of an enclosing statement.",
+            ),
+            (None, None) => self.error_at(
+                keyword,
+                "A 'break' statement can only be used within an enclosing iteration statement.",
+            ),
         }
     }
 
-    // pub fn compile(&mut self, source: String, chunk: &Chunk) {
-    pub fn compile(&mut self, chunk: &'a mut Chunk) {
-        // #[cfg(feature = "log_level_debug")]
-        // Compiler::debug_scanner(self.parser.scanner.source.clone());
-        self.parser.advance();
-        let line = self.get_line();
-        println!("[scanner][line {}] line start", line);
-        self.compiling_chunk = Some(chunk);
+    fn continue_statement(&mut self) {
+        let keyword = self.previous().span();
+        let label = self.jump_label();
+        let target = self.loops.iter().rposition(|target| {
+            target.is_iteration && label.is_none_or(|label| target.labels.contains(&label))
+        });
 
-        while !self.parser.match_next(TokenType::EOF) {
-            let line = self.get_line();
-            println!("[compiler][line {}] compile::(while !EOF)", line);
-            self.declaration();
+        match (target, label) {
+            (Some(target), _) => {
+                let depth = self.loops[target].continue_depth;
+                self.discard_locals(depth);
+                match self.loops[target].continue_target {
+                    Some(continue_target) => self.emit_loop(continue_target),
+                    None => {
+                        let jump = self.emit_jump(OpCode::JUMP(0));
+                        self.loops[target].continue_jumps.push(jump);
+                    }
+                }
+            }
+            (None, Some(_)) => self.error_at(
+                keyword,
+                "A 'continue' statement can only jump to a label of an enclosing iteration statement.",
+            ),
+            (None, None) => self.error_at(
+                keyword,
+                "A 'continue' statement can only be used within an enclosing iteration statement.",
+            ),
         }
-        let line = self.get_line();
-        println!("[compiler][line {}] compile::out of (while !EOF)", line);
-        let function = self.end_compiliation();
-        // if self.parser_mut().had_error {
-        //     None
-        // } else {
-        //     Some(Rc::new(Closure {
-        //         function,
-        //         upvalues: SmallVec::new(),
-        //     }))
-        // }
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
+    /// Parse the optional label and the ';' ending a `break` or `continue`
+    fn jump_label(&mut self) -> Option<StrId> {
+        let label = if self.parser.match_next(TokenType::Identifier) {
+            Some(intern(self.previous().lexeme))
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expected ';' after jump statement.");
+        label
     }
 
-    fn declaration(&mut self) {
-        match () {
-            // _ if self.parser.match_next(TokenType::Class) => self.class_declaration(),
-            // _ if self.parser.match_next(TokenType::Var) => self.var_declaration(),
-            // _ if self.parser.match_next(TokenType::Fun) => self.fun_declaration(),
-            _ => self.statement(),
+    fn begin_loop(&mut self, is_iteration: bool, continue_target: Option<usize>) {
+        self.loops.push(Loop {
+            labels: mem::take(&mut self.pending_labels),
+            is_iteration,
+            scope_depth: self.scope_depth,
+            continue_depth: self.scope_depth,
+            continue_target,
+            continue_jumps: Vec::new(),
+            break_jumps: Vec::new(),
+        });
+    }
+
+    fn patch_continues(&mut self) {
+        let continue_jumps = mem::take(&mut self.loops.last_mut().unwrap().continue_jumps);
+        for jump in continue_jumps {
+            self.patch_jump(jump);
         }
+    }
 
-        self.parser.synchronize();
+    fn end_loop(&mut self) {
+        let finished = self.loops.pop().unwrap();
+        for jump in finished.break_jumps {
+            self.patch_jump(jump);
+        }
     }
 
-    fn statement(&mut self) {
-        match () {
-            _ if self.parser.match_next(TokenType::Print) => self.print_statement(),
-            // _ if self.parser.match_next(TokenType::If) => self.if_statement(),
-            // _ if self.parser.match_next(TokenType::While) => self.while_statement(),
-            // _ if self.parser.match_next(TokenType::For) => self.for_statement(),
-            // _ if self.parser.match_next(TokenType::Return) => self.return_statement(),
-            // _ if self.parser.match_next(TokenType::LeftBrace) => {
-            //     self.begin_scope();
-            //     self.block();
-            //     self.end_scope();
-            // }
-            _ => self.expression_statement(),
-        };
+    fn return_statement(&mut self) {
+        if self.function_type == FunctionType::Script {
+            self.error("A 'return' statement can only be used within a function body.");
+        }
+
+        if self.parser.match_next(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.function_type == FunctionType::Constructor {
+                self.error("Return type of constructor signature must be assignable to the instance type of the class.");
+            }
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expected ';' after return value.");
+            self.emit_byte(OpCode::RETURN);
+        }
     }
 
     fn print_statement(&mut self) {
@@ -221,6 +1374,10 @@ impl<'a> Compiler<'a> {
     }
 
     fn grouping(&mut self) {
+        if self.parser.is_arrow_parameters() {
+            self.arrow_function(None);
+            return;
+        }
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
@@ -239,31 +1396,374 @@ impl<'a> Compiler<'a> {
     fn binary(&mut self) {
         // Remember the operator.
         let operator_type: TokenType = self.parser.get_previous().t_type;
+        let operator = self.position();
         // Compile the right operand.
         let rule = Compiler::get_rule(operator_type);
         unsafe {
             self.parse_precedence(Precedence::from_usize(rule.precedence.to_usize() + 1));
         }
-        // Emit the operator instruction.
-        match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::EQUAL, OpCode::NOT),
-            TokenType::EqualEqual => self.emit_byte(OpCode::EQUAL),
-            TokenType::Greater => self.emit_byte(OpCode::GREATER),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::LESS, OpCode::NOT),
-            TokenType::Less => self.emit_byte(OpCode::LESS),
-            TokenType::LessEqual => self.emit_bytes(OpCode::GREATER, OpCode::NOT),
-            TokenType::Plus => self.emit_byte(OpCode::ADD),
-            TokenType::Minus => self.emit_byte(OpCode::SUBTRACT),
-            TokenType::Star => self.emit_byte(OpCode::MULTIPLY),
-            TokenType::Slash => self.emit_byte(OpCode::DIVIDE),
+        // Emit the operator instruction, pointing at the operator rather than the operand.
+        let op_codes: &[OpCode] = match operator_type {
+            TokenType::BangEqual => &[OpCode::EQUAL, OpCode::NOT],
+            TokenType::EqualEqual => &[OpCode::EQUAL],
+            TokenType::Greater => &[OpCode::GREATER],
+            TokenType::GreaterEqual => &[OpCode::LESS, OpCode::NOT],
+            TokenType::Less => &[OpCode::LESS],
+            TokenType::LessEqual => &[OpCode::GREATER, OpCode::NOT],
+            TokenType::Plus => &[OpCode::ADD],
+            TokenType::Minus => &[OpCode::SUBTRACT],
+            TokenType::Star => &[OpCode::MULTIPLY],
+            TokenType::Slash => &[OpCode::DIVIDE],
             _ => return,
+        };
+        for op_code in op_codes {
+            self.emit_byte_at(*op_code, operator);
+        }
+    }
+
+    fn ternary(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JUMP_IF_FALSE(0));
+        self.emit_byte(OpCode::POP);
+        self.parse_precedence(Precedence::Assignment);
+        self.consume(TokenType::Colon, "Expected ':' in conditional expression.");
+        let end_jump = self.emit_jump(OpCode::JUMP(0));
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::POP);
+        // Right associative, so `a ? b : c ? d : e` nests in the else branch
+        self.parse_precedence(Precedence::Ternary);
+        self.patch_jump(end_jump);
+    }
+
+    fn new_(&mut self) {
+        // The callee is a plain member expression, its argument list belongs to `new`
+        self.parse_precedence(Precedence::Primary);
+        while self.parser.match_next(TokenType::Dot) {
+            self.consume(TokenType::Identifier, "Expected property name after '.'.");
+            let (get_ops, _) = self.property_ops(intern(self.previous().lexeme));
+            self.emit_ops(&get_ops);
+        }
+
+        if !self.parser.match_next(TokenType::LeftParen) {
+            self.emit_byte(OpCode::NEW(0));
+            return;
+        }
+        match self.argument_list() {
+            Some(arg_count) => self.emit_byte(OpCode::NEW(arg_count)),
+            None => self.emit_byte(OpCode::NEW_SPREAD),
+        }
+    }
+
+    fn call(&mut self) {
+        let paren = self.position();
+        match self.argument_list() {
+            Some(arg_count) => self.emit_byte_at(OpCode::CALL(arg_count), paren),
+            None => self.emit_byte_at(OpCode::CALL_SPREAD, paren),
+        }
+    }
+
+    /// A call on the object a property was just read from, once the '(' is consumed
+    fn invoke(&mut self) {
+        let paren = self.position();
+        match self.argument_list() {
+            Some(arg_count) => self.emit_byte_at(OpCode::INVOKE(arg_count), paren),
+            None => self.emit_byte_at(OpCode::INVOKE_SPREAD, paren),
+        }
+    }
+
+    /// Returns the argument count, or None once a spread has collected them into an array
+    fn argument_list(&mut self) -> Option<usize> {
+        let mut arg_count = 0;
+        let mut spread = false;
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                if self.parser.match_next(TokenType::DotDotDot) {
+                    if !spread {
+                        self.emit_byte(OpCode::ARRAY(arg_count));
+                        spread = true;
+                    }
+                    self.expression();
+                    self.emit_byte(OpCode::ARRAY_SPREAD);
+                } else {
+                    self.expression();
+                    if spread {
+                        self.emit_byte(OpCode::ARRAY_PUSH);
+                    }
+                }
+                arg_count += 1;
+                if !self.parser.match_next(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expected ')' after arguments.");
+        if spread {
+            None
+        } else {
+            Some(arg_count)
+        }
+    }
+
+    /// `[1, , ...rest]` once the '[' is consumed. Elements go on the stack until a hole
+    /// or spread needs the array itself, later ones are pushed onto it
+    fn array(&mut self) {
+        let mut count = 0;
+        let mut is_built = false;
+        while !self.parser.check(TokenType::RightBracket) && !self.parser.check(TokenType::EOF) {
+            let is_hole = self.parser.check(TokenType::Comma);
+            let is_spread = self.parser.match_next(TokenType::DotDotDot);
+            if (is_hole || is_spread) && !is_built {
+                self.emit_byte(OpCode::ARRAY(count));
+                is_built = true;
+            }
+            if is_hole {
+                self.emit_byte(OpCode::ARRAY_HOLE);
+            } else {
+                self.expression();
+                if is_spread {
+                    self.emit_byte(OpCode::ARRAY_SPREAD);
+                } else if is_built {
+                    self.emit_byte(OpCode::ARRAY_PUSH);
+                } else {
+                    count += 1;
+                }
+            }
+            if !self.parser.match_next(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(
+            TokenType::RightBracket,
+            "Expected ']' after array elements.",
+        );
+        if !is_built {
+            self.emit_byte(OpCode::ARRAY(count));
+        }
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expected property name after '.'.");
+        let name = intern(self.previous().lexeme);
+        let (get_ops, set_ops) = self.property_ops(name);
+        if can_assign && self.parser.match_next(TokenType::Equal) {
+            self.expression();
+            self.emit_ops(&set_ops);
+        } else if can_assign && self.match_logical_assignment() {
+            // The object stays below for the assignment, under a copy to read from
+            let get_ops = [&[OpCode::DUPLICATE(0)], &get_ops[..]].concat();
+            self.logical_assignment(&get_ops, &set_ops, 1);
+        } else if matches!(get_ops[..], [OpCode::GET_PROPERTY(_)])
+            && self.parser.match_next(TokenType::LeftParen)
+        {
+            self.emit_byte(OpCode::GET_METHOD(name));
+            self.invoke();
+        } else {
+            self.emit_ops(&get_ops);
+        }
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expected ']' after index.");
+        if can_assign && self.parser.match_next(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::SET_INDEX);
+        } else if can_assign && self.match_logical_assignment() {
+            let get_ops = [OpCode::DUPLICATE(1), OpCode::DUPLICATE(1), OpCode::GET_INDEX];
+            self.logical_assignment(&get_ops, &[OpCode::SET_INDEX], 2);
+        } else if self.parser.match_next(TokenType::LeftParen) {
+            self.emit_byte(OpCode::GET_INDEX_METHOD);
+            self.invoke();
+        } else {
+            self.emit_byte(OpCode::GET_INDEX);
+        }
+    }
+
+    /// `{ a: 1, b, [key]: 2, m() {}, ...other }` once the '{' is consumed
+    fn object(&mut self) {
+        self.emit_byte(OpCode::OBJECT);
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::EOF) {
+            if self.parser.match_next(TokenType::DotDotDot) {
+                self.expression();
+                self.emit_byte(OpCode::OBJECT_SPREAD);
+            } else if self.parser.match_next(TokenType::LeftBracket) {
+                self.expression();
+                self.consume(
+                    TokenType::RightBracket,
+                    "Expected ']' after computed property name.",
+                );
+                self.property_value(None);
+            } else {
+                self.property();
+            }
+            if !self.parser.match_next(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after object literal.");
+    }
+
+    fn property(&mut self) {
+        self.parser.advance();
+        let token = self.previous();
+        let name = match token.t_type {
+            TokenType::String => intern(&token.lexeme[1..token.lexeme.len() - 1]),
+            // Numeric keys are stored the way the number prints
+            TokenType::Number => {
+                intern(Value::ValNumber(token.lexeme.parse().unwrap()).to_string())
+            }
+            // Keywords are fine as property names too
+            _ if token
+                .lexeme
+                .starts_with(|c: char| c.is_alphabetic() || c == '_') =>
+            {
+                intern(&token.lexeme)
+            }
+            _ => {
+                self.error("Property assignment expected.");
+                return;
+            }
+        };
+        let is_identifier = token.t_type == TokenType::Identifier;
+
+        if to_str(name) == "__proto__" && self.parser.match_next(TokenType::Colon) {
+            self.expression();
+            self.emit_byte(OpCode::PROTOTYPE);
+        } else if is_identifier
+            && !self.parser.check(TokenType::Colon)
+            && !self.parser.check(TokenType::LeftParen)
+        {
+            // Shorthand `{ a }` for `{ a: a }`
+            self.emit_string(name);
+            self.named_variable(name, false);
+            self.emit_byte(OpCode::INIT_PROPERTY);
+        } else {
+            self.emit_string(name);
+            self.property_value(Some(name));
+        }
+    }
+
+    fn property_value(&mut self, name: Option<StrId>) {
+        if self.parser.check(TokenType::LeftParen) {
+            self.begin_function(FunctionType::Method, name);
+            self.function_body(None);
+        } else {
+            self.consume(TokenType::Colon, "Expected ':' after property name.");
+            self.expression();
+        }
+        self.emit_byte(OpCode::INIT_PROPERTY);
+    }
+
+    /// What reads property `name` off the object on top of the stack, and what stores the
+    /// value on top into the object below it
+    fn property_ops(&mut self, name: StrId) -> (Vec<OpCode>, Vec<OpCode>) {
+        if !to_str(name).starts_with('#') {
+            return (
+                vec![OpCode::GET_PROPERTY(name)],
+                vec![OpCode::SET_PROPERTY(name)],
+            );
+        }
+        let load_class = self.private_class(name);
+        (
+            vec![load_class, OpCode::GET_PRIVATE(name)],
+            vec![load_class, OpCode::SET_PRIVATE(name)],
+        )
+    }
+
+    /// The instruction loading the class that declared a private name
+    fn private_class(&mut self, name: StrId) -> OpCode {
+        if let Some(binding) = self.resolve_private(name) {
+            if let Some(slot) = self.resolve_local(binding) {
+                return OpCode::GET_LOCAL(slot);
+            }
+            if let Some(index) = self.resolve_upvalue(binding) {
+                return OpCode::GET_UPVALUE(index);
+            }
+        }
+        let message = match self.enclosing_class() {
+            Some(class) => format!(
+                "Property '{}' does not exist on type '{}'.",
+                to_str(name),
+                to_str(class.name)
+            ),
+            None => String::from("Private identifiers are not allowed outside class bodies."),
+        };
+        self.error(&message);
+        OpCode::UNDEFINED
+    }
+
+    /// Report a private name used for anything other than a class member
+    fn check_not_private(&mut self, name: StrId) {
+        if !to_str(name).starts_with('#') {
+            return;
+        }
+        match self.enclosing_class() {
+            Some(_) => self.error("Private identifiers are only allowed in class bodies and may only be used as part of a class member declaration, property access, or on the left-hand-side of an 'in' expression"),
+            None => self.error("Private identifiers are not allowed outside class bodies."),
+        }
+    }
+
+    /// The binding of the class that declared a private name
+    fn resolve_private(&self, name: StrId) -> Option<StrId> {
+        match &self.class {
+            Some(class) if class.private_names.contains_key(&name) => Some(class.binding()),
+            _ => self.enclosing.as_ref()?.resolve_private(name),
+        }
+    }
+
+    fn enclosing_class(&self) -> Option<&ClassState> {
+        match &self.class {
+            Some(class) => Some(class),
+            None => self.enclosing.as_ref()?.enclosing_class(),
+        }
+    }
+
+    // Logical operators evaluate to the operand that decided the result, as in JS
+    fn and(&mut self) {
+        let end_jump = self.emit_jump(OpCode::JUMP_IF_FALSE(0));
+        self.emit_byte(OpCode::POP);
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JUMP_IF_FALSE(0));
+        let end_jump = self.emit_jump(OpCode::JUMP(0));
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::POP);
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn nullish(&mut self) {
+        let end_jump = self.emit_jump(OpCode::JUMP_IF_NOT_NULLISH(0));
+        self.emit_byte(OpCode::POP);
+        // Leaves `||` and `&&` for `parse_precedence` to reject, as they need parentheses
+        self.parse_precedence(Precedence::Equality);
+        self.patch_jump(end_jump);
+    }
+
+    /// `??` can't share an operand with `||` or `&&` unless parentheses say which goes first
+    fn check_nullish_mix(&mut self, left: TokenType, right: TokenType) {
+        let logical = |operator| match operator {
+            TokenType::Or => Some("||"),
+            TokenType::And => Some("&&"),
+            _ => None,
+        };
+        let other = match (left, right) {
+            (TokenType::QuestionQuestion, other) | (other, TokenType::QuestionQuestion) => {
+                logical(other)
+            }
+            _ => None,
+        };
+        if let Some(other) = other {
+            self.error(&format!(
+                "'{}' and '??' operations cannot be mixed without parentheses.",
+                other
+            ));
         }
     }
 
-    fn call(&mut self) {}
-    fn dot(&mut self, _can_assign: bool) {}
-    fn and(&mut self) {}
-    fn or(&mut self) {}
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.parser.advance();
 
@@ -278,6 +1778,7 @@ impl<'a> Compiler<'a> {
             return;
         }
 
+        let mut previous_operator = None;
         while precedence.to_usize()
             <= Compiler::get_rule(self.current().t_type)
                 .precedence
@@ -285,6 +1786,11 @@ impl<'a> Compiler<'a> {
         {
             // do stuf
             self.parser.advance();
+            let operator = self.previous().t_type;
+            if let Some(previous_operator) = previous_operator {
+                self.check_nullish_mix(previous_operator, operator);
+            }
+            previous_operator = Some(operator);
             let infix_rule = Compiler::get_rule(self.parser.get_previous().t_type).infix;
             match infix_rule {
                 Some(infix_rule_exec) => infix_rule_exec(self, can_assign),
@@ -292,16 +1798,19 @@ impl<'a> Compiler<'a> {
             }
         }
 
-        // if (can_assign && self.match_next(TokenType::Equal)) {
-        //     self.error("Invalid assignment target.");
-        //     self.expression();
-        // }
+        if can_assign
+            && (self.parser.match_next(TokenType::Equal) || self.match_logical_assignment())
+        {
+            self.error("Invalid assignment target.");
+            self.expression();
+        }
     }
 
     fn literal(&mut self) {
         match self.parser.get_previous().t_type {
             TokenType::False => self.emit_byte(OpCode::FALSE),
             TokenType::Null => self.emit_byte(OpCode::NULL),
+            TokenType::Undefined => self.emit_byte(OpCode::UNDEFINED),
             TokenType::True => self.emit_byte(OpCode::TRUE),
             TokenType::Number => {
                 // let value: f64 = (self.parser.get_previous().lexeme)
@@ -314,9 +1823,7 @@ impl<'a> Compiler<'a> {
                     .lexeme
                     .parse::<f64>()
                     .expect("Not a number!");
-                // self.compiling_chunk().add_constant(value);
-
-                self.emit_byte(OpCode::NUMBER(value));
+                self.emit_constant(Value::ValNumber(value));
             }
             _ => return,
         }
@@ -324,15 +1831,278 @@ impl<'a> Compiler<'a> {
 
     fn string(&mut self) {
         let token = self.previous();
-        self.emit_byte(OpCode::STRING(intern(token.lexeme)))
+        // Trim the surrounding quotes
+        let value = &token.lexeme[1..token.lexeme.len() - 1];
+        self.emit_string(intern(value));
+    }
+
+    fn this(&mut self) {
+        if let Some(ConstructorState {
+            is_derived: true,
+            super_called: false,
+            ..
+        }) = self.constructor
+        {
+            self.error("'super' must be called before accessing 'this' in the constructor of a derived class.");
+        }
+        // Outside of a function `this` is undefined, as in strict mode
+        match self.resolve_this() {
+            Some(op_code) => self.emit_byte(op_code),
+            None => self.emit_byte(OpCode::UNDEFINED),
+        }
+    }
+
+    fn resolve_this(&mut self) -> Option<OpCode> {
+        if let Some(slot) = self.find_local(intern("this")) {
+            return Some(OpCode::GET_LOCAL(slot));
+        }
+        if self.function_type != FunctionType::Arrow {
+            return None;
+        }
+        self.resolve_this_upvalue().map(OpCode::GET_UPVALUE)
+    }
+
+    /// Arrow functions capture `this` from the closest enclosing function that has one,
+    /// stopping at the first regular function
+    fn resolve_this_upvalue(&mut self) -> Option<usize> {
+        let this = intern("this");
+        let enclosing = self.enclosing.as_mut()?;
+        if let Some(slot) = enclosing.find_local(this) {
+            enclosing.locals[slot].is_captured = true;
+            return Some(self.add_upvalue(true, slot, VarKind::Const));
+        }
+        if enclosing.function_type != FunctionType::Arrow {
+            return None;
+        }
+        let index = enclosing.resolve_this_upvalue()?;
+        Some(self.add_upvalue(false, index, VarKind::Const))
+    }
+    fn super_(&mut self) {
+        if self.parser.match_next(TokenType::LeftParen) {
+            self.super_call();
+            return;
+        }
+        self.consume(TokenType::Dot, "Expected '(' or '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expected superclass method name.");
+        let name = intern(self.previous().lexeme);
+        if let Some(ConstructorState {
+            is_derived: true,
+            super_called: false,
+            ..
+        }) = self.constructor
+        {
+            self.error("'super' must be called before accessing a property of 'super' in the constructor of a derived class.");
+        }
+        match self.resolve_this() {
+            Some(op_code) => self.emit_byte(op_code),
+            None => self.emit_byte(OpCode::UNDEFINED),
+        }
+        self.load_super();
+        self.emit_byte(OpCode::GET_SUPER(name));
+    }
+
+    /// Call the superclass constructor on `this`, then run this class's field initializers.
+    /// Evaluates to `this`
+    fn super_call(&mut self) {
+        let class = self.super_call_target().unwrap_or(0);
+        self.emit_byte(OpCode::GET_UPVALUE(class));
+        self.load_super();
+        self.emit_byte(OpCode::GET_LOCAL(0));
+        match self.argument_list() {
+            Some(arg_count) => self.emit_byte(OpCode::SUPER_CALL(arg_count)),
+            None => self.emit_byte(OpCode::SUPER_CALL_SPREAD),
+        }
+        self.emit_byte(OpCode::INIT_FIELDS);
+    }
+
+    fn super_call_target(&mut self) -> Option<usize> {
+        match &mut self.constructor {
+            Some(constructor) if constructor.is_derived => {
+                constructor.super_called = true;
+                Some(constructor.class)
+            }
+            Some(_) => {
+                self.error("'super' can only be referenced in a derived class.");
+                None
+            }
+            None => {
+                self.error("Super calls are not permitted outside constructors or in nested functions inside constructors.");
+                None
+            }
+        }
+    }
+
+    fn load_super(&mut self) {
+        let name = intern("super");
+        if let Some(slot) = self.find_local(name) {
+            self.emit_byte(OpCode::GET_LOCAL(slot));
+        } else if let Some(index) = self.resolve_upvalue(name) {
+            self.emit_byte(OpCode::GET_UPVALUE(index));
+        } else {
+            self.error("'super' can only be referenced in a derived class.");
+        }
+    }
+    fn variable(&mut self, can_assign: bool) {
+        let name = intern(self.previous().lexeme);
+        self.check_not_private(name);
+        if self.parser.check(TokenType::Arrow) {
+            self.arrow_function(Some(name));
+            return;
+        }
+        self.named_variable(name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: StrId, can_assign: bool) {
+        let span = self.previous().span();
+        self.reads.push((name, self.scope_depth, span.clone()));
+
+        let (get_op, set_op, kind) = if let Some(slot) = self.resolve_local(name) {
+            (
+                OpCode::GET_LOCAL(slot),
+                OpCode::SET_LOCAL(slot),
+                Some(self.locals[slot].kind),
+            )
+        } else if let Some(index) = self.resolve_upvalue(name) {
+            (
+                OpCode::GET_UPVALUE(index),
+                OpCode::SET_UPVALUE(index),
+                Some(self.upvalues[index].kind),
+            )
+        } else {
+            (
+                OpCode::GET_GLOBAL(name),
+                OpCode::SET_GLOBAL(name),
+                self.globals.get(&name).copied(),
+            )
+        };
+
+        if can_assign && self.parser.match_next(TokenType::Equal) {
+            self.check_assignable(name, kind, span);
+            self.expression();
+            self.emit_byte(set_op);
+        } else if can_assign && self.match_logical_assignment() {
+            self.check_assignable(name, kind, span);
+            self.logical_assignment(&[get_op], &[set_op], 0);
+        } else {
+            self.emit_byte(get_op);
+        }
+    }
+
+    /// Report an assignment to a constant, at its name
+    fn check_assignable(&mut self, name: StrId, kind: Option<VarKind>, span: Span) {
+        if let Some(VarKind::Const) = kind {
+            self.error_with_help(
+                span,
+                &format!(
+                    "Cannot assign to '{}' because it is a constant.",
+                    to_str(name)
+                ),
+                "declare it with 'let' to be able to assign to it",
+            );
+        }
+    }
+
+    fn match_logical_assignment(&mut self) -> bool {
+        self.parser.match_next(TokenType::AndEqual)
+            || self.parser.match_next(TokenType::OrEqual)
+            || self.parser.match_next(TokenType::QuestionQuestionEqual)
+    }
+
+    /// `a &&= b`, `a ||= b` and `a ??= b` only assign when `a` doesn't already decide the result.
+    /// `operands` is how many values the assignment takes besides the new one, the object and
+    /// key of a property, left on the stack by whatever came before and dropped if no assignment
+    fn logical_assignment(&mut self, get_ops: &[OpCode], set_ops: &[OpCode], operands: usize) {
+        let operator_type = self.previous().t_type;
+        self.emit_ops(get_ops);
+        let end_jump = match operator_type {
+            TokenType::AndEqual => self.emit_jump(OpCode::JUMP_IF_FALSE(0)),
+            TokenType::OrEqual => {
+                let else_jump = self.emit_jump(OpCode::JUMP_IF_FALSE(0));
+                let end_jump = self.emit_jump(OpCode::JUMP(0));
+                self.patch_jump(else_jump);
+                end_jump
+            }
+            _ => self.emit_jump(OpCode::JUMP_IF_NOT_NULLISH(0)),
+        };
+        self.emit_byte(OpCode::POP);
+        self.expression();
+        self.emit_ops(set_ops);
+        if operands == 0 {
+            self.patch_jump(end_jump);
+            return;
+        }
+        let assigned_jump = self.emit_jump(OpCode::JUMP(0));
+        self.patch_jump(end_jump);
+        self.emit_byte(OpCode::POP_BELOW(operands));
+        self.patch_jump(assigned_jump);
+    }
+
+    fn find_local(&self, name: StrId) -> Option<usize> {
+        self.locals.iter().rposition(|local| local.name == name)
     }
 
-    fn this(&mut self) {}
-    fn super_(&mut self) {}
-    fn variable(&mut self, _can_assign: bool) {}
+    fn resolve_local(&mut self, name: StrId) -> Option<usize> {
+        let slot = self.find_local(name)?;
+        if !self.locals[slot].initialized {
+            self.error(&format!(
+                "Block-scoped variable '{}' used before its declaration.",
+                to_str(name)
+            ));
+        }
+        Some(slot)
+    }
+
+    // Function bodies run later, so capturing a variable before its declaration is fine
+    fn resolve_upvalue(&mut self, name: StrId) -> Option<usize> {
+        let enclosing = self.enclosing.as_mut()?;
+        if let Some(slot) = enclosing.find_local(name) {
+            enclosing.locals[slot].is_captured = true;
+            let kind = enclosing.locals[slot].kind;
+            return Some(self.add_upvalue(true, slot, kind));
+        }
+        let index = enclosing.resolve_upvalue(name)?;
+        let kind = enclosing.upvalues[index].kind;
+        Some(self.add_upvalue(false, index, kind))
+    }
+
+    fn add_upvalue(&mut self, is_local: bool, index: usize, kind: VarKind) -> usize {
+        let capture = Capture { is_local, index };
+        if let Some(existing) = self
+            .upvalues
+            .iter()
+            .position(|upvalue| upvalue.capture == capture)
+        {
+            return existing;
+        }
+        self.upvalues.push(Upvalue { capture, kind });
+        self.upvalues.len() - 1
+    }
 
     fn error(&mut self, message: &str) {
-        self.parser.error(message.to_string());
+        self.parser
+            .error_at_previous(ErrorCode::InvalidSyntax, message.to_string());
+    }
+
+    /// Report an error at `span`, for when the token just consumed isn't the culprit
+    fn error_at(&mut self, span: Span, message: &str) {
+        let diagnostic = Diagnostic::error(ErrorCode::InvalidSyntax, message.to_string(), span);
+        self.parser.report(diagnostic);
+    }
+
+    /// Report an error at `span` along with how it could be fixed
+    fn error_with_help(&mut self, span: Span, message: &str, help: &str) {
+        let diagnostic = Diagnostic::error(ErrorCode::InvalidSyntax, message.to_string(), span)
+            .with_help(help.to_string());
+        self.parser.report(diagnostic);
+    }
+
+    /// Report code that doesn't fit in the bytecode's operands, `limit` saying which one
+    fn too_large(&mut self, message: &str, limit: String) {
+        let span = self.parser.previous.span();
+        let diagnostic = Diagnostic::error(ErrorCode::TooLarge, message.to_string(), span)
+            .with_note(limit)
+            .with_help(String::from("split the code into smaller functions"));
+        self.parser.report(diagnostic);
     }
 
     pub fn consume(&mut self, t_type: TokenType, message: &str) {
@@ -340,7 +2110,8 @@ impl<'a> Compiler<'a> {
             self.parser.advance();
             return;
         }
-        self.parser.error(message.to_string());
+        self.parser
+            .error(ErrorCode::ExpectedToken, message.to_string());
     }
 
     pub fn get_line(&mut self) -> isize {
@@ -349,8 +2120,23 @@ impl<'a> Compiler<'a> {
 
     // Emition
     pub fn emit_byte(&mut self, op_code: OpCode) {
-        let line = self.previous().line;
-        self.current_chunk_mut().add_op_code(op_code, line);
+        let position = self.position();
+        self.emit_byte_at(op_code, position);
+    }
+
+    fn emit_byte_at(&mut self, op_code: OpCode, position: Position) {
+        self.current_chunk_mut().add_op_code(op_code, position);
+    }
+
+    /// Where the token just consumed starts
+    fn position(&self) -> Position {
+        let token = &self.parser.previous;
+        Position {
+            line: token.line as u32,
+            column: token.column as u32,
+            start: token.start as u32,
+            length: token.length as u32,
+        }
     }
 
     pub fn emit_bytes(&mut self, op_code1: OpCode, op_code2: OpCode) {
@@ -358,16 +2144,79 @@ impl<'a> Compiler<'a> {
         self.emit_byte(op_code2);
     }
 
+    fn emit_ops(&mut self, op_codes: &[OpCode]) {
+        for op_code in op_codes {
+            self.emit_byte(*op_code);
+        }
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.current_chunk_mut().add_constant(value);
+        if constant >= MAX_CONSTANTS {
+            self.too_large(
+                "Too many constants in one chunk.",
+                format!("a function can hold at most {} constants", MAX_CONSTANTS),
+            );
+        }
+        self.emit_byte(OpCode::CONSTANT(constant));
+    }
+
+    fn emit_string(&mut self, name: StrId) {
+        self.emit_constant(Value::String(string(name)));
+    }
+
+    /// Emit a jump with a placeholder offset, returning its index for patching
+    fn emit_jump(&mut self, op_code: OpCode) -> usize {
+        let offset = self.current_chunk_mut().code.len();
+        self.emit_byte(op_code);
+        offset
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        if let Err(message) = self.current_chunk_mut().patch_jump(offset) {
+            self.too_large(
+                message,
+                format!("a jump can cover at most {} bytes of code", MAX_JUMP),
+            );
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        // Past the loop instruction itself, which takes three bytes
+        let offset = self.current_chunk_mut().code.len() + 3 - loop_start;
+        if offset > MAX_JUMP {
+            self.too_large(
+                "Loop body too large.",
+                format!("a loop can cover at most {} bytes of code", MAX_JUMP),
+            );
+            return;
+        }
+        self.emit_byte(OpCode::LOOP(offset));
+    }
+
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::RETURN);
+        if self.function_type == FunctionType::Constructor {
+            self.emit_bytes(OpCode::GET_LOCAL(0), OpCode::RETURN);
+        } else {
+            self.emit_bytes(OpCode::UNDEFINED, OpCode::RETURN);
+        }
     }
 
-    fn end_compiliation(&mut self) {
+    fn end_compiliation(&mut self) -> Rc<Function> {
         self.emit_return();
-        // Rc::new(RefCell::new(mem::replace(
-        //     &mut self.function,
-        //     Self::new_function(None, 0),
-        // )))
+        let mut function = mem::replace(&mut self.function, Function::new(None));
+        function.captures = self
+            .upvalues
+            .iter()
+            .map(|upvalue| upvalue.capture)
+            .collect();
+        #[cfg(feature = "log_level_debug")]
+        if self.function_type != FunctionType::Script {
+            function.chunk.disassemble(&Some(
+                function.name.unwrap_or_else(|| intern("<anonymous>")),
+            ));
+        }
+        Rc::new(function)
     }
 
     fn get_rule(t_type: TokenType) -> &'static ParseRule {
@@ -383,8 +2232,7 @@ impl<'a> Compiler<'a> {
     }
 
     fn current_chunk_mut(&mut self) -> &mut Chunk {
-        // &mut self.function.chunk
-        self.compiling_chunk.as_mut().unwrap()
+        &mut self.function.chunk
     }
 
     // In order to debug the scanner, just hook this up from outside :)
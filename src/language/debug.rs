@@ -1,5 +1,6 @@
 pub use super::chunk::Chunk;
 pub use super::common::{to_str, OpCode, StrId};
+use super::value::Value;
 use smol_str::SmolStr;
 
 #[cfg(feature = "log_level_debug")]
@@ -27,27 +28,44 @@ impl Debug for Chunk {
                 name.map(to_str)
                     .unwrap_or_else(|| SmolStr::new_inline("SCRIPT"))
             );
-            for i in 0..self.code.len() {
-                match self.code.get(i) {
-                    Some(instruction) => disassemble_instruction(i, instruction, self),
-                    _ => println!("Error debugging OpCode on at {}", i),
-                }
+            let mut offset = 0;
+            while offset < self.code.len() {
+                offset = disassemble_instruction(offset, self);
             }
         }
     }
 }
 
-fn disassemble_instruction(index: usize, instruction: &OpCode, chunk: &Chunk) {
-    print!("{:04} ", index);
-    if index > 0 && chunk.lines[index] == chunk.lines[index - 1] {
-        print!("   | ");
+/// Print the instruction at `offset`, returning the offset of the next one
+fn disassemble_instruction(offset: usize, chunk: &Chunk) -> usize {
+    print!("{:04} ", offset);
+    let position = chunk.positions.get(offset);
+    if offset > 0 && position == chunk.positions.get(offset - 1) {
+        print!("        | ");
     } else {
-        print!("{:04} ", chunk.lines[index]);
+        print!("{:>9} ", position.to_string());
     }
+    let (instruction, next) = chunk.read(offset);
     match instruction {
-        OpCode::STRING(value) => print!("{:03}\n", instruction),
+        OpCode::JUMP(jump) | OpCode::JUMP_IF_FALSE(jump) | OpCode::JUMP_IF_NOT_NULLISH(jump) => {
+            println!("{:03} -> {:04}", instruction, next + jump)
+        }
+        OpCode::LOOP(jump) => println!("{:03} -> {:04}", instruction, next - jump),
+        OpCode::CONSTANT(constant) => {
+            println!("{:03} '{}'", instruction, chunk.constants[constant])
+        }
+        OpCode::CLOSURE(constant) => {
+            println!("{:03} '{}'", instruction, chunk.constants[constant]);
+            if let Value::Function(function) = &chunk.constants[constant] {
+                for capture in function.captures.iter() {
+                    let kind = if capture.is_local { "local" } else { "upvalue" };
+                    println!("     |                  {} {}", kind, capture.index);
+                }
+            }
+        }
         _ => print!("{:03} \n", instruction),
     }
+    next
 }
 
 // #[cfg(feature = "log_level_debug")]
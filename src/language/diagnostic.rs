@@ -0,0 +1,228 @@
+//! Errors from compiling or running a script, kept as data for embedders to look into.
+//!
+//! Each problem found is a diagnostic pointing at the source it's about. Compiling goes on
+//! after an error to report the ones in later statements too, and nothing runs if there
+//! were any. Running stops at the first error, which comes with the calls it happened in.
+
+use super::chunk::Position;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    // Nothing is reported as a warning yet
+    #[allow(dead_code)]
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// The kind of problem, shared by every message about it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    // Compile errors
+    InvalidToken,
+    ExpectedToken,
+    InvalidSyntax,
+    TooLarge,
+    // Runtime errors, named after the JS error they stand for
+    RuntimeError,
+    TypeError,
+    ReferenceError,
+    RangeError,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidToken => "E0001",
+            ErrorCode::ExpectedToken => "E0002",
+            ErrorCode::InvalidSyntax => "E0003",
+            ErrorCode::TooLarge => "E0004",
+            ErrorCode::RuntimeError => "E1000",
+            ErrorCode::TypeError => "E1001",
+            ErrorCode::ReferenceError => "E1002",
+            ErrorCode::RangeError => "E1003",
+        }
+    }
+
+    /// The code of a runtime error, by the JS error its message starts with
+    pub fn of_runtime_error(message: &str) -> ErrorCode {
+        match message.split_once(':').map(|(name, _)| name) {
+            Some("TypeError") => ErrorCode::TypeError,
+            Some("ReferenceError") => ErrorCode::ReferenceError,
+            Some("RangeError") => ErrorCode::RangeError,
+            _ => ErrorCode::RuntimeError,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A piece of source, by line and column and by its byte range
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    // Unknown for code typed in the REPL or loaded from bytecode
+    pub file: Option<String>,
+    pub line: u32,
+    pub column: u32,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<Position> for Span {
+    fn from(position: Position) -> Span {
+        Span {
+            file: None,
+            line: position.line,
+            column: position.column,
+            start: position.start as usize,
+            end: (position.start + position.length) as usize,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    pub span: Span,
+    // More on why it's an error
+    pub notes: Vec<String>,
+    // How it could be fixed
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, message: String, span: Span) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            span,
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn with_note(mut self, note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn with_help(mut self, help: String) -> Diagnostic {
+        self.help = Some(help);
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}[{}]: {}",
+            self.span, self.severity, self.code, self.message
+        )
+    }
+}
+
+/// A call that was under way when a runtime error happened
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+    pub function: String,
+    // Where the call, or the error in the innermost one, happened
+    pub span: Span,
+    // How many more times the same call was made right before this one, as in recursion
+    pub repeated: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at {} ({})", self.function, self.span)?;
+        if self.repeated > 0 {
+            write!(f, ", repeated {} more times", self.repeated)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Compile,
+    Runtime,
+}
+
+/// Why a script didn't run to completion
+#[derive(Clone, Debug, PartialEq)]
+pub struct InterpretError {
+    pub stage: Stage,
+    pub diagnostics: Vec<Diagnostic>,
+    // Innermost call first, empty for compile errors
+    pub stack_trace: Vec<TraceFrame>,
+}
+
+impl InterpretError {
+    pub fn compile(diagnostics: Vec<Diagnostic>) -> InterpretError {
+        InterpretError {
+            stage: Stage::Compile,
+            diagnostics,
+            stack_trace: Vec::new(),
+        }
+    }
+
+    pub fn runtime(diagnostic: Diagnostic, stack_trace: Vec<TraceFrame>) -> InterpretError {
+        InterpretError {
+            stage: Stage::Runtime,
+            diagnostics: vec![diagnostic],
+            stack_trace,
+        }
+    }
+
+    /// Name the file every span of the error is in
+    pub fn set_file(&mut self, file: &str) {
+        let diagnostics = self
+            .diagnostics
+            .iter_mut()
+            .map(|diagnostic| &mut diagnostic.span);
+        let frames = self.stack_trace.iter_mut().map(|frame| &mut frame.span);
+        for span in diagnostics.chain(frames) {
+            span.file = Some(file.to_string());
+        }
+    }
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, diagnostic) in self.diagnostics.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        for frame in self.stack_trace.iter() {
+            write!(f, "\n    {}", frame)?;
+        }
+        Ok(())
+    }
+}
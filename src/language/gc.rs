@@ -0,0 +1,872 @@
+//! Tracing collector for the reference cycles `Rc` can't free on its own.
+//!
+//! Heap values stay reference counted, so anything that becomes unreachable without being
+//! part of a cycle is freed right away. The collector keeps a weak handle to every object
+//! the VM allocates and marks what is reachable from the VM roots, a slice at a time so no
+//! single pause runs past the budget. Objects allocated while a cycle is underway are
+//! marked right away.
+//!
+//! Marking doesn't need a write barrier: the program may move references around behind
+//! the marker, so an unmarked object isn't taken for garbage yet. Once marking is done,
+//! the unmarked objects get checked against their reference counts, also a slice at a
+//! time. Each of them is counted along with the references it holds to the others, and
+//! any with more strong references than the others account for is held from outside, so
+//! it is kept along with everything it leads to. The program keeps running in between, so
+//! the VM reports what it stores in or takes out of an object then, and what natives hold
+//! on to, and those are kept as well. A last look at the roots, in the same pause that
+//! settles the check, keeps what only the stack or the globals refer to by then. What's
+//! left only survives through cycles among themselves and nothing can reach it anymore,
+//! so it gets cleared out, which drops the references forming those cycles and lets `Rc`
+//! free the lot.
+
+use super::object::{
+    Array, BoundMethod, Class, Closure, Instance, Member, Object, PropertyKey, Upvalue,
+};
+use super::value::Value;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{
+    cell::RefCell,
+    mem,
+    rc::{Rc, Weak},
+    time::{Duration, Instant},
+};
+
+// Start a cycle once the heap grew this many times past what survived the last one
+const HEAP_GROW_FACTOR: usize = 2;
+const FIRST_GC: usize = 1024 * 1024;
+const DEFAULT_PAUSE_BUDGET: Duration = Duration::from_micros(500);
+// Objects traced, swept or checked between looks at the clock
+const WORK_BETWEEN_CLOCK_CHECKS: usize = 64;
+pub const PAUSE_BUCKETS: usize = 16;
+
+/// A heap value the collector can look into
+pub trait Trace {
+    /// Hand each heap value directly referenced by this one to `visit`
+    fn trace(&self, visit: &mut dyn FnMut(Rc<dyn Trace>));
+    /// Drop every reference held, breaking the cycles this value is part of
+    fn clear(&self);
+    /// Rough number of bytes taken, to decide when to collect
+    fn size(&self) -> usize;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GcStats {
+    pub cycles: usize,
+    // Sizes are estimated once, when objects are allocated
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    pub objects_freed: usize,
+    // Bucket 0 counts pauses under 1µs, bucket i those under 2^i µs,
+    // and the last bucket everything longer
+    pub pause_histogram: [usize; PAUSE_BUCKETS],
+    pub longest_pause: Duration,
+}
+
+impl GcStats {
+    fn record_pause(&mut self, pause: Duration) {
+        let micros = pause.as_micros() as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.pause_histogram[bucket.min(PAUSE_BUCKETS - 1)] += 1;
+        self.longest_pause = self.longest_pause.max(pause);
+    }
+
+    fn record_free(&mut self, live_bytes: &mut usize, tracked: &Tracked) {
+        *live_bytes -= tracked.size;
+        self.bytes_freed += tracked.size;
+    }
+}
+
+struct Tracked {
+    object: Weak<dyn Trace>,
+    size: usize,
+}
+
+impl Tracked {
+    // Left behind where a handle was moved out
+    fn empty() -> Tracked {
+        Tracked {
+            object: Weak::<Closure>::new(),
+            size: 0,
+        }
+    }
+}
+
+enum Phase {
+    Idle,
+    // Tracing from the gray objects until there are none left
+    Marking,
+    // Walking the heap, moving marked objects to the front and setting aside the others
+    Sweeping {
+        position: usize,
+        // Objects allocated since the sweep began are marked, so it stops short of them
+        end: usize,
+        survivors: usize,
+    },
+    // Checking the unmarked objects against their reference counts
+    Verifying,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    // Finding the candidates by address
+    Indexing,
+    // Taking hold of each candidate and counting the references to and from it
+    Counting,
+    // Keeping the candidates with references from outside
+    Deciding,
+    // Keeping what the kept candidates referred to
+    Propagating,
+    // Keeping what the roots refer to, and settling the check if that was kept already
+    Rescanning,
+    // Clearing out the candidates that weren't kept
+    Freeing,
+    // Closing the gap the candidates left in the heap
+    Compacting,
+}
+
+// Kept from one cycle to the next, so its buffers don't have to be allocated and freed
+// in one go each time
+struct Verification {
+    stage: Stage,
+    // Next candidate the stage looks at
+    position: usize,
+    // Objects kept so far, at the front of the heap. Those before `swept` were swept, the
+    // ones after were allocated since and are kept
+    survivors: usize,
+    swept: usize,
+    candidates: Vec<Tracked>,
+    // Candidate positions, by address
+    index: FxHashMap<usize, usize>,
+    // The candidates still alive when counted, held until they're kept or freed
+    held: Vec<Option<Rc<dyn Trace>>>,
+    // Strong references to each candidate when it was counted, less the one held here
+    strong: Vec<usize>,
+    // References to each candidate from the others, as they were when those got counted
+    internal: Vec<usize>,
+    // The candidates referred to by candidate i are `edges[starts[i]..starts[i + 1]]`
+    starts: Vec<usize>,
+    edges: Vec<usize>,
+    kept: Vec<bool>,
+    // Kept, but what they refer to isn't yet
+    gray: Vec<usize>,
+    freed: usize,
+}
+
+impl Verification {
+    fn new() -> Verification {
+        Verification {
+            stage: Stage::Indexing,
+            position: 0,
+            survivors: 0,
+            swept: 0,
+            candidates: Vec::new(),
+            index: FxHashMap::default(),
+            held: Vec::new(),
+            strong: Vec::new(),
+            internal: Vec::new(),
+            starts: Vec::new(),
+            edges: Vec::new(),
+            kept: Vec::new(),
+            gray: Vec::new(),
+            freed: 0,
+        }
+    }
+
+    // Set up to check the candidates the sweep left, the last check having used up the
+    // ones before
+    fn start(&mut self, survivors: usize, swept: usize) {
+        let count = self.candidates.len();
+        self.next_stage(Stage::Indexing);
+        self.survivors = survivors;
+        self.swept = swept;
+        // Sized up front, growing it would rehash every candidate in one go
+        self.index.clear();
+        self.index.reserve(count);
+        self.held.reserve(count);
+        self.strong.clear();
+        self.strong.reserve(count);
+        self.internal.clear();
+        self.internal.resize(count, 0);
+        self.starts.clear();
+        self.starts.reserve(count + 1);
+        self.edges.clear();
+        self.kept.clear();
+        self.kept.resize(count, false);
+        self.gray.clear();
+        self.freed = 0;
+    }
+
+    fn keep(&mut self, candidate: usize) {
+        if !self.kept[candidate] {
+            self.kept[candidate] = true;
+            self.gray.push(candidate);
+        }
+    }
+
+    fn keep_address(&mut self, address: usize) {
+        if let Some(&candidate) = self.index.get(&address) {
+            self.keep(candidate);
+        }
+    }
+
+    fn next_stage(&mut self, stage: Stage) {
+        self.stage = stage;
+        self.position = 0;
+    }
+
+    /// Check one more candidate, reference or root, returning whether the check is over
+    fn advance(
+        &mut self,
+        roots: Option<&[Rc<dyn Trace>]>,
+        objects: &mut Vec<Tracked>,
+        live_bytes: &mut usize,
+        stats: &mut GcStats,
+    ) -> bool {
+        let position = self.position;
+        self.position += 1;
+        match self.stage {
+            Stage::Indexing => match self.candidates.get(position) {
+                Some(tracked) => {
+                    self.index.insert(weak_address(&tracked.object), position);
+                }
+                None => self.next_stage(Stage::Counting),
+            },
+            Stage::Counting => {
+                self.starts.push(self.edges.len());
+                let tracked = match self.candidates.get(position) {
+                    Some(tracked) => tracked,
+                    None => {
+                        self.next_stage(Stage::Deciding);
+                        return false;
+                    }
+                };
+                let object = tracked.object.upgrade();
+                match &object {
+                    Some(object) => {
+                        self.strong.push(Rc::strong_count(object) - 1);
+                        let index = &self.index;
+                        let edges = &mut self.edges;
+                        let internal = &mut self.internal;
+                        object.trace(&mut |child| {
+                            if let Some(&child) = index.get(&address(&child)) {
+                                edges.push(child);
+                                internal[child] += 1;
+                            }
+                        });
+                    }
+                    // Already freed by its reference count
+                    None => self.strong.push(0),
+                }
+                self.held.push(object);
+            }
+            Stage::Deciding => match self.held.get(position) {
+                Some(held) => {
+                    if held.is_some() && self.strong[position] > self.internal[position] {
+                        self.keep(position);
+                    }
+                }
+                None => self.next_stage(Stage::Propagating),
+            },
+            Stage::Propagating => match self.gray.pop() {
+                Some(candidate) => {
+                    for edge in self.starts[candidate]..self.starts[candidate + 1] {
+                        self.keep(self.edges[edge]);
+                    }
+                }
+                None => self.next_stage(Stage::Rescanning),
+            },
+            Stage::Rescanning => {
+                for root in roots.unwrap_or_default() {
+                    self.keep_address(address(root));
+                }
+                // The program hasn't run since the last look if nothing new was kept, so
+                // the rest was out of its reach all along
+                if self.gray.is_empty() {
+                    self.next_stage(Stage::Freeing);
+                } else {
+                    self.next_stage(Stage::Propagating);
+                }
+            }
+            // Taken from the back, so there's nothing left to drop all at once in the end
+            Stage::Freeing => {
+                let (tracked, held) = match (self.candidates.pop(), self.held.pop()) {
+                    (Some(tracked), Some(held)) => (tracked, held),
+                    _ => {
+                        self.next_stage(Stage::Compacting);
+                        return false;
+                    }
+                };
+                match held {
+                    // The candidates came from the swept part of the heap, so there's room
+                    Some(_) if self.kept[self.candidates.len()] => {
+                        objects[self.survivors] = tracked;
+                        self.survivors += 1;
+                    }
+                    Some(object) => {
+                        object.clear();
+                        self.freed += 1;
+                        stats.objects_freed += 1;
+                        stats.record_free(live_bytes, &tracked);
+                    }
+                    None => stats.record_free(live_bytes, &tracked),
+                }
+            }
+            // Filling the gap left in the heap from its back, rather than shifting everything
+            // allocated since the sweep
+            Stage::Compacting => {
+                if self.survivors == self.swept {
+                    return true;
+                }
+                if objects.len() > self.swept {
+                    objects.swap_remove(self.survivors);
+                    self.survivors += 1;
+                } else {
+                    objects.pop();
+                    self.swept -= 1;
+                }
+            }
+        }
+        false
+    }
+}
+
+pub struct Heap {
+    objects: Vec<Tracked>,
+    phase: Phase,
+    verification: Verification,
+    // Reached but not traced yet
+    gray: Vec<Rc<dyn Trace>>,
+    // Gray and black objects, by address
+    marked: FxHashSet<usize>,
+    live_bytes: usize,
+    next_gc: usize,
+    pause_budget: Duration,
+    stats: GcStats,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            phase: Phase::Idle,
+            verification: Verification::new(),
+            gray: Vec::new(),
+            marked: FxHashSet::default(),
+            live_bytes: 0,
+            next_gc: FIRST_GC,
+            pause_budget: DEFAULT_PAUSE_BUDGET,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn track<T: Trace + 'static>(&mut self, object: &Rc<T>) {
+        let size = object.size();
+        self.live_bytes += size;
+        self.stats.bytes_allocated += size;
+        let object: Rc<dyn Trace> = object.clone();
+        if !matches!(self.phase, Phase::Idle) {
+            self.marked.insert(address(&object));
+        }
+        if let Phase::Verifying = self.phase {
+            // It may have been made out of candidates counted before the program got them
+            let verification = &mut self.verification;
+            object.trace(&mut |child| verification.keep_address(address(&child)));
+        }
+        self.objects.push(Tracked {
+            object: Rc::downgrade(&object),
+            size,
+        });
+    }
+
+    /// Let the collector know `object` is about to change, taking values out of it or
+    /// storing `values` in it
+    pub fn write_barrier<T: Trace + ?Sized>(&mut self, object: &Rc<T>, values: &[Value]) {
+        if let Phase::Verifying = self.phase {
+            let verification = &mut self.verification;
+            verification.keep_address(Rc::as_ptr(object) as *const () as usize);
+            for object in values.iter().filter_map(as_trace) {
+                verification.keep_address(address(&object));
+            }
+        }
+    }
+
+    /// Let the collector know native code holds on to `value`, out of sight of the roots
+    pub fn hold(&mut self, value: &Value) {
+        if let (Phase::Verifying, Some(object)) = (&self.phase, as_trace(value)) {
+            self.verification.keep_address(address(&object));
+        }
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    pub fn set_pause_budget(&mut self, budget: Duration) {
+        self.pause_budget = budget;
+    }
+
+    /// Whether a new cycle should begin, taking the roots from `start`
+    pub fn should_start(&self) -> bool {
+        matches!(self.phase, Phase::Idle)
+            && (cfg!(feature = "stress_gc") || self.live_bytes > self.next_gc)
+    }
+
+    pub fn start(&mut self, roots: Vec<Rc<dyn Trace>>) {
+        for root in roots {
+            if self.marked.insert(address(&root)) {
+                self.gray.push(root);
+            }
+        }
+        self.phase = Phase::Marking;
+    }
+
+    pub fn is_collecting(&self) -> bool {
+        !matches!(self.phase, Phase::Idle)
+    }
+
+    /// Whether the next step has to be given the roots to make progress
+    pub fn needs_roots(&self) -> bool {
+        matches!(self.phase, Phase::Verifying) && self.verification.stage == Stage::Rescanning
+    }
+
+    /// Advance the cycle underway for as long as the pause budget allows,
+    /// returning how many objects were freed if that finished it
+    pub fn step(&mut self, roots: Option<Vec<Rc<dyn Trace>>>) -> Option<usize> {
+        if matches!(self.phase, Phase::Idle) {
+            return None;
+        }
+        let started = Instant::now();
+        // Stress runs take the smallest steps, so the program runs between as many as possible
+        let mut budget = if cfg!(feature = "stress_gc") {
+            1
+        } else {
+            usize::MAX
+        };
+        let freed = loop {
+            let work = budget.min(WORK_BETWEEN_CLOCK_CHECKS);
+            budget -= work;
+            if let Some(freed) = self.work(work, roots.as_deref()) {
+                break Some(freed);
+            }
+            let stuck = roots.is_none() && self.needs_roots();
+            if stuck || budget == 0 || started.elapsed() >= self.pause_budget {
+                break None;
+            }
+        };
+        self.stats.record_pause(started.elapsed());
+        freed
+    }
+
+    /// Finish the cycle underway if any, then run a whole new one from `roots`,
+    /// returning how many objects that one freed
+    pub fn collect(&mut self, roots: Vec<Rc<dyn Trace>>) -> usize {
+        let started = Instant::now();
+        while !matches!(self.phase, Phase::Idle) {
+            self.work(usize::MAX, Some(&roots));
+        }
+        self.start(roots.clone());
+        let freed = loop {
+            if let Some(freed) = self.work(usize::MAX, Some(&roots)) {
+                break freed;
+            }
+        };
+        self.stats.record_pause(started.elapsed());
+        freed
+    }
+
+    /// Trace, sweep or check up to `work` objects, returning how many were freed
+    /// if the cycle is over
+    fn work(&mut self, mut work: usize, roots: Option<&[Rc<dyn Trace>]>) -> Option<usize> {
+        while work > 0 {
+            work -= 1;
+            match &mut self.phase {
+                Phase::Idle => return None,
+                Phase::Marking => match self.gray.pop() {
+                    Some(object) => {
+                        let marked = &mut self.marked;
+                        let gray = &mut self.gray;
+                        object.trace(&mut |child| {
+                            if marked.insert(address(&child)) {
+                                gray.push(child);
+                            }
+                        });
+                    }
+                    None => {
+                        self.phase = Phase::Sweeping {
+                            position: 0,
+                            end: self.objects.len(),
+                            survivors: 0,
+                        }
+                    }
+                },
+                Phase::Sweeping {
+                    position,
+                    end,
+                    survivors,
+                } => {
+                    if *position == *end {
+                        self.verification.start(*survivors, *position);
+                        self.phase = Phase::Verifying;
+                        continue;
+                    }
+                    // Move the handle out, so the ones of dead objects go in this step
+                    // rather than all at once when the cycle ends
+                    let tracked = mem::replace(&mut self.objects[*position], Tracked::empty());
+                    *position += 1;
+                    match tracked.object.upgrade() {
+                        // Already freed by its reference count
+                        None => self.stats.record_free(&mut self.live_bytes, &tracked),
+                        Some(object) if self.marked.contains(&address(&object)) => {
+                            self.objects[*survivors] = tracked;
+                            *survivors += 1;
+                        }
+                        Some(_) => self.verification.candidates.push(tracked),
+                    }
+                }
+                Phase::Verifying => {
+                    let verification = &mut self.verification;
+                    if verification.stage == Stage::Rescanning && roots.is_none() {
+                        return None;
+                    }
+                    let objects = &mut self.objects;
+                    if verification.advance(roots, objects, &mut self.live_bytes, &mut self.stats) {
+                        return Some(self.finish());
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // End the cycle, returning how many objects it freed
+    fn finish(&mut self) -> usize {
+        self.phase = Phase::Idle;
+        self.marked.clear();
+        self.next_gc = (self.live_bytes * HEAP_GROW_FACTOR).max(FIRST_GC);
+        self.stats.cycles += 1;
+        self.verification.freed
+    }
+}
+
+fn address(object: &Rc<dyn Trace>) -> usize {
+    Rc::as_ptr(object) as *const () as usize
+}
+
+// The same as `address` while the object lives, and the allocation stays put while the
+// handle does
+fn weak_address(object: &Weak<dyn Trace>) -> usize {
+    object.as_ptr() as *const () as usize
+}
+
+/// The heap object behind a value, if it is one the collector knows about
+pub fn as_trace(value: &Value) -> Option<Rc<dyn Trace>> {
+    match value {
+        Value::Array(array) => Some(array.clone()),
+        Value::Object(object) => Some(object.clone()),
+        Value::Instance(instance) => Some(instance.clone()),
+        Value::Class(class) => Some(class.clone()),
+        Value::Closure(closure) => Some(closure.clone()),
+        Value::BoundMethod(bound) => Some(bound.clone()),
+        _ => None,
+    }
+}
+
+fn trace_value(value: &Value, visit: &mut dyn FnMut(Rc<dyn Trace>)) {
+    if let Some(object) = as_trace(value) {
+        visit(object);
+    }
+}
+
+impl Trace for RefCell<Array> {
+    fn trace(&self, visit: &mut dyn FnMut(Rc<dyn Trace>)) {
+        for element in self.borrow().elements.iter().flatten() {
+            trace_value(element, visit);
+        }
+    }
+
+    fn clear(&self) {
+        let elements = mem::take(&mut self.borrow_mut().elements);
+        drop(elements);
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.borrow().elements.len() * mem::size_of::<Option<Value>>()
+    }
+}
+
+impl Trace for RefCell<Object> {
+    fn trace(&self, visit: &mut dyn FnMut(Rc<dyn Trace>)) {
+        let object = self.borrow();
+        for (_, value) in object.properties.iter() {
+            trace_value(value, visit);
+        }
+        if let Some(prototype) = &object.prototype {
+            visit(prototype.clone());
+        }
+    }
+
+    fn clear(&self) {
+        let object = mem::take(&mut *self.borrow_mut());
+        drop(object);
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.borrow().properties.len() * mem::size_of::<(PropertyKey, Value)>()
+    }
+}
+
+impl Trace for RefCell<Instance> {
+    fn trace(&self, visit: &mut dyn FnMut(Rc<dyn Trace>)) {
+        let instance = self.borrow();
+        visit(instance.class.clone());
+        for (_, value) in instance.fields.iter() {
+            trace_value(value, visit);
+        }
+    }
+
+    fn clear(&self) {
+        let fields = mem::take(&mut self.borrow_mut().fields);
+        drop(fields);
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.borrow().fields.len() * mem::size_of::<(PropertyKey, Value)>()
+    }
+}
+
+impl Trace for RefCell<Class> {
+    fn trace(&self, visit: &mut dyn FnMut(Rc<dyn Trace>)) {
+        let class = self.borrow();
+        if let Some(superclass) = &class.superclass {
+            visit(superclass.clone());
+        }
+        let closures = class.constructor.iter().chain(class.initializer.iter());
+        for closure in closures {
+            visit(closure.clone());
+        }
+        for member in class.members.values().chain(class.static_members.values()) {
+            match member {
+                Member::Method(method) => visit(method.clone()),
+                Member::Accessor { getter, setter } => {
+                    for accessor in getter.iter().chain(setter.iter()) {
+                        visit(accessor.clone());
+                    }
+                }
+            }
+        }
+        for (_, value) in class.fields.iter() {
+            trace_value(value, visit);
+        }
+    }
+
+    fn clear(&self) {
+        let mut class = self.borrow_mut();
+        let superclass = class.superclass.take();
+        let constructor = class.constructor.take();
+        let initializer = class.initializer.take();
+        let members = mem::take(&mut class.members);
+        let static_members = mem::take(&mut class.static_members);
+        let fields = mem::take(&mut class.fields);
+        drop(class);
+        drop((
+            superclass,
+            constructor,
+            initializer,
+            members,
+            static_members,
+            fields,
+        ));
+    }
+
+    fn size(&self) -> usize {
+        let class = self.borrow();
+        let members = class.members.len() + class.static_members.len();
+        mem::size_of::<Self>()
+            + members * mem::size_of::<Member>()
+            + class.fields.len() * mem::size_of::<(PropertyKey, Value)>()
+    }
+}
+
+impl Trace for RefCell<Upvalue> {
+    fn trace(&self, visit: &mut dyn FnMut(Rc<dyn Trace>)) {
+        if let Upvalue::Closed(value) = &*self.borrow() {
+            trace_value(value, visit);
+        }
+    }
+
+    fn clear(&self) {
+        let upvalue = mem::replace(
+            &mut *self.borrow_mut(),
+            Upvalue::Closed(Value::ValUndefined),
+        );
+        drop(upvalue);
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+}
+
+// Closures and bound methods can't be changed after they're made, so any cycle through
+// them also goes through one of the objects above, and clearing that is enough
+impl Trace for Closure {
+    fn trace(&self, visit: &mut dyn FnMut(Rc<dyn Trace>)) {
+        for upvalue in self.upvalues.iter() {
+            visit(upvalue.clone());
+        }
+    }
+
+    fn clear(&self) {}
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.upvalues.len() * mem::size_of::<Rc<()>>()
+    }
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, visit: &mut dyn FnMut(Rc<dyn Trace>)) {
+        trace_value(&self.receiver, visit);
+        trace_value(&self.method, visit);
+    }
+
+    fn clear(&self) {}
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn array(heap: &mut Heap, elements: Vec<Value>) -> Rc<RefCell<Array>> {
+        let array = Rc::new(RefCell::new(Array::new(elements)));
+        heap.track(&array);
+        array
+    }
+
+    fn link(from: &Rc<RefCell<Array>>, to: &Rc<RefCell<Array>>) {
+        from.borrow_mut()
+            .elements
+            .push(Some(Value::Array(to.clone())));
+    }
+
+    // Run the cycle underway to its end, the program holding on to `roots` throughout
+    fn finish(heap: &mut Heap, roots: &[Rc<dyn Trace>]) -> usize {
+        loop {
+            if let Some(freed) = heap.work(1, Some(roots)) {
+                return freed;
+            }
+        }
+    }
+
+    // Run the cycle underway until the first candidate has been counted
+    fn count_first(heap: &mut Heap) {
+        while !matches!(heap.phase, Phase::Verifying)
+            || heap.verification.stage != Stage::Counting
+            || heap.verification.position == 0
+        {
+            heap.work(1, Some(&[]));
+        }
+    }
+
+    #[test]
+    fn frees_a_large_cycle_a_slice_at_a_time() {
+        const LENGTH: usize = 20_000;
+        let mut heap = Heap::new();
+        heap.set_pause_budget(Duration::ZERO);
+        // Each node refers to both its neighbours, so it takes the whole list to free any
+        let nodes: Vec<_> = (0..LENGTH).map(|_| array(&mut heap, Vec::new())).collect();
+        for (i, node) in nodes.iter().enumerate() {
+            link(node, &nodes[(i + LENGTH - 1) % LENGTH]);
+            link(node, &nodes[(i + 1) % LENGTH]);
+        }
+        drop(nodes);
+
+        heap.start(Vec::new());
+        let mut pauses = 0;
+        let freed = loop {
+            pauses += 1;
+            if let Some(freed) = heap.step(Some(Vec::new())) {
+                break freed;
+            }
+        };
+        assert_eq!(freed, LENGTH);
+        // A zero budget gets through one slice of work per pause, and each object only
+        // takes a handful of those
+        let slice = if cfg!(feature = "stress_gc") {
+            1
+        } else {
+            WORK_BETWEEN_CLOCK_CHECKS
+        };
+        assert!(pauses > LENGTH / slice);
+        assert!(pauses < 10 * LENGTH / slice);
+        let stats = heap.stats();
+        assert_eq!(stats.cycles, 1);
+        assert_eq!(stats.objects_freed, LENGTH);
+        assert_eq!(stats.bytes_freed, stats.bytes_allocated);
+        assert_eq!(stats.pause_histogram.iter().sum::<usize>(), pauses);
+    }
+
+    // The first element of an array
+    fn first(array: &Rc<RefCell<Array>>) -> Rc<RefCell<Array>> {
+        match array.borrow().get(0) {
+            Value::Array(first) => first,
+            _ => unreachable!(),
+        }
+    }
+
+    // Two arrays referring to each other, only the second of which the program holds
+    fn pair(heap: &mut Heap) -> Rc<RefCell<Array>> {
+        let a = array(heap, Vec::new());
+        let b = array(heap, Vec::new());
+        link(&a, &b);
+        link(&b, &a);
+        b
+    }
+
+    #[test]
+    fn keeps_what_the_roots_picked_up_while_counting() {
+        let mut heap = Heap::new();
+        let b = pair(&mut heap);
+        // The marker missed `b`, as if the program had moved it around
+        heap.start(Vec::new());
+        count_first(&mut heap);
+        // Only held by `b` when counted, then the program took it and let go of `b`
+        let roots: Vec<Rc<dyn Trace>> = vec![first(&b)];
+        drop(b);
+        assert_eq!(finish(&mut heap, &roots), 0);
+    }
+
+    #[test]
+    fn keeps_what_the_program_stored_while_counting() {
+        let mut heap = Heap::new();
+        let root = array(&mut heap, Vec::new());
+        let b = pair(&mut heap);
+        let roots: Vec<Rc<dyn Trace>> = vec![root.clone()];
+        heap.start(roots.clone());
+        count_first(&mut heap);
+        // Stored in the root once counted, out of sight of the counts, and the program
+        // lets go of `b`
+        let a = first(&b);
+        heap.write_barrier(&root, &[Value::Array(a.clone())]);
+        link(&root, &a);
+        drop((a, b));
+        assert_eq!(finish(&mut heap, &roots), 0);
+        assert_eq!(first(&root).borrow().elements.len(), 1);
+    }
+
+    #[test]
+    fn sorts_pauses_by_length() {
+        let mut stats = GcStats::default();
+        for micros in [0, 1, 3, 3, 1_000_000] {
+            stats.record_pause(Duration::from_micros(micros));
+        }
+        assert_eq!(stats.pause_histogram[..3], [1, 1, 2]);
+        assert_eq!(stats.pause_histogram[PAUSE_BUCKETS - 1], 1);
+        assert_eq!(stats.pause_histogram.iter().sum::<usize>(), 5);
+        assert_eq!(stats.longest_pause, Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,332 @@
+//! Interned strings, owned by a VM and reclaimed once no live code or value refers to them.
+//!
+//! Ids are only meaningful to the interner that handed them out. The compiler and the
+//! runtime reach it through `common::intern` and `common::to_str`, which use whichever
+//! interner was last entered on this thread, so each VM enters its own while it runs.
+
+use super::common::MutRc;
+use super::object::{Function, Member, Properties, PropertyKey, Upvalue};
+use super::string::{hash_text, JsString};
+use super::value::Value;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::{cell::RefCell, collections::hash_map::Entry, mem, rc::Rc};
+
+// Look for unused strings once the table grew this many times past what was live last time
+const GROW_FACTOR: usize = 2;
+const FIRST_RECLAIM: usize = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StrId(u32);
+
+impl StrId {
+    /// The id as written in bytecode
+    pub fn to_u32(self) -> u32 {
+        self.0
+    }
+
+    pub fn from_u32(id: u32) -> StrId {
+        StrId(id)
+    }
+}
+
+pub struct Interner {
+    // Ids by the hash of their string
+    ids: FxHashMap<u64, StrId>,
+    // Strings whose hash was already taken by another one when they were interned
+    collisions: Vec<StrId>,
+    strings: Vec<Option<JsString>>,
+    // Slots of reclaimed strings, handed out again before growing the table
+    free: Vec<u32>,
+    len: usize,
+    next_reclaim: usize,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner {
+            ids: FxHashMap::default(),
+            collisions: Vec::new(),
+            strings: Vec::with_capacity(500),
+            free: Vec::new(),
+            len: 0,
+            next_reclaim: FIRST_RECLAIM,
+        }
+    }
+
+    pub fn intern(&mut self, text: &str) -> StrId {
+        match self.find(hash_text(text), text) {
+            Some(id) => id,
+            None => self.insert(JsString::from(text)),
+        }
+    }
+
+    /// Intern a string made at runtime, keeping it as the string for its text if it's new
+    pub fn intern_string(&mut self, string: &JsString) -> StrId {
+        match self.find(string.hash_value(), string) {
+            Some(id) => id,
+            None => self.insert(string.clone()),
+        }
+    }
+
+    fn find(&self, hash: u64, text: &str) -> Option<StrId> {
+        let id = *self.ids.get(&hash)?;
+        if self.resolve(id) == text {
+            return Some(id);
+        }
+        self.collisions.iter().copied().find(|id| {
+            let string = self.string(*id);
+            string.hash_value() == hash && string.as_str() == text
+        })
+    }
+
+    fn insert(&mut self, string: JsString) -> StrId {
+        let hash = string.hash_value();
+        let id = match self.free.pop() {
+            Some(slot) => {
+                self.strings[slot as usize] = Some(string);
+                StrId(slot)
+            }
+            None => {
+                self.strings.push(Some(string));
+                StrId(self.strings.len() as u32 - 1)
+            }
+        };
+        self.place(hash, id);
+        self.len += 1;
+        id
+    }
+
+    fn place(&mut self, hash: u64, id: StrId) {
+        match self.ids.entry(hash) {
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+            }
+            Entry::Occupied(_) => self.collisions.push(id),
+        }
+    }
+
+    pub fn string(&self, id: StrId) -> &JsString {
+        self.strings[id.0 as usize]
+            .as_ref()
+            .expect("string was reclaimed while still in use")
+    }
+
+    pub fn resolve(&self, id: StrId) -> &str {
+        self.string(id).as_str()
+    }
+
+    /// Number of strings currently interned
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the table grew enough since the last reclaim to look for unused strings
+    pub fn should_reclaim(&self) -> bool {
+        self.len() >= self.next_reclaim
+    }
+
+    /// Free every string not in `live`, returning how many there were.
+    /// Strings still held by values stay valid, they just stop being interned
+    pub fn reclaim(&mut self, live: &FxHashSet<StrId>) -> usize {
+        let mut freed = 0;
+        for (slot, entry) in self.strings.iter_mut().enumerate() {
+            let id = StrId(slot as u32);
+            if entry.is_some() && !live.contains(&id) {
+                let hash = entry.take().unwrap().hash_value();
+                if self.ids.get(&hash) == Some(&id) {
+                    self.ids.remove(&hash);
+                }
+                self.free.push(slot as u32);
+                freed += 1;
+            }
+        }
+        // A collision can take the place of the string that was in its way
+        for id in mem::take(&mut self.collisions) {
+            if live.contains(&id) {
+                self.place(self.string(id).hash_value(), id);
+            }
+        }
+        self.len -= freed;
+        self.next_reclaim = (self.len() * GROW_FACTOR).max(FIRST_RECLAIM);
+        freed
+    }
+}
+
+impl Default for Interner {
+    fn default() -> Interner {
+        Interner::new()
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<MutRc<Interner>>> = const { RefCell::new(None) };
+}
+
+/// Keeps an interner current until dropped, then puts back the one it replaced
+pub struct Scope {
+    previous: Option<MutRc<Interner>>,
+}
+
+/// Make `interner` the one `intern` and `to_str` use on this thread
+pub fn enter(interner: &MutRc<Interner>) -> Scope {
+    let previous = CURRENT.with(|current| current.replace(Some(interner.clone())));
+    Scope { previous }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+pub fn with_current<R>(f: impl FnOnce(&mut Interner) -> R) -> R {
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        let interner = current
+            .as_ref()
+            .expect("strings can only be interned while a VM is running");
+        let mut interner = interner.borrow_mut();
+        f(&mut interner)
+    })
+}
+
+/// Collects the ids of every string reachable from the values it's shown
+#[derive(Default)]
+pub struct Marker {
+    live: FxHashSet<StrId>,
+    seen: FxHashSet<*const ()>,
+    pending: Vec<Value>,
+}
+
+impl Marker {
+    pub fn mark(&mut self, id: StrId) {
+        self.live.insert(id);
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        let address = match value {
+            Value::Function(function) => Rc::as_ptr(function) as *const (),
+            Value::Closure(closure) => Rc::as_ptr(closure) as *const (),
+            Value::Array(array) => Rc::as_ptr(array) as *const (),
+            Value::Class(class) => Rc::as_ptr(class) as *const (),
+            Value::Instance(instance) => Rc::as_ptr(instance) as *const (),
+            Value::BoundMethod(bound) => Rc::as_ptr(bound) as *const (),
+            Value::Object(object) => Rc::as_ptr(object) as *const (),
+            Value::Symbol(symbol) => Rc::as_ptr(symbol) as *const (),
+            Value::Native(native) => Rc::as_ptr(native) as *const (),
+            Value::ValBool(_)
+            | Value::ValNull
+            | Value::ValUndefined
+            | Value::ValNumber(_)
+            | Value::String(_) => return,
+        };
+        if self.seen.insert(address) {
+            self.pending.push(value.clone());
+        }
+    }
+
+    pub fn mark_upvalue(&mut self, upvalue: &MutRc<Upvalue>) {
+        if let Upvalue::Closed(value) = &*upvalue.borrow() {
+            self.mark_value(value);
+        }
+    }
+
+    /// Follow everything marked so far, returning the ids of all strings found
+    pub fn finish(mut self) -> FxHashSet<StrId> {
+        while let Some(value) = self.pending.pop() {
+            self.scan(&value);
+        }
+        self.live
+    }
+
+    fn scan(&mut self, value: &Value) {
+        match value {
+            Value::Function(function) => self.scan_function(function),
+            Value::Closure(closure) => {
+                self.mark_value(&Value::Function(closure.function.clone()));
+                for upvalue in closure.upvalues.iter() {
+                    self.mark_upvalue(upvalue);
+                }
+            }
+            Value::Array(array) => {
+                for element in array.borrow().elements.iter().flatten() {
+                    self.mark_value(element);
+                }
+            }
+            Value::Class(class) => {
+                let class = class.borrow();
+                self.mark(class.name);
+                if let Some(superclass) = &class.superclass {
+                    self.mark_value(&Value::Class(superclass.clone()));
+                }
+                let closures = class.constructor.iter().chain(class.initializer.iter());
+                for closure in closures {
+                    self.mark_value(&Value::Closure(closure.clone()));
+                }
+                let members = class.members.iter().chain(class.static_members.iter());
+                for (name, member) in members {
+                    self.mark(*name);
+                    match member {
+                        Member::Method(method) => self.mark_value(&Value::Closure(method.clone())),
+                        Member::Accessor { getter, setter } => {
+                            for accessor in getter.iter().chain(setter.iter()) {
+                                self.mark_value(&Value::Closure(accessor.clone()));
+                            }
+                        }
+                    }
+                }
+                self.scan_properties(&class.fields);
+                for (name, key) in class.private_keys.iter() {
+                    self.mark(*name);
+                    self.mark(*key);
+                }
+            }
+            Value::Instance(instance) => {
+                let instance = instance.borrow();
+                self.mark_value(&Value::Class(instance.class.clone()));
+                self.scan_properties(&instance.fields);
+            }
+            Value::BoundMethod(bound) => {
+                self.mark_value(&bound.receiver);
+                self.mark_value(&bound.method);
+            }
+            Value::Object(object) => {
+                let object = object.borrow();
+                if let Some(prototype) = &object.prototype {
+                    self.mark_value(&Value::Object(prototype.clone()));
+                }
+                self.scan_properties(&object.properties);
+            }
+            Value::Symbol(symbol) => {
+                if let Some(description) = symbol.description {
+                    self.mark(description);
+                }
+            }
+            Value::Native(native) => self.mark(native.name),
+            _ => {}
+        }
+    }
+
+    fn scan_function(&mut self, function: &Function) {
+        if let Some(name) = function.name {
+            self.mark(name);
+        }
+        for id in function.chunk.instructions().filter_map(|op| op.str_id()) {
+            self.mark(id);
+        }
+        for constant in function.chunk.constants.iter() {
+            self.mark_value(constant);
+        }
+    }
+
+    fn scan_properties(&mut self, properties: &Properties) {
+        for (key, value) in properties.iter() {
+            match key {
+                PropertyKey::String(name) => self.mark(*name),
+                PropertyKey::Symbol(symbol) => self.mark_value(&Value::Symbol(symbol.clone())),
+            }
+            self.mark_value(value);
+        }
+    }
+}
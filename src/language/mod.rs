@@ -1,9 +1,18 @@
+pub mod bytecode;
 pub mod chunk;
 pub mod common;
 pub mod compiler;
 pub mod debug;
+pub mod diagnostic;
+pub mod gc;
+pub mod interner;
+pub mod native;
+pub mod object;
 pub mod parser;
+pub mod report;
 pub mod scanner;
+pub mod string;
 pub mod token;
 pub mod value;
+pub mod verifier;
 pub mod vm;
@@ -0,0 +1,342 @@
+//! Built-in functions the VM defines as globals
+
+use super::common::{intern, MutRc};
+use super::object::{Array, Symbol};
+use super::value::{join, Value};
+use super::vm::VM;
+use std::{cell::RefCell, rc::Rc};
+
+/// Natives get the VM to call back into, their `this` and their arguments
+pub type NativeFn = fn(&mut VM, &Value, &[Value]) -> Result<Value, String>;
+
+/// `Symbol(description?)` creates a new unique symbol
+pub fn symbol(_: &mut VM, _: &Value, args: &[Value]) -> Result<Value, String> {
+    let description = match args.first() {
+        None | Some(Value::ValUndefined) => None,
+        Some(description) => Some(intern(description.to_string())),
+    };
+    Ok(Value::Symbol(Rc::new(Symbol { description })))
+}
+
+/// `Array.isArray(value)`
+pub fn is_array(_: &mut VM, _: &Value, args: &[Value]) -> Result<Value, String> {
+    Ok(Value::ValBool(matches!(
+        args.first(),
+        Some(Value::Array(_))
+    )))
+}
+
+/// The methods found on `Array.prototype`
+pub const ARRAY_METHODS: [(&str, NativeFn); 14] = [
+    ("push", array_push),
+    ("pop", array_pop),
+    ("slice", array_slice),
+    ("splice", array_splice),
+    ("map", array_map),
+    ("filter", array_filter),
+    ("reduce", array_reduce),
+    ("forEach", array_for_each),
+    ("find", array_find),
+    ("indexOf", array_index_of),
+    ("includes", array_includes),
+    ("join", array_join),
+    ("sort", array_sort),
+    ("concat", array_concat),
+];
+
+fn array_push(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "push")?;
+    vm.write_barrier(&array, args);
+    let mut array = array.borrow_mut();
+    array.elements.extend(args.iter().cloned().map(Some));
+    Ok(Value::ValNumber(array.elements.len() as f64))
+}
+
+fn array_pop(vm: &mut VM, this: &Value, _: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "pop")?;
+    vm.write_barrier(&array, &[]);
+    let last = array.borrow_mut().elements.pop().flatten();
+    Ok(last.unwrap_or(Value::ValUndefined))
+}
+
+fn array_slice(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "slice")?;
+    let length = array.borrow().elements.len();
+    let start = relative_index(args.first(), length, 0);
+    let end = relative_index(args.get(1), length, length).max(start);
+    let sliced = array.borrow().elements[start..end].to_vec();
+    Ok(new_array(vm, sliced))
+}
+
+fn array_splice(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "splice")?;
+    let length = array.borrow().elements.len();
+    let start = relative_index(args.first(), length, 0);
+    let delete_count = match args.get(1) {
+        None if args.is_empty() => 0,
+        None => length - start,
+        Some(Value::ValNumber(count)) if !count.is_nan() => {
+            count.trunc().clamp(0.0, (length - start) as f64) as usize
+        }
+        Some(_) => 0,
+    };
+    let items = args.iter().skip(2).cloned().map(Some);
+    vm.write_barrier(&array, args);
+    let removed = array
+        .borrow_mut()
+        .elements
+        .splice(start..start + delete_count, items)
+        .collect();
+    Ok(new_array(vm, removed))
+}
+
+fn array_map(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "map")?;
+    let callback = callback(args)?;
+    let length = array.borrow().elements.len();
+    let mut mapped = Vec::with_capacity(length);
+    for index in 0..length {
+        // Holes stay holes, the callback never sees them
+        let element = match element(&array, index) {
+            Some(element) => Some(vm.call_function(&callback, &iteration(element, index, this))?),
+            None => None,
+        };
+        mapped.push(element);
+    }
+    Ok(new_array(vm, mapped))
+}
+
+fn array_filter(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "filter")?;
+    let callback = callback(args)?;
+    let length = array.borrow().elements.len();
+    let mut kept = Vec::new();
+    for index in 0..length {
+        if let Some(element) = element(&array, index) {
+            let result = vm.call_function(&callback, &iteration(element.clone(), index, this))?;
+            if !result.is_falsey() {
+                kept.push(Some(element));
+            }
+        }
+    }
+    Ok(new_array(vm, kept))
+}
+
+fn array_reduce(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "reduce")?;
+    let callback = callback(args)?;
+    let length = array.borrow().elements.len();
+    let mut indices = (0..length).filter(|index| element(&array, *index).is_some());
+    let mut accumulator = match args.get(1) {
+        Some(initial) => initial.clone(),
+        None => match indices.next() {
+            Some(index) => array.borrow().get(index),
+            None => {
+                return Err(String::from(
+                    "TypeError: Reduce of empty array with no initial value",
+                ))
+            }
+        },
+    };
+    for index in indices {
+        if let Some(element) = element(&array, index) {
+            let args = [
+                accumulator,
+                element,
+                Value::ValNumber(index as f64),
+                this.clone(),
+            ];
+            accumulator = vm.call_function(&callback, &args)?;
+        }
+    }
+    Ok(accumulator)
+}
+
+fn array_for_each(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "forEach")?;
+    let callback = callback(args)?;
+    let length = array.borrow().elements.len();
+    for index in 0..length {
+        if let Some(element) = element(&array, index) {
+            vm.call_function(&callback, &iteration(element, index, this))?;
+        }
+    }
+    Ok(Value::ValUndefined)
+}
+
+fn array_find(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "find")?;
+    let callback = callback(args)?;
+    let length = array.borrow().elements.len();
+    // Unlike the other iterations, find visits holes as undefined
+    for index in 0..length {
+        let element = array.borrow().get(index);
+        let result = vm.call_function(&callback, &iteration(element.clone(), index, this))?;
+        if !result.is_falsey() {
+            return Ok(element);
+        }
+    }
+    Ok(Value::ValUndefined)
+}
+
+fn array_index_of(_: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "indexOf")?;
+    let array = array.borrow();
+    let search = args.first().cloned().unwrap_or(Value::ValUndefined);
+    let start = relative_index(args.get(1), array.elements.len(), 0);
+    let index = array.elements[start..]
+        .iter()
+        .position(|element| element.as_ref() == Some(&search));
+    Ok(Value::ValNumber(
+        index.map_or(-1.0, |index| (start + index) as f64),
+    ))
+}
+
+fn array_includes(_: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "includes")?;
+    let array = array.borrow();
+    let search = args.first().cloned().unwrap_or(Value::ValUndefined);
+    let start = relative_index(args.get(1), array.elements.len(), 0);
+    // Unlike indexOf, NaN is found and holes count as undefined
+    let found = (start..array.elements.len()).any(|index| match (array.get(index), &search) {
+        (Value::ValNumber(a), Value::ValNumber(b)) if a.is_nan() && b.is_nan() => true,
+        (element, search) => element == *search,
+    });
+    Ok(Value::ValBool(found))
+}
+
+fn array_join(_: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "join")?;
+    let separator = match args.first() {
+        None | Some(Value::ValUndefined) => String::from(","),
+        Some(separator) => separator.to_js_string().to_string(),
+    };
+    let joined = join(&array, &separator);
+    Ok(Value::String(joined.into()))
+}
+
+fn array_sort(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "sort")?;
+    let comparator = match args.first() {
+        None | Some(Value::ValUndefined) => None,
+        Some(_) => Some(callback(args)?),
+    };
+    let elements = array.borrow().elements.clone();
+    let length = elements.len();
+    // Undefined sorts after everything else and holes after that, neither reaching the comparator
+    let (undefined, values): (Vec<Value>, Vec<Value>) = elements
+        .into_iter()
+        .flatten()
+        .partition(|element| *element == Value::ValUndefined);
+
+    let mut compare = |a: &Value, b: &Value| -> Result<bool, String> {
+        match &comparator {
+            Some(comparator) => {
+                let order = vm.call_function(comparator, &[a.clone(), b.clone()])?;
+                Ok(!matches!(order, Value::ValNumber(order) if order > 0.0))
+            }
+            None => Ok(a.to_js_string() <= b.to_js_string()),
+        }
+    };
+    let mut sorted = merge_sort(values, &mut compare)?;
+    sorted.extend(undefined);
+
+    vm.write_barrier(&array, &sorted);
+    let mut array = array.borrow_mut();
+    array.elements = sorted.into_iter().map(Some).collect();
+    array.set_length(length);
+    Ok(this.clone())
+}
+
+fn array_concat(vm: &mut VM, this: &Value, args: &[Value]) -> Result<Value, String> {
+    let array = this_array(this, "concat")?;
+    let mut elements = array.borrow().elements.clone();
+    for arg in args {
+        match arg {
+            Value::Array(other) => elements.extend(other.borrow().elements.iter().cloned()),
+            value => elements.push(Some(value.clone())),
+        }
+    }
+    Ok(new_array(vm, elements))
+}
+
+fn this_array(this: &Value, method: &str) -> Result<MutRc<Array>, String> {
+    match this {
+        Value::Array(array) => Ok(array.clone()),
+        _ => Err(format!(
+            "TypeError: Array.prototype.{} called on {}",
+            method, this
+        )),
+    }
+}
+
+fn new_array(vm: &mut VM, elements: Vec<Option<Value>>) -> Value {
+    Value::Array(vm.allocate(RefCell::new(Array { elements })))
+}
+
+// Read afresh on every step, as the callback may change the array
+fn element(array: &MutRc<Array>, index: usize) -> Option<Value> {
+    array.borrow().elements.get(index).cloned().flatten()
+}
+
+fn callback(args: &[Value]) -> Result<Value, String> {
+    match args.first() {
+        Some(callback @ (Value::Closure(_) | Value::BoundMethod(_) | Value::Native(_))) => {
+            Ok(callback.clone())
+        }
+        callback => Err(format!(
+            "TypeError: {} is not a function",
+            callback.unwrap_or(&Value::ValUndefined)
+        )),
+    }
+}
+
+/// The `(element, index, array)` arguments of an iteration callback
+fn iteration(element: Value, index: usize, array: &Value) -> [Value; 3] {
+    [element, Value::ValNumber(index as f64), array.clone()]
+}
+
+/// An index argument clamped to `0..=length`, counting from the end when negative
+fn relative_index(value: Option<&Value>, length: usize, default: usize) -> usize {
+    match value {
+        None | Some(Value::ValUndefined) => default,
+        Some(Value::ValNumber(number)) if !number.is_nan() => {
+            let number = number.trunc();
+            if number < 0.0 {
+                (length as f64 + number).max(0.0) as usize
+            } else {
+                number.min(length as f64) as usize
+            }
+        }
+        Some(_) => 0,
+    }
+}
+
+/// A stable sort that stops at the first error from the comparator.
+/// `slice::sort_by` can't be used, it panics when a comparator is inconsistent
+fn merge_sort(
+    mut values: Vec<Value>,
+    in_order: &mut dyn FnMut(&Value, &Value) -> Result<bool, String>,
+) -> Result<Vec<Value>, String> {
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+    let right = values.split_off(values.len() / 2);
+    let left = merge_sort(values, in_order)?;
+    let right = merge_sort(right, in_order)?;
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        let next = if in_order(a, b)? {
+            left.next()
+        } else {
+            right.next()
+        };
+        merged.extend(next);
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
@@ -0,0 +1,576 @@
+use super::chunk::Chunk;
+use super::common::{intern, to_str, MutRc, StrId};
+use super::native::NativeFn;
+use super::value::Value;
+use rustc_hash::FxHashMap;
+use std::{fmt, ptr, rc::Rc};
+
+/// Where a closure finds a captured variable when it is created
+#[derive(Copy, Clone, PartialEq)]
+pub struct Capture {
+    // A local of the enclosing function, or one of its own upvalues
+    pub is_local: bool,
+    pub index: usize,
+}
+
+pub struct Function {
+    pub name: Option<StrId>,
+    // Parameters before the rest parameter, if there is one
+    pub arity: usize,
+    pub has_rest: bool,
+    // Called through a property, it takes the object as `this`, as all but arrow functions do
+    pub binds_this: bool,
+    pub chunk: Chunk,
+    pub captures: Vec<Capture>,
+}
+
+impl Function {
+    pub fn new(name: Option<StrId>) -> Function {
+        Function {
+            name,
+            arity: 0,
+            has_rest: false,
+            binds_this: false,
+            chunk: Chunk::new(),
+            captures: Vec::new(),
+        }
+    }
+}
+
+// Functions are compared by identity, as in JS
+impl PartialEq for Function {
+    fn eq(&self, other: &Function) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "[Function: {}]", to_str(name)),
+            None => write!(f, "[Function (anonymous)]"),
+        }
+    }
+}
+
+/// A captured variable, living on the stack until its scope ends
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<MutRc<Upvalue>>,
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Closure) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+pub struct Array {
+    // Holes, as left by `[1, , 3]` or by growing the array, are None
+    pub elements: Vec<Option<Value>>,
+}
+
+impl Array {
+    pub fn new(elements: Vec<Value>) -> Array {
+        Array {
+            elements: elements.into_iter().map(Some).collect(),
+        }
+    }
+
+    /// The element at `index`, undefined for holes and past the end
+    pub fn get(&self, index: usize) -> Value {
+        match self.elements.get(index) {
+            Some(Some(value)) => value.clone(),
+            _ => Value::ValUndefined,
+        }
+    }
+
+    /// Store an element, leaving holes if it lands past the end
+    pub fn set(&mut self, index: usize, value: Value) {
+        if index >= self.elements.len() {
+            self.elements.resize(index + 1, None);
+        }
+        self.elements[index] = Some(value);
+    }
+
+    pub fn set_length(&mut self, length: usize) {
+        self.elements.resize(length, None);
+    }
+
+    /// Every element, with holes read as undefined
+    pub fn values(&self) -> Vec<Value> {
+        (0..self.elements.len())
+            .map(|index| self.get(index))
+            .collect()
+    }
+}
+
+impl PartialEq for Array {
+    fn eq(&self, other: &Array) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Array {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+// Printed the way node does, with strings quoted inside the brackets
+impl fmt::Display for Array {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.elements.is_empty() {
+            return write!(f, "[]");
+        }
+        write!(f, "[ ")?;
+        let mut elements = self.elements.iter().peekable();
+        let mut first = true;
+        while let Some(element) = elements.next() {
+            if !first {
+                write!(f, ", ")?;
+            }
+            first = false;
+            match element {
+                Some(element) => write_nested(f, element)?,
+                // A run of holes prints as a single `<n empty items>`
+                None => {
+                    let mut holes = 1;
+                    while elements.next_if(|element| element.is_none()).is_some() {
+                        holes += 1;
+                    }
+                    let plural = if holes == 1 { "item" } else { "items" };
+                    write!(f, "<{} empty {}>", holes, plural)?;
+                }
+            }
+        }
+        write!(f, " ]")
+    }
+}
+
+fn write_nested(f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+    match value {
+        Value::String(_) => write!(f, "'{}'", value),
+        _ => write!(f, "{}", value),
+    }
+}
+
+pub struct Symbol {
+    pub description: Option<StrId>,
+}
+
+// Every symbol is unique, whatever its description
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.description {
+            Some(description) => write!(f, "Symbol({})", to_str(description)),
+            None => write!(f, "Symbol()"),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum PropertyKey {
+    String(StrId),
+    Symbol(Rc<Symbol>),
+}
+
+impl PropertyKey {
+    /// Keys that are canonical array indices, which enumerate first and in numeric order
+    pub fn as_index(&self) -> Option<u32> {
+        match self {
+            PropertyKey::String(name) => {
+                let name = to_str(*name);
+                let index = name.parse::<u32>().ok()?;
+                (index != u32::MAX && index.to_string() == name).then_some(index)
+            }
+            PropertyKey::Symbol(_) => None,
+        }
+    }
+}
+
+impl From<StrId> for PropertyKey {
+    fn from(name: StrId) -> PropertyKey {
+        PropertyKey::String(name)
+    }
+}
+
+// As written in an object literal, quoted unless it's a valid identifier
+impl fmt::Display for PropertyKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PropertyKey::String(name) => {
+                let name = to_str(*name);
+                let mut chars = name.chars();
+                let is_identifier = chars
+                    .next()
+                    .is_some_and(|c| c.is_alphabetic() || c == '_' || c == '$')
+                    && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$');
+                if is_identifier {
+                    write!(f, "{}", name)
+                } else {
+                    write!(f, "'{}'", name)
+                }
+            }
+            PropertyKey::Symbol(symbol) => write!(f, "[{}]", symbol),
+        }
+    }
+}
+
+/// Own properties of an object, kept in insertion order
+#[derive(Default)]
+pub struct Properties {
+    entries: Vec<(PropertyKey, Value)>,
+}
+
+impl Properties {
+    pub fn get(&self, key: &PropertyKey) -> Option<&Value> {
+        self.entries
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn set(&mut self, key: PropertyKey, value: Value) {
+        match self.entries.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, slot)) => *slot = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    /// Entries in ECMAScript enumeration order: integer keys ascending,
+    /// then strings, then symbols, each in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = &(PropertyKey, Value)> {
+        let mut indices: Vec<_> = self
+            .entries
+            .iter()
+            .filter_map(|entry| Some((entry.0.as_index()?, entry)))
+            .collect();
+        indices.sort_by_key(|(index, _)| *index);
+        let strings = self
+            .entries
+            .iter()
+            .filter(|(key, _)| matches!(key, PropertyKey::String(_)) && key.as_index().is_none());
+        let symbols = self
+            .entries
+            .iter()
+            .filter(|(key, _)| matches!(key, PropertyKey::Symbol(_)));
+        indices
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .chain(strings)
+            .chain(symbols)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Write `{ key: value, ... }`, leaving out private class members
+fn write_properties(f: &mut fmt::Formatter, properties: &Properties) -> fmt::Result {
+    let mut entries = properties.iter().filter(|(key, _)| match key {
+        PropertyKey::String(name) => !to_str(*name).starts_with('#'),
+        PropertyKey::Symbol(_) => true,
+    });
+    let Some((key, value)) = entries.next() else {
+        return write!(f, "{{}}");
+    };
+    write!(f, "{{ {}: ", key)?;
+    write_nested(f, value)?;
+    for (key, value) in entries {
+        write!(f, ", {}: ", key)?;
+        write_nested(f, value)?;
+    }
+    write!(f, " }}")
+}
+
+/// A plain object, as created by an object literal
+#[derive(Default)]
+pub struct Object {
+    pub properties: Properties,
+    pub prototype: Option<MutRc<Object>>,
+}
+
+impl Object {
+    /// Look a property up on the object, then along its prototype chain
+    pub fn get(&self, key: &PropertyKey) -> Option<Value> {
+        match self.properties.get(key) {
+            Some(value) => Some(value.clone()),
+            None => self.prototype.as_ref()?.borrow().get(key),
+        }
+    }
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Object) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_properties(f, &self.properties)
+    }
+}
+
+/// A function implemented in Rust
+pub struct Native {
+    pub name: StrId,
+    pub function: NativeFn,
+}
+
+impl PartialEq for Native {
+    fn eq(&self, other: &Native) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[Function: {}]", to_str(self.name))
+    }
+}
+
+/// Something a class puts on its instances' prototype, or on itself when static
+#[derive(Clone)]
+pub enum Member {
+    Method(Rc<Closure>),
+    Accessor {
+        getter: Option<Rc<Closure>>,
+        setter: Option<Rc<Closure>>,
+    },
+}
+
+pub struct Class {
+    pub name: StrId,
+    pub superclass: Option<MutRc<Class>>,
+    pub constructor: Option<Rc<Closure>>,
+    // Runs the field initializers against a new instance, before the constructor body
+    pub initializer: Option<Rc<Closure>>,
+    pub members: FxHashMap<StrId, Member>,
+    pub static_members: FxHashMap<StrId, Member>,
+    // Static fields
+    pub fields: Properties,
+    // Tells this evaluation of the class body apart from others of the same source
+    brand: usize,
+    // The keys private names are stored under, which carry the brand
+    pub private_keys: FxHashMap<StrId, StrId>,
+}
+
+impl Class {
+    pub fn new(name: StrId, brand: usize) -> Class {
+        Class {
+            name,
+            superclass: None,
+            constructor: None,
+            initializer: None,
+            members: FxHashMap::default(),
+            static_members: FxHashMap::default(),
+            fields: Properties::default(),
+            brand,
+            private_keys: FxHashMap::default(),
+        }
+    }
+
+    /// The key a `#name` declared in this class is stored under
+    pub fn private_key(&mut self, name: StrId) -> StrId {
+        let brand = self.brand;
+        *self
+            .private_keys
+            .entry(name)
+            .or_insert_with(|| intern(format!("{}@{}", to_str(name), brand)))
+    }
+
+    /// The key a member is stored under, private names get their brand
+    pub fn member_key(&mut self, name: StrId) -> StrId {
+        if to_str(name).starts_with('#') {
+            self.private_key(name)
+        } else {
+            name
+        }
+    }
+
+    /// Look a member up on this class, then along its superclass chain
+    pub fn find_member(&self, name: StrId, is_static: bool) -> Option<Member> {
+        let members = if is_static {
+            &self.static_members
+        } else {
+            &self.members
+        };
+        match members.get(&name) {
+            Some(member) => Some(member.clone()),
+            None => self
+                .superclass
+                .as_ref()?
+                .borrow()
+                .find_member(name, is_static),
+        }
+    }
+
+    /// Static fields are inherited too, as subclasses have their superclass as prototype
+    pub fn find_field(&self, name: &PropertyKey) -> Option<Value> {
+        match self.fields.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.superclass.as_ref()?.borrow().find_field(name),
+        }
+    }
+
+    pub fn add_method(&mut self, name: StrId, method: Rc<Closure>, is_static: bool) {
+        self.members_mut(is_static)
+            .insert(name, Member::Method(method));
+    }
+
+    /// A getter and a setter of the same name share one accessor
+    pub fn add_accessor(
+        &mut self,
+        name: StrId,
+        function: Rc<Closure>,
+        is_setter: bool,
+        is_static: bool,
+    ) {
+        let members = self.members_mut(is_static);
+        let (mut getter, mut setter) = match members.remove(&name) {
+            Some(Member::Accessor { getter, setter }) => (getter, setter),
+            _ => (None, None),
+        };
+        if is_setter {
+            setter = Some(function);
+        } else {
+            getter = Some(function);
+        }
+        members.insert(name, Member::Accessor { getter, setter });
+    }
+
+    fn members_mut(&mut self, is_static: bool) -> &mut FxHashMap<StrId, Member> {
+        if is_static {
+            &mut self.static_members
+        } else {
+            &mut self.members
+        }
+    }
+}
+
+impl PartialEq for Class {
+    fn eq(&self, other: &Class) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[class {}]", to_str(self.name))
+    }
+}
+
+pub struct Instance {
+    pub class: MutRc<Class>,
+    pub fields: Properties,
+}
+
+impl Instance {
+    pub fn new(class: MutRc<Class>) -> Instance {
+        Instance {
+            class,
+            fields: Properties::default(),
+        }
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Instance) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ", to_str(self.class.borrow().name))?;
+        write_properties(f, &self.fields)
+    }
+}
+
+/// A method looked up on an instance, remembering the instance as its `this`
+pub struct BoundMethod {
+    pub receiver: Value,
+    // Either a closure or a native
+    pub method: Value,
+}
+
+// Looking the same method up twice on one instance gives an equal value, as in JS
+impl PartialEq for BoundMethod {
+    fn eq(&self, other: &BoundMethod) -> bool {
+        self.method == other.method && self.receiver == other.receiver
+    }
+}
+
+impl fmt::Debug for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
@@ -1,7 +1,62 @@
+use super::diagnostic::{Diagnostic, ErrorCode};
 use super::scanner::Scanner;
 use super::token::{Token, TokenType};
+use rustc_hash::FxHashMap;
 use std::mem;
 
+/// Where the parser is in the source, to carry on from there later
+#[derive(Clone)]
+pub struct Checkpoint {
+    previous: Token,
+    current: Token,
+    scanner: (usize, usize, isize, usize),
+    panic_mode: bool,
+}
+
+/// A name declared by a block, which is in scope from the start of the block
+#[derive(Clone)]
+pub struct Declaration {
+    // The keyword declaring it
+    pub kind: TokenType,
+    pub name: String,
+    // Where the keyword of a function declaration starts, and where to compile it from,
+    // the name being the current token
+    pub function: Option<(usize, Checkpoint)>,
+}
+
+// A block whose declarations are being collected, see `Parser::block_declarations`
+struct BlockScan {
+    // Where its '{' is, none for the whole source
+    start: Option<usize>,
+    // Whether it's the body of a function or class, which keeps its `var` names to itself
+    is_function: bool,
+    declarations: Vec<Declaration>,
+    // The token before each open '('
+    parens: Vec<TokenType>,
+    brackets: usize,
+    // The keyword of the variable declaration underway and how deeply it's nested,
+    // names following it or a ',' at that depth being declared
+    declaring: Option<(TokenType, usize)>,
+    expect_name: bool,
+    // The `function` or `class` keyword of a declaration whose name is next
+    keyword: Option<Token>,
+}
+
+impl BlockScan {
+    fn new(start: Option<usize>, is_function: bool) -> BlockScan {
+        BlockScan {
+            start,
+            is_function,
+            declarations: Vec::new(),
+            parens: Vec::new(),
+            brackets: 0,
+            declaring: None,
+            expect_name: false,
+            keyword: None,
+        }
+    }
+}
+
 pub struct Parser {
     pub scanner: Scanner,
     pub previous: Token,
@@ -9,6 +64,10 @@ pub struct Parser {
 
     pub had_error: bool,
     pub panic_mode: bool,
+    // Errors found so far, one for each statement that had any
+    pub diagnostics: Vec<Diagnostic>,
+    // What each block declares, collected the first time one is asked for
+    blocks: Option<FxHashMap<Option<usize>, Vec<Declaration>>>,
 }
 
 impl Parser {
@@ -21,6 +80,8 @@ impl Parser {
 
             had_error: false,
             panic_mode: false,
+            diagnostics: Vec::new(),
+            blocks: None,
         }
     }
 
@@ -39,7 +100,8 @@ impl Parser {
             self.previous = mem::replace(&mut self.current, tok);
 
             if let TokenType::Error = self.current.t_type {
-                self.error(self.current.lexeme.clone());
+                let message = self.current.error.clone().unwrap_or_default();
+                self.error(ErrorCode::InvalidToken, message);
             } else {
                 break;
             }
@@ -58,7 +120,7 @@ impl Parser {
         if t_type == self.current.t_type {
             self.advance();
         } else {
-            self.error(message.to_string());
+            self.error(ErrorCode::ExpectedToken, message.to_string());
         }
     }
 
@@ -66,20 +128,231 @@ impl Parser {
         t_type == self.current.t_type
     }
 
-    pub fn error(&mut self, message: String) {
+    /// Check the token following the current one
+    pub fn check_next(&mut self, t_type: TokenType) -> bool {
+        t_type == self.scanner.peek_token().t_type
+    }
+
+    /// Right after a '(' tell whether it opens the parameter list of an arrow
+    /// function, by scanning ahead to the matching ')' and looking for a '=>'
+    pub fn is_arrow_parameters(&mut self) -> bool {
+        let checkpoint = self.scanner.checkpoint();
+        let mut depth = 1;
+        let mut t_type = self.current.t_type;
+        let is_arrow = loop {
+            match t_type {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break self.scanner.scan_token().t_type == TokenType::Arrow;
+                    }
+                }
+                TokenType::EOF => break false,
+                _ => (),
+            }
+            t_type = self.scanner.scan_token().t_type;
+        };
+        self.scanner.rewind(checkpoint);
+        is_arrow
+    }
+
+    /// Right after a class body's '{' collect the `#names` at the top level of the body,
+    /// so members can refer to private names declared further down
+    pub fn private_names(&mut self) -> Vec<String> {
+        let checkpoint = self.scanner.checkpoint();
+        let mut names = Vec::new();
+        let mut depth = 1;
+        let mut token = self.current.clone();
+        loop {
+            match token.t_type {
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth == 1 => break,
+                TokenType::RightBrace => depth -= 1,
+                TokenType::Identifier if depth == 1 && token.lexeme.starts_with('#') => {
+                    names.push(token.lexeme)
+                }
+                TokenType::EOF => break,
+                _ => (),
+            }
+            token = self.scanner.scan_token();
+        }
+        self.scanner.rewind(checkpoint);
+        names
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            previous: self.previous.clone(),
+            current: self.current.clone(),
+            scanner: self.scanner.checkpoint(),
+            panic_mode: self.panic_mode,
+        }
+    }
+
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.previous = checkpoint.previous;
+        self.current = checkpoint.current;
+        self.scanner.rewind(checkpoint.scanner);
+        self.panic_mode = checkpoint.panic_mode;
+    }
+
+    /// Right after a block's '{', or at the start of the source, the names declared at the
+    /// top level of the block. In a function body, also the `var` names anywhere in it
+    /// outside of nested functions, as those are scoped to the function
+    pub fn block_declarations(&mut self, function_body: bool) -> Vec<Declaration> {
+        if self.blocks.is_none() {
+            self.blocks = Some(self.scan_blocks());
+        }
+        let blocks = self.blocks.as_ref().unwrap();
+        let start = (self.previous.t_type == TokenType::LeftBrace).then_some(self.previous.start);
+        blocks
+            .get(&start)
+            .into_iter()
+            .flatten()
+            .filter(|declaration| function_body || declaration.kind != TokenType::Var)
+            .cloned()
+            .collect()
+    }
+
+    /// Collect the declarations of every block in the source in one go, by where it starts
+    fn scan_blocks(&mut self) -> FxHashMap<Option<usize>, Vec<Declaration>> {
+        let checkpoint = self.scanner.checkpoint();
+        let mut scanned = FxHashMap::default();
+        // The blocks open at the token, innermost last
+        let mut blocks = vec![BlockScan::new(None, true)];
+        // The token before the '(' closed last
+        let mut closed = None;
+        let mut class_body_next = false;
+        let mut previous = None;
+        let mut token = self.current.clone();
+        loop {
+            let is_nested = blocks.len() > 1;
+            let block = blocks.last_mut().unwrap();
+            let nesting = block.parens.len() + block.brackets;
+            if let Some(keyword) = block.keyword.take() {
+                if token.t_type == TokenType::Identifier {
+                    let function = (keyword.t_type == TokenType::Function).then(|| {
+                        let at = Checkpoint {
+                            previous: keyword.clone(),
+                            current: token.clone(),
+                            scanner: self.scanner.checkpoint(),
+                            panic_mode: false,
+                        };
+                        (keyword.start, at)
+                    });
+                    block.declarations.push(Declaration {
+                        kind: keyword.t_type,
+                        name: token.lexeme.clone(),
+                        function,
+                    });
+                }
+            }
+            if let Some((kind, depth)) = block.declaring {
+                if token.t_type == TokenType::Identifier && block.expect_name {
+                    block.declarations.push(Declaration {
+                        kind,
+                        name: token.lexeme.clone(),
+                        function: None,
+                    });
+                }
+                let at_depth = nesting == depth;
+                block.expect_name = token.t_type == TokenType::Comma && at_depth;
+                if token.t_type == TokenType::Semicolon && at_depth || nesting < depth {
+                    block.declaring = None;
+                }
+            }
+
+            // Function and class declarations start a statement, elsewhere they're expressions
+            let statement_start = matches!(
+                previous,
+                None | Some(TokenType::Semicolon)
+                    | Some(TokenType::LeftBrace)
+                    | Some(TokenType::RightBrace)
+            );
+            match token.t_type {
+                TokenType::EOF => break,
+                TokenType::Var => {
+                    block.declaring = Some((TokenType::Var, nesting));
+                    block.expect_name = true;
+                }
+                TokenType::Let | TokenType::Const if nesting == 0 => {
+                    block.declaring = Some((token.t_type, nesting));
+                    block.expect_name = true;
+                }
+                TokenType::Function | TokenType::Class if nesting == 0 && statement_start => {
+                    block.keyword = Some(token.clone())
+                }
+                _ => {}
+            }
+
+            match token.t_type {
+                TokenType::LeftBrace => {
+                    // Function bodies follow an arrow or a parameter list, which unlike
+                    // the condition of a statement doesn't follow a keyword
+                    let is_function = class_body_next
+                        || previous == Some(TokenType::Arrow)
+                        || previous == Some(TokenType::RightParen)
+                            && !matches!(
+                                closed,
+                                Some(TokenType::If) | Some(TokenType::While) | Some(TokenType::For)
+                            );
+                    blocks.push(BlockScan::new(Some(token.start), is_function));
+                    class_body_next = false;
+                }
+                // A stray '}' is left for the compiler to report
+                TokenType::RightBrace if is_nested => {
+                    let block = blocks.pop().unwrap();
+                    // `var` belongs to the function, so it's declared by the blocks around
+                    // too, up to the function body
+                    if !block.is_function {
+                        let vars = block
+                            .declarations
+                            .iter()
+                            .filter(|declaration| declaration.kind == TokenType::Var)
+                            .cloned();
+                        blocks.last_mut().unwrap().declarations.extend(vars);
+                    }
+                    scanned.insert(block.start, block.declarations);
+                }
+                TokenType::LeftParen => block.parens.push(previous.unwrap_or(TokenType::Error)),
+                TokenType::RightParen => closed = block.parens.pop(),
+                TokenType::LeftBracket => block.brackets += 1,
+                TokenType::RightBracket => block.brackets = block.brackets.saturating_sub(1),
+                TokenType::Class => class_body_next = true,
+                _ => {}
+            }
+            previous = Some(token.t_type);
+            token = self.scanner.scan_token();
+        }
+        // Blocks left open at the end of the source still declare what they got to
+        for block in blocks {
+            scanned.insert(block.start, block.declarations);
+        }
+        self.scanner.rewind(checkpoint);
+        scanned
+    }
+
+    /// Report an error at the current token, unless one was just reported
+    pub fn error(&mut self, code: ErrorCode, message: String) {
+        let span = self.current.span();
+        self.report(Diagnostic::error(code, message, span));
+    }
+
+    /// Report an error at the token just consumed, unless one was just reported
+    pub fn error_at_previous(&mut self, code: ErrorCode, message: String) {
+        let span = self.previous.span();
+        self.report(Diagnostic::error(code, message, span));
+    }
+
+    /// Report an error, unless one was just reported, as it may only follow from that one
+    pub fn report(&mut self, diagnostic: Diagnostic) {
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
 
-        eprint!("[Line {}] Error", self.current.line);
-        match self.current.t_type {
-            TokenType::EOF => eprint!(" at end"),
-            TokenType::Error => (),
-            _ => eprint!(" at line {}", self.current.line),
-        }
-        eprintln!(": {}", message);
-
+        self.diagnostics.push(diagnostic);
         self.had_error = true;
     }
 
@@ -98,9 +371,12 @@ impl Parser {
                 TokenType::Class
                 | TokenType::Function
                 | TokenType::Var
+                | TokenType::Let
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
+                | TokenType::Do
                 | TokenType::Print
                 | TokenType::Return => return,
                 _ => (),
@@ -0,0 +1,309 @@
+//! Errors rendered for people to read, or as JSON for editors and CI to parse.
+//!
+//! The human format follows rustc: a header with the code and message, where it happened,
+//! the source line with the span underlined, then any notes, help and the calls a runtime
+//! error happened in. Without the source, as for code loaded from bytecode, only the
+//! location is shown. The JSON format is one object per diagnostic, one per line.
+
+use super::diagnostic::{Diagnostic, InterpretError, Severity, Span, Stage, TraceFrame};
+use std::fmt::Write;
+
+// ANSI styles
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// Render every diagnostic of `error`, quoting `source` when it's the code they point into
+pub fn render(error: &InterpretError, source: Option<&str>, color: bool) -> String {
+    let mut out = String::new();
+    for (index, diagnostic) in error.diagnostics.iter().enumerate() {
+        if index > 0 {
+            out.push('\n');
+        }
+        let mut notes: Vec<String> = diagnostic.notes.clone();
+        // The trace only belongs to the runtime error, which is the only diagnostic
+        notes.extend(error.stack_trace.iter().map(|frame| frame.to_string()));
+        render_diagnostic(&mut out, diagnostic, &notes, source, color);
+    }
+    out
+}
+
+fn render_diagnostic(
+    out: &mut String,
+    diagnostic: &Diagnostic,
+    notes: &[String],
+    source: Option<&str>,
+    color: bool,
+) {
+    let paint = |text: &str, style: &str| match color {
+        true => format!("{}{}{}", style, text, RESET),
+        false => text.to_string(),
+    };
+    let severity = match diagnostic.severity {
+        Severity::Error => RED,
+        Severity::Warning => YELLOW,
+    };
+    let span = &diagnostic.span;
+    let gutter = " ".repeat(span.line.to_string().len());
+
+    let header = format!("{}[{}]", diagnostic.severity, diagnostic.code);
+    let message = format!(": {}", diagnostic.message);
+    let _ = writeln!(out, "{}{}", paint(&header, severity), paint(&message, BOLD));
+    let _ = writeln!(out, "{}{} {}", gutter, paint("-->", BLUE), span);
+
+    if let Some((text, underline)) = source.and_then(|source| snippet(source, span)) {
+        let bar = paint("|", BLUE);
+        let _ = writeln!(out, "{} {}", gutter, bar);
+        let number = paint(&span.line.to_string(), BLUE);
+        let _ = writeln!(out, "{} {} {}", number, bar, text);
+        let _ = writeln!(out, "{} {} {}", gutter, bar, paint(&underline, severity));
+        if !notes.is_empty() || diagnostic.help.is_some() {
+            let _ = writeln!(out, "{} {}", gutter, bar);
+        }
+    }
+
+    let labels = notes
+        .iter()
+        .map(|note| ("note", note))
+        .chain(diagnostic.help.iter().map(|help| ("help", help)));
+    for (label, text) in labels {
+        let label = paint(&format!("{}:", label), BOLD);
+        let _ = writeln!(out, "{} {} {} {}", gutter, paint("=", BLUE), label, text);
+    }
+}
+
+// The line `span` starts on, and carets under the part of it the span covers
+fn snippet<'a>(source: &'a str, span: &Span) -> Option<(&'a str, String)> {
+    let text = source.lines().nth((span.line as usize).checked_sub(1)?)?;
+    let start = (span.column as usize).checked_sub(1)?;
+    let before = text.get(..start)?;
+    // Spans over several lines are underlined to the end of the first one
+    let end = (start + span.end.saturating_sub(span.start)).min(text.len());
+    let covered = text.get(start..end)?.chars().count().max(1);
+
+    // Keep tabs so the carets line up with the text above them
+    let mut underline: String = before
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    underline.push_str(&"^".repeat(covered));
+    Some((text, underline))
+}
+
+/// Every diagnostic of `error` as a JSON object, one per line
+pub fn to_json(error: &InterpretError) -> String {
+    let stage = match error.stage {
+        Stage::Compile => "compile",
+        Stage::Runtime => "runtime",
+    };
+    let stack_trace: Vec<String> = error.stack_trace.iter().map(frame_json).collect();
+
+    let mut out = String::new();
+    for diagnostic in error.diagnostics.iter() {
+        let notes: Vec<String> = diagnostic
+            .notes
+            .iter()
+            .map(|note| string_json(note))
+            .collect();
+        let help = match &diagnostic.help {
+            Some(help) => string_json(help),
+            None => String::from("null"),
+        };
+        let _ = writeln!(
+            out,
+            "{{\"stage\":\"{}\",\"severity\":\"{}\",\"code\":\"{}\",\"message\":{},\"span\":{},\"notes\":[{}],\"help\":{},\"stack_trace\":[{}]}}",
+            stage,
+            diagnostic.severity,
+            diagnostic.code,
+            string_json(&diagnostic.message),
+            span_json(&diagnostic.span),
+            notes.join(","),
+            help,
+            stack_trace.join(",")
+        );
+    }
+    out
+}
+
+fn frame_json(frame: &TraceFrame) -> String {
+    format!(
+        "{{\"function\":{},\"span\":{},\"repeated\":{}}}",
+        string_json(&frame.function),
+        span_json(&frame.span),
+        frame.repeated
+    )
+}
+
+fn span_json(span: &Span) -> String {
+    let file = match &span.file {
+        Some(file) => string_json(file),
+        None => String::from("null"),
+    };
+    format!(
+        "{{\"file\":{},\"line\":{},\"column\":{},\"start\":{},\"end\":{}}}",
+        file, span.line, span.column, span.start, span.end
+    )
+}
+
+fn string_json(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::diagnostic::ErrorCode;
+
+    fn span(line: u32, column: u32, start: usize, end: usize) -> Span {
+        Span {
+            file: None,
+            line,
+            column,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn underlines_the_span_under_its_line() {
+        let source = "let a = 1;\nprint  bc;\n";
+        let (text, underline) = snippet(source, &span(2, 8, 18, 20)).unwrap();
+        assert_eq!(text, "print  bc;");
+        assert_eq!(underline, "       ^^");
+        // Empty spans still get a caret
+        assert_eq!(
+            snippet(source, &span(1, 11, 10, 10)).unwrap().1,
+            "          ^"
+        );
+        assert_eq!(snippet(source, &span(3, 1, 22, 23)), None);
+    }
+
+    #[test]
+    fn keeps_tabs_before_the_carets() {
+        let source = "\tif (x)\t{ y; }";
+        let (_, underline) = snippet(source, &span(1, 9, 8, 9)).unwrap();
+        assert_eq!(underline, "\t      \t^");
+    }
+
+    #[test]
+    fn underlines_spans_over_several_lines_to_the_end_of_the_first() {
+        let source = "print `one\ntwo`;";
+        let (text, underline) = snippet(source, &span(1, 7, 6, 15)).unwrap();
+        assert_eq!(text, "print `one");
+        assert_eq!(underline, "      ^^^^");
+    }
+
+    fn runtime_error() -> InterpretError {
+        let diagnostic = Diagnostic::error(
+            ErrorCode::ReferenceError,
+            String::from("ReferenceError: x is not defined"),
+            span(12, 3, 100, 101),
+        )
+        .with_help(String::from("declare it first"))
+        .with_note(String::from("it's read here"));
+        let frames = vec![
+            TraceFrame {
+                function: String::from("f"),
+                span: span(12, 3, 100, 101),
+                repeated: 2,
+            },
+            TraceFrame {
+                function: String::from("<script>"),
+                span: span(14, 1, 120, 121),
+                repeated: 0,
+            },
+        ];
+        InterpretError::runtime(diagnostic, frames)
+    }
+
+    #[test]
+    fn renders_notes_then_the_trace_then_help() {
+        let source = "\n".repeat(11) + "  x;\n";
+        let rendered = render(&runtime_error(), Some(&source), false);
+        assert_eq!(
+            rendered,
+            "error[E1002]: ReferenceError: x is not defined
+  --> 12:3
+   |
+12 |   x;
+   |   ^
+   |
+   = note: it's read here
+   = note: at f (12:3), repeated 2 more times
+   = note: at <script> (14:1)
+   = help: declare it first
+"
+        );
+        // Without the source there's only where it happened
+        let rendered = render(&runtime_error(), None, false);
+        assert!(rendered
+            .starts_with("error[E1002]: ReferenceError: x is not defined\n  --> 12:3\n   = note"));
+    }
+
+    #[test]
+    fn colours_only_when_asked_to() {
+        let source = "\n".repeat(11) + "  x;\n";
+        assert!(!render(&runtime_error(), Some(&source), false).contains('\x1b'));
+        let rendered = render(&runtime_error(), Some(&source), true);
+        assert!(rendered.starts_with(&format!(
+            "{}error[E1002]{}{}: ReferenceError",
+            RED, RESET, BOLD
+        )));
+        assert!(rendered.contains(&format!("{}  ^{}", RED, RESET)));
+        assert!(rendered.contains(&format!("{}-->{}", BLUE, RESET)));
+    }
+
+    #[test]
+    fn writes_a_json_object_per_diagnostic() {
+        let mut error = InterpretError::compile(vec![
+            Diagnostic::error(
+                ErrorCode::ExpectedToken,
+                String::from("Expected ';' after \"a\\b\"\n\tnot \u{1}"),
+                span(1, 2, 1, 2),
+            ),
+            Diagnostic::error(
+                ErrorCode::InvalidToken,
+                String::from("Unexpected character 'é'."),
+                span(2, 1, 5, 6),
+            )
+            .with_help(String::from("remove it")),
+        ]);
+        error.set_file("dir/a \"b\".ts");
+        let json = to_json(&error);
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"stage":"compile","severity":"error","code":"E0002","message":"Expected ';' after \"a\\b\"\n\tnot \u0001","span":{"file":"dir/a \"b\".ts","line":1,"column":2,"start":1,"end":2},"notes":[],"help":null,"stack_trace":[]}"#,
+                r#"{"stage":"compile","severity":"error","code":"E0001","message":"Unexpected character 'é'.","span":{"file":"dir/a \"b\".ts","line":2,"column":1,"start":5,"end":6},"notes":[],"help":"remove it","stack_trace":[]}"#,
+            ]
+        );
+
+        let json = to_json(&runtime_error());
+        assert!(json.starts_with(r#"{"stage":"runtime","#));
+        assert!(json.ends_with(concat!(
+            r#""notes":["it's read here"],"help":"declare it first","stack_trace":["#,
+            r#"{"function":"f","span":{"file":null,"line":12,"column":3,"start":100,"end":101},"repeated":2},"#,
+            r#"{"function":"<script>","span":{"file":null,"line":14,"column":1,"start":120,"end":121},"repeated":0}]}"#,
+            "\n"
+        )));
+    }
+}
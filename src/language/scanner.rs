@@ -7,6 +7,11 @@ pub struct Scanner {
     lexeme: String,
     pub source: String,
     pub line: isize,
+    // Where the current line begins, to tell the column of a token
+    line_start: usize,
+    // Position of the first character of the token being scanned
+    start_line: isize,
+    start_column: isize,
 }
 
 impl Scanner {
@@ -17,6 +22,9 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
@@ -89,7 +97,8 @@ impl Scanner {
     fn make_token(&self, t_type: TokenType) -> Token {
         Token {
             length: self.current - self.start,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             start: self.start,
             // source: self.source,
             lexeme: self.source[(self.start)..(self.current)].to_string(),
@@ -100,8 +109,9 @@ impl Scanner {
 
     fn error_token(&self, message: &'static str) -> Token {
         Token {
-            length: message.len(),
-            line: self.line,
+            length: self.current - self.start,
+            line: self.start_line,
+            column: self.start_column,
             start: self.start,
             lexeme: self.source[(self.start)..(self.current)].to_string(),
             // source: self.source,
@@ -110,6 +120,12 @@ impl Scanner {
         }
     }
 
+    // Called on the newline character, before advancing past it
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current + 1;
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             if self.is_at_end() {
@@ -122,7 +138,7 @@ impl Scanner {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
+                    self.new_line();
                     println!("[scanner][line {}] line advanced", self.line);
                     self.advance();
                 }
@@ -136,13 +152,12 @@ impl Scanner {
                                 break;
                             }
                         }
-                    }
-                    if self.peek_next() == '*' {
+                    } else if self.peek_next() == '*' {
                         // A /* */ comment means we need to find */ or the end of file
                         loop {
                             // Handle new line increments
                             if self.peek() == '\n' {
-                                self.line += 1;
+                                self.new_line();
                             }
                             if self.is_at_end() {
                                 // nothing to do here
@@ -161,6 +176,9 @@ impl Scanner {
                                 self.advance();
                             }
                         }
+                    } else {
+                        // A lone '/' is the division operator
+                        return;
                     }
                 }
                 _ => return,
@@ -169,55 +187,60 @@ impl Scanner {
     }
 
     fn check_keyword(&self, start: usize, rest: &str, token_type: TokenType) -> TokenType {
-        // TODO: all of this looks horrible, surely there is a better way to compare chars and mplement this trie
-        let mut offset = 0;
-        let end = rest.len();
-        loop {
-            let mut chars = self.source.chars();
-            let mut rest = rest.chars();
-            let curr_char = chars.nth(self.start + start + offset).unwrap();
-            let curr_checked = rest.nth(offset).unwrap();
-            if curr_char != curr_checked {
-                return TokenType::Identifier;
-            }
-            offset = offset + 1;
-            if offset >= end - start {
-                return token_type;
-            }
+        let lexeme = &self.source[(self.start)..(self.current)];
+        if lexeme.len() == start + rest.len() && &lexeme[start..] == rest {
+            token_type
+        } else {
+            TokenType::Identifier
         }
     }
 
     fn identifier_type(&mut self) -> TokenType {
+        let mut chars = self.source[(self.start)..(self.current)].chars();
         //TODO: improve error handling
-        let c = self
-            .source
-            .chars()
-            .nth(self.start)
+        let c = chars
+            .next()
             .expect("[scanner] trying to peek identifier out of bounds character");
+        let second = chars.next().unwrap_or('\0');
 
         return match c {
             // 'a' => self.check_keyword(1, "nd", TokenType::And),
             // 'o' => self.check_keyword(1, "r", TokenType::Or),
-            'c' => self.check_keyword(1, "lass", TokenType::Class),
-            'e' => match self.peek_next() {
+            'b' => self.check_keyword(1, "reak", TokenType::Break),
+            'c' => match second {
+                'l' => self.check_keyword(2, "ass", TokenType::Class),
+                'o' => match self.check_keyword(2, "nst", TokenType::Const) {
+                    TokenType::Identifier => self.check_keyword(2, "ntinue", TokenType::Continue),
+                    token_type => token_type,
+                },
+                _ => TokenType::Identifier,
+            },
+            'd' => self.check_keyword(1, "o", TokenType::Do),
+            'e' => match second {
                 'l' => self.check_keyword(2, "se", TokenType::Else),
                 'x' => self.check_keyword(2, "tends", TokenType::Extends),
                 _ => TokenType::Identifier,
             },
             'i' => self.check_keyword(1, "f", TokenType::If),
-            'n' => self.check_keyword(1, "ull", TokenType::Null),
+            'l' => self.check_keyword(1, "et", TokenType::Let),
+            'n' => match second {
+                'e' => self.check_keyword(2, "w", TokenType::New),
+                'u' => self.check_keyword(2, "ll", TokenType::Null),
+                _ => TokenType::Identifier,
+            },
             'p' => self.check_keyword(1, "rint", TokenType::Print),
             'r' => self.check_keyword(1, "eturn", TokenType::Return),
             's' => self.check_keyword(1, "uper", TokenType::Super),
+            'u' => self.check_keyword(1, "ndefined", TokenType::Undefined),
             'v' => self.check_keyword(1, "ar", TokenType::Var),
             'w' => self.check_keyword(1, "hile", TokenType::While),
-            'f' => match self.peek_next() {
+            'f' => match second {
                 'a' => self.check_keyword(2, "lse", TokenType::False),
                 'o' => self.check_keyword(2, "r", TokenType::For),
                 'u' => self.check_keyword(2, "nction", TokenType::Function),
                 _ => TokenType::Identifier,
             },
-            't' => match self.peek_next() {
+            't' => match second {
                 'h' => self.check_keyword(2, "is", TokenType::This),
                 'r' => self.check_keyword(2, "ue", TokenType::True),
                 _ => TokenType::Identifier,
@@ -268,7 +291,7 @@ impl Scanner {
         loop {
             if self.peek() != terminator && !self.is_at_end() {
                 if self.peek() == '\n' {
-                    self.line = self.line + 1;
+                    self.new_line();
                 }
                 self.advance();
             } else {
@@ -277,7 +300,7 @@ impl Scanner {
         }
 
         if self.is_at_end() {
-            return self.error_token("[scanner] Unterminated string.");
+            return self.error_token("Unterminated string.");
         }
 
         //closing quote
@@ -285,10 +308,33 @@ impl Scanner {
         return self.make_token(TokenType::String);
     }
 
+    /// Scan the token after the current one without consuming it
+    pub fn peek_token(&mut self) -> Token {
+        let checkpoint = self.checkpoint();
+        let token = self.scan_token();
+        self.rewind(checkpoint);
+        token
+    }
+
+    /// Remember the scanning position, to backtrack to it after looking ahead
+    pub fn checkpoint(&self) -> (usize, usize, isize, usize) {
+        (self.start, self.current, self.line, self.line_start)
+    }
+
+    pub fn rewind(&mut self, checkpoint: (usize, usize, isize, usize)) {
+        let (start, current, line, line_start) = checkpoint;
+        self.start = start;
+        self.current = current;
+        self.line = line;
+        self.line_start = line_start;
+    }
+
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = (self.start - self.line_start + 1) as isize;
 
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
@@ -299,7 +345,8 @@ impl Scanner {
         if Self::is_digit(c) {
             return self.number();
         }
-        if Self::is_alpha(c) {
+        // `#name` is a private class member
+        if Self::is_alpha(c) || (c == '#' && Self::is_alpha(self.peek())) {
             return self.identifier();
         }
 
@@ -308,15 +355,34 @@ impl Scanner {
             ')' => return self.make_token(TokenType::RightParen),
             '{' => return self.make_token(TokenType::LeftBrace),
             '}' => return self.make_token(TokenType::RightBrace),
+            '[' => return self.make_token(TokenType::LeftBracket),
+            ']' => return self.make_token(TokenType::RightBracket),
             ';' => return self.make_token(TokenType::Semicolon),
             ',' => return self.make_token(TokenType::Comma),
-            '.' => return self.make_token(TokenType::Dot),
+            '.' => {
+                if self.peek() == '.' && self.peek_next() == '.' {
+                    self._match('.');
+                    self._match('.');
+                    return self.make_token(TokenType::DotDotDot);
+                }
+                return self.make_token(TokenType::Dot);
+            }
             '-' => return self.make_token(TokenType::Minus),
             '+' => return self.make_token(TokenType::Plus),
             '/' => return self.make_token(TokenType::Slash),
             '*' => return self.make_token(TokenType::Star),
+            '?' => {
+                let token_type = match self._match('?') {
+                    true if self._match('=') => TokenType::QuestionQuestionEqual,
+                    true => TokenType::QuestionQuestion,
+                    _ => TokenType::Question,
+                };
+                return self.make_token(token_type);
+            }
+            ':' => return self.make_token(TokenType::Colon),
             '&' => {
                 let token_type = match self._match('&') {
+                    true if self._match('=') => TokenType::AndEqual,
                     true => TokenType::And,
                     _ => TokenType::BitwiseAnd,
                 };
@@ -324,6 +390,7 @@ impl Scanner {
             }
             '|' => {
                 let token_type = match self._match('|') {
+                    true if self._match('=') => TokenType::OrEqual,
                     true => TokenType::Or,
                     _ => TokenType::BitwiseOr,
                 };
@@ -340,8 +407,9 @@ impl Scanner {
             }
             // TODO: strict comparisons
             '=' => {
-                let token_type = match self._match('=') {
-                    true => TokenType::EqualEqual,
+                let token_type = match () {
+                    _ if self._match('=') => TokenType::EqualEqual,
+                    _ if self._match('>') => TokenType::Arrow,
                     _ => TokenType::Equal,
                 };
                 return self.make_token(token_type);
@@ -0,0 +1,247 @@
+//! The one representation of string values, however they were produced.
+//!
+//! A string is compared by its text, and hashes to a value computed the first time it's
+//! needed. Literals come from the interner, which hands out the same string for the same
+//! text, so comparing them usually stops at the pointer check.
+//!
+//! Concatenating long strings doesn't copy them: the result only points at both halves,
+//! and gets flattened into one buffer the first time its text is read. Appending to a
+//! string in a loop builds a chain of these, which is copied once at the end.
+
+use rustc_hash::FxHasher;
+use std::{
+    cell::{OnceCell, RefCell},
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    rc::Rc,
+};
+
+// Shorter results are copied right away, it's cheaper than keeping the halves around
+const MIN_ROPE_LEN: usize = 64;
+
+#[derive(Clone)]
+pub struct JsString(Rc<Text>);
+
+struct Text {
+    len: usize,
+    hash: OnceCell<u64>,
+    flat: OnceCell<Box<str>>,
+    // Halves of a concatenation, until it's flattened
+    parts: RefCell<Option<(JsString, JsString)>>,
+}
+
+/// What any string with this text hashes to
+pub fn hash_text(text: &str) -> u64 {
+    let mut hasher = FxHasher::default();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl JsString {
+    fn flat(text: Box<str>) -> JsString {
+        JsString(Rc::new(Text {
+            len: text.len(),
+            hash: OnceCell::new(),
+            flat: OnceCell::from(text),
+            parts: RefCell::new(None),
+        }))
+    }
+
+    /// This string followed by `other`
+    pub fn concat(&self, other: &JsString) -> JsString {
+        if self.is_empty() {
+            return other.clone();
+        }
+        if other.is_empty() {
+            return self.clone();
+        }
+        let len = self.0.len + other.0.len;
+        if len < MIN_ROPE_LEN {
+            let mut text = String::with_capacity(len);
+            text.push_str(self.as_str());
+            text.push_str(other.as_str());
+            return JsString::from(text);
+        }
+        JsString(Rc::new(Text {
+            len,
+            hash: OnceCell::new(),
+            flat: OnceCell::new(),
+            parts: RefCell::new(Some((self.clone(), other.clone()))),
+        }))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.flat.get_or_init(|| self.flatten())
+    }
+
+    /// Length of the text in bytes, known without flattening
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+
+    pub fn hash_value(&self) -> u64 {
+        *self.0.hash.get_or_init(|| hash_text(self.as_str()))
+    }
+
+    /// Whether both are the same string, not just the same text
+    pub fn ptr_eq(&self, other: &JsString) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    // Copy the leaves of the concatenation in order, without recursing,
+    // as appending in a loop makes the tree as deep as it is long
+    fn flatten(&self) -> Box<str> {
+        let parts = self.0.parts.borrow_mut().take();
+        let (left, right) = parts.expect("a string is either flat or a concatenation");
+        let mut text = String::with_capacity(self.0.len);
+        let mut pending = vec![right, left];
+        while let Some(string) = pending.pop() {
+            if let Some(flat) = string.0.flat.get() {
+                text.push_str(flat);
+                continue;
+            }
+            let parts = string.0.parts.borrow();
+            let (left, right) = parts.as_ref().unwrap();
+            pending.push(right.clone());
+            pending.push(left.clone());
+        }
+        text.into_boxed_str()
+    }
+}
+
+// Dropping a long chain of concatenations one level at a time would overflow the stack
+impl Drop for Text {
+    fn drop(&mut self) {
+        let mut pending: Vec<JsString> = Vec::new();
+        if let Some((left, right)) = self.parts.get_mut().take() {
+            pending.extend([left, right]);
+        }
+        while let Some(string) = pending.pop() {
+            if let Ok(mut text) = Rc::try_unwrap(string.0) {
+                if let Some((left, right)) = text.parts.get_mut().take() {
+                    pending.extend([left, right]);
+                }
+            }
+        }
+    }
+}
+
+impl From<&str> for JsString {
+    fn from(text: &str) -> JsString {
+        JsString::flat(Box::from(text))
+    }
+}
+
+impl From<String> for JsString {
+    fn from(text: String) -> JsString {
+        JsString::flat(text.into_boxed_str())
+    }
+}
+
+impl Deref for JsString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq for JsString {
+    fn eq(&self, other: &JsString) -> bool {
+        self.ptr_eq(other)
+            || (self.len() == other.len()
+                && self.hash_value() == other.hash_value()
+                && self.as_str() == other.as_str())
+    }
+}
+
+impl Eq for JsString {}
+
+impl Hash for JsString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash_value());
+    }
+}
+
+impl PartialOrd for JsString {
+    fn partial_cmp(&self, other: &JsString) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for JsString {
+    fn cmp(&self, other: &JsString) -> std::cmp::Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl fmt::Debug for JsString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for JsString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn append(times: usize, piece: &str) -> JsString {
+        let piece = JsString::from(piece);
+        let mut string = JsString::from("");
+        for _ in 0..times {
+            string = string.concat(&piece);
+        }
+        string
+    }
+
+    #[test]
+    fn appends_without_copying_until_read() {
+        let started = Instant::now();
+        let string = append(100_000, "x");
+        assert_eq!(string.len(), 100_000);
+        assert!(string.0.flat.get().is_none());
+        assert_eq!(string.as_str(), "x".repeat(100_000));
+        // Copying the text on every append takes seconds, this leaves room for a busy machine
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn flattens_once_on_first_comparison() {
+        let left = JsString::from("a".repeat(40));
+        let right = JsString::from("b".repeat(40));
+        let string = left.concat(&right);
+        assert!(string.0.flat.get().is_none());
+        assert_eq!(string, JsString::from("a".repeat(40) + &"b".repeat(40)));
+        assert!(string.0.parts.borrow().is_none());
+        assert_eq!(string.hash_value(), hash_text(&string));
+        // Short results are copied straight away
+        assert!(JsString::from("a")
+            .concat(&right)
+            .0
+            .parts
+            .borrow()
+            .is_none());
+    }
+
+    #[test]
+    fn drops_long_chains_without_recursing() {
+        // Deep enough to overflow a test thread's stack one level at a time
+        drop(append(1_000_000, "xy"));
+        let shared = append(1_000, "x");
+        let longer = shared.concat(&append(1_000, "y"));
+        drop(longer);
+        assert_eq!(shared.as_str(), "x".repeat(1_000));
+    }
+}
@@ -1,3 +1,4 @@
+use super::diagnostic::Span;
 use plain_enum::plain_enum_mod;
 use std::fmt;
 
@@ -7,55 +8,74 @@ plain_enum_mod! {this,TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
+    DotDotDot,
     Minus,
+    // 10
     Plus,
     Semicolon,
     Slash,
-    // 10
     Star,
+    Question,
+    Colon,
     // One or two character tokens.
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
+    // 20
+    Arrow,
     Greater,
     GreaterEqual,
     Less,
     LessEqual,
     // Literals.
     Identifier,
-    // 20
     String,
     Number,
     //Operations
     And,
     Or,
+    // 30
+    QuestionQuestion,
+    AndEqual,
+    OrEqual,
+    QuestionQuestionEqual,
     // https://developer.mozilla.org/en-US/docs/Web/JavaScript/Guide/Expressions_and_Operators
     BitwiseAnd, // a & b
     BitwiseOr,  // a | b
     BitwiseXor, // a ^ b
     BitwiseNot, // ~ a
     // Keywords.
+    Break,
     Class,
+    // 40
+    Const,
+    Continue,
+    Do,
     Else,
-    // 30
     Extends,
     False,
     For,
     Function,
     If,
+    Let,
+    // 50
+    New,
     Null,
     Print,
     Return,
     Super,
     This,
-    // 40
     True,
+    Undefined,
     Var,
     While,
 
+    // 60
     Error,
     EOF,
 }}
@@ -66,6 +86,7 @@ pub struct Token {
     pub start: usize,
     pub length: usize,
     pub line: isize,
+    pub column: isize,
     pub lexeme: String,
     pub error: Option<String>,
 }
@@ -77,10 +98,22 @@ impl Token {
             start: 0,
             length: 0,
             line: 0,
+            column: 0,
             lexeme: String::from(""),
             error: None,
         }
     }
+
+    /// The source this token was scanned from
+    pub fn span(&self) -> Span {
+        Span {
+            file: None,
+            line: self.line as u32,
+            column: self.column as u32,
+            start: self.start,
+            end: self.start + self.length,
+        }
+    }
 }
 
 impl fmt::Display for TokenType {
@@ -90,17 +123,23 @@ impl fmt::Display for TokenType {
             TokenType::RightParen => write!(f, "TokenType::RIGHT_PAREN"),
             TokenType::LeftBrace => write!(f, "TokenType::LEFT_BRACE"),
             TokenType::RightBrace => write!(f, "TokenType::RIGHT_BRACE"),
+            TokenType::LeftBracket => write!(f, "TokenType::LEFT_BRACKET"),
+            TokenType::RightBracket => write!(f, "TokenType::RIGHT_BRACKET"),
             TokenType::Comma => write!(f, "TokenType::COMMA"),
             TokenType::Dot => write!(f, "TokenType::DOT"),
+            TokenType::DotDotDot => write!(f, "TokenType::DOT_DOT_DOT"),
             TokenType::Minus => write!(f, "TokenType::MINUS"),
             TokenType::Plus => write!(f, "TokenType::PLUS"),
             TokenType::Semicolon => write!(f, "TokenType::SEMICOLON"),
             TokenType::Slash => write!(f, "TokenType::SLASH"),
             TokenType::Star => write!(f, "TokenType::STAR"),
+            TokenType::Question => write!(f, "TokenType::QUESTION"),
+            TokenType::Colon => write!(f, "TokenType::COLON"),
             TokenType::Bang => write!(f, "TokenType::BANG"),
             TokenType::BangEqual => write!(f, "TokenType::BANG_EQUAL"),
             TokenType::Equal => write!(f, "TokenType::EQUAL"),
             TokenType::EqualEqual => write!(f, "TokenType::EQUAL_EQUAL"),
+            TokenType::Arrow => write!(f, "TokenType::ARROW"),
             TokenType::Greater => write!(f, "TokenType::GREATER"),
             TokenType::GreaterEqual => write!(f, "TokenType::GREATER_EQUAL"),
             TokenType::Less => write!(f, "TokenType::LESS"),
@@ -109,20 +148,31 @@ impl fmt::Display for TokenType {
             TokenType::String => write!(f, "TokenType::STRING"),
             TokenType::Number => write!(f, "TokenType::NUMBER"),
             TokenType::And => write!(f, "TokenType::AND"),
+            TokenType::Break => write!(f, "TokenType::BREAK"),
             TokenType::Class => write!(f, "TokenType::CLASS"),
+            TokenType::Const => write!(f, "TokenType::CONST"),
+            TokenType::Continue => write!(f, "TokenType::CONTINUE"),
+            TokenType::Do => write!(f, "TokenType::DO"),
             TokenType::Else => write!(f, "TokenType::ELSE"),
             TokenType::Extends => write!(f, "TokenType::EXTENDS"),
             TokenType::False => write!(f, "TokenType::FALSE"),
             TokenType::For => write!(f, "TokenType::FOR"),
             TokenType::Function => write!(f, "TokenType::FUN"),
             TokenType::If => write!(f, "TokenType::IF"),
+            TokenType::Let => write!(f, "TokenType::LET"),
+            TokenType::New => write!(f, "TokenType::NEW"),
             TokenType::Null => write!(f, "TokenType::NULL"),
             TokenType::Or => write!(f, "TokenType::OR"),
+            TokenType::QuestionQuestion => write!(f, "TokenType::QUESTION_QUESTION"),
+            TokenType::AndEqual => write!(f, "TokenType::AND_EQUAL"),
+            TokenType::OrEqual => write!(f, "TokenType::OR_EQUAL"),
+            TokenType::QuestionQuestionEqual => write!(f, "TokenType::QUESTION_QUESTION_EQUAL"),
             TokenType::Print => write!(f, "TokenType::PRINT"),
             TokenType::Return => write!(f, "TokenType::RETURN"),
             TokenType::Super => write!(f, "TokenType::SUPER"),
             TokenType::This => write!(f, "TokenType::THIS"),
             TokenType::True => write!(f, "TokenType::TRUE"),
+            TokenType::Undefined => write!(f, "TokenType::UNDEFINED"),
             TokenType::Var => write!(f, "TokenType::VAR"),
             TokenType::While => write!(f, "TokenType::WHILE"),
             TokenType::Error => write!(f, "TokenType::ERROR"),
@@ -1,8 +1,12 @@
-use super::common::{to_str, StrId};
+use super::common::MutRc;
+use super::object::{
+    Array, BoundMethod, Class, Closure, Function, Instance, Native, Object, Symbol,
+};
+use super::string::JsString;
 use enum_methods::EnumAsGetters;
 use enum_methods::EnumIntoGetters;
 use enum_methods::EnumIsA;
-use std::{fmt, mem::discriminant, rc::Rc};
+use std::{cell::RefCell, fmt, mem::discriminant, rc::Rc};
 
 pub type NumberValueType = f64;
 
@@ -10,9 +14,18 @@ pub type NumberValueType = f64;
 pub enum Value {
     ValBool(bool),
     ValNull,
+    ValUndefined,
     ValNumber(f64),
-    ConstString(StrId),
-    DynString(Rc<str>),
+    String(JsString),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Array(MutRc<Array>),
+    Class(MutRc<Class>),
+    Instance(MutRc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    Object(MutRc<Object>),
+    Symbol(Rc<Symbol>),
+    Native(Rc<Native>),
 }
 
 impl Value {
@@ -20,33 +33,83 @@ impl Value {
         match self {
             Value::ValBool(value) => !*value,
             Value::ValNull => true,
-            _ => false,
+            Value::ValUndefined => true,
+            Value::ValNumber(value) => *value == 0.0 || value.is_nan(),
+            Value::String(value) => value.is_empty(),
+            Value::Function(_)
+            | Value::Closure(_)
+            | Value::Array(_)
+            | Value::Class(_)
+            | Value::Instance(_)
+            | Value::BoundMethod(_)
+            | Value::Object(_)
+            | Value::Symbol(_)
+            | Value::Native(_) => false,
         }
     }
+    pub fn is_nullish(&self) -> bool {
+        matches!(self, Value::ValNull | Value::ValUndefined)
+    }
+
     pub fn same_type_as(&self, other: &Value) -> bool {
         discriminant(self) == discriminant(other)
     }
 
     pub fn less(&self, other: Value) -> Option<Value> {
-        if self.same_type_as(&other) && self.is_val_number() {
-            Some(Value::ValBool(self.as_val_number() < other.as_val_number()))
-        } else {
-            None
+        match (self, &other) {
+            (Value::ValNumber(s), Value::ValNumber(o)) => Some(Value::ValBool(s < o)),
+            (Value::String(s), Value::String(o)) => Some(Value::ValBool(s < o)),
+            _ => None,
         }
     }
 
     pub fn greater(&self, other: Value) -> Option<Value> {
-        if self.same_type_as(&other) && self.is_val_number() {
-            Some(Value::ValBool(self.as_val_number() > other.as_val_number()))
-        } else {
-            None
+        match (self, &other) {
+            (Value::ValNumber(s), Value::ValNumber(o)) => Some(Value::ValBool(s > o)),
+            (Value::String(s), Value::String(o)) => Some(Value::ValBool(s > o)),
+            _ => None,
         }
     }
 
-    pub fn add(self, other: Value) -> Value {
-        match (&self, &other) {
-            (Value::ValNumber(s), Value::ValNumber(o)) => Value::ValNumber(s + o),
-            _ => Value::DynString(Rc::from((self.to_string() + &other.to_string()).as_str())),
+    /// Concatenate if either side is a string, or an object or array, which convert to one.
+    /// Otherwise add both sides as numbers, which symbols don't convert to
+    pub fn add(self, other: Value) -> Option<Value> {
+        match (self.to_number(), other.to_number()) {
+            (Some(s), Some(o)) => Some(Value::ValNumber(s + o)),
+            _ if matches!(self, Value::Symbol(_)) || matches!(other, Value::Symbol(_)) => None,
+            _ => Some(Value::String(
+                self.to_js_string().concat(&other.to_js_string()),
+            )),
+        }
+    }
+
+    // The number a primitive converts to, as `Number(value)` does, for all but strings
+    // and symbols
+    fn to_number(&self) -> Option<f64> {
+        match self {
+            Value::ValNumber(number) => Some(*number),
+            Value::ValBool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            Value::ValNull => Some(0.0),
+            Value::ValUndefined => Some(f64::NAN),
+            _ => None,
+        }
+    }
+
+    /// The string the value converts to, as `String(value)` does.
+    /// Strings are used as they are, so adding to a long one doesn't copy it
+    pub fn to_js_string(&self) -> JsString {
+        match self {
+            Value::String(string) => string.clone(),
+            value => JsString::from(value.to_string_within(&mut Vec::new())),
+        }
+    }
+
+    // `arrays` are the ones being converted already, which a cycle leads back to
+    fn to_string_within(&self, arrays: &mut Vec<*const RefCell<Array>>) -> String {
+        match self {
+            Value::Array(array) => join_within(array, ",", arrays),
+            Value::Object(_) | Value::Instance(_) => String::from("[object Object]"),
+            value => value.to_string(),
         }
     }
 
@@ -63,7 +126,7 @@ impl Value {
 
             (_, Value::ValNumber(o)) if *o > 0.0 => {
                 let string = self.to_string();
-                Some(Value::DynString(Rc::from(string.repeat(*o as usize))))
+                Some(Value::String(JsString::from(string.repeat(*o as usize))))
             }
 
             _ => None,
@@ -89,14 +152,52 @@ impl Value {
     }
 }
 
+/// The elements of `array` converted to strings and joined by `separator`, as `join` does.
+/// Holes, undefined and null become empty strings, and so does an array inside itself
+pub fn join(array: &MutRc<Array>, separator: &str) -> String {
+    join_within(array, separator, &mut Vec::new())
+}
+
+fn join_within(
+    array: &MutRc<Array>,
+    separator: &str,
+    arrays: &mut Vec<*const RefCell<Array>>,
+) -> String {
+    let pointer = Rc::as_ptr(array);
+    if arrays.contains(&pointer) {
+        return String::new();
+    }
+    arrays.push(pointer);
+    let strings: Vec<String> = array
+        .borrow()
+        .elements
+        .iter()
+        .map(|element| match element {
+            None | Some(Value::ValUndefined) | Some(Value::ValNull) => String::new(),
+            Some(element) => element.to_string_within(arrays),
+        })
+        .collect();
+    arrays.pop();
+    strings.join(separator)
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::ValBool(val) => write!(f, "{}", val),
             Value::ValNull => write!(f, "null"),
+            Value::ValUndefined => write!(f, "undefined"),
             Value::ValNumber(val) => write!(f, "{}", val),
-            Value::ConstString(val) => write!(f, "{}", to_str(*val)),
-            Value::DynString(val) => write!(f, "{}", val),
+            Value::String(val) => write!(f, "{}", val),
+            Value::Function(val) => write!(f, "{}", val),
+            Value::Closure(val) => write!(f, "{}", val),
+            Value::Array(val) => write!(f, "{}", val.borrow()),
+            Value::Class(val) => write!(f, "{}", val.borrow()),
+            Value::Instance(val) => write!(f, "{}", val.borrow()),
+            Value::BoundMethod(val) => write!(f, "{}", val),
+            Value::Object(val) => write!(f, "{}", val.borrow()),
+            Value::Symbol(val) => write!(f, "{}", val),
+            Value::Native(val) => write!(f, "{}", val),
         }
     }
 }
@@ -0,0 +1,374 @@
+//! Checks that a function's code can run without reading past its chunk or its stack.
+//!
+//! The VM trusts the code it runs: operands index straight into the constant pool, the
+//! locals and the upvalues, and every instruction assumes its operands are on the stack.
+//! The compiler only emits code that holds to that, code loaded from elsewhere has to be
+//! checked first. Every path through the code is followed, keeping track of how deep the
+//! stack is, and it must be equally deep whenever two paths meet.
+
+use super::common::OpCode;
+use super::object::Function;
+use super::value::Value;
+use std::fmt;
+
+/// Where a function's code breaks an assumption of the VM
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    // The function the code belongs to, as it prints
+    pub function: String,
+    pub offset: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ErrorKind {
+    InvalidInstruction,
+    // A jump to somewhere other than the start of an instruction
+    InvalidJump(usize),
+    StackUnderflow,
+    StackMismatch { expected: usize, found: usize },
+    InvalidConstant(usize),
+    NotAFunction(usize),
+    InvalidLocal(usize),
+    InvalidUpvalue(usize),
+    MissingReturn,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at offset {}: {}",
+            self.function, self.offset, self.kind
+        )
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::InvalidInstruction => write!(f, "invalid instruction"),
+            ErrorKind::InvalidJump(target) => write!(f, "jump to invalid offset {}", target),
+            ErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            ErrorKind::StackMismatch { expected, found } => write!(
+                f,
+                "stack depth is {} on one path and {} on another",
+                expected, found
+            ),
+            ErrorKind::InvalidConstant(index) => write!(f, "no constant at index {}", index),
+            ErrorKind::NotAFunction(index) => write!(f, "constant {} is not a function", index),
+            ErrorKind::InvalidLocal(slot) => write!(f, "no local in slot {}", slot),
+            ErrorKind::InvalidUpvalue(index) => write!(f, "no upvalue at index {}", index),
+            ErrorKind::MissingReturn => write!(f, "code runs past the end without returning"),
+        }
+    }
+}
+
+/// Check the code of `function` and of every function declared in it
+pub fn verify(function: &Function) -> Result<(), VerifyError> {
+    let chunk = &function.chunk;
+    let error = |offset, kind| VerifyError {
+        function: function.to_string(),
+        offset,
+        kind,
+    };
+
+    // Whether each offset starts an instruction, the end of the code counting as one
+    let mut starts = vec![false; chunk.code.len() + 1];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        starts[offset] = true;
+        offset = match chunk.try_read(offset) {
+            Some((_, next)) => next,
+            None => return Err(error(offset, ErrorKind::InvalidInstruction)),
+        };
+    }
+    starts[offset] = true;
+
+    // Stack depth before each instruction reached so far, counting the function's own slot
+    let mut depths: Vec<Option<usize>> = vec![None; chunk.code.len()];
+    // The arguments follow the function, with the rest parameter collected into one
+    let arguments = function.arity + function.has_rest as usize;
+    let mut pending = vec![(0, 1 + arguments)];
+    while let Some((offset, depth)) = pending.pop() {
+        if offset == chunk.code.len() {
+            return Err(error(offset, ErrorKind::MissingReturn));
+        }
+        match depths[offset] {
+            Some(expected) if expected == depth => continue,
+            Some(expected) => {
+                let kind = ErrorKind::StackMismatch {
+                    expected,
+                    found: depth,
+                };
+                return Err(error(offset, kind));
+            }
+            None => depths[offset] = Some(depth),
+        }
+        let (instruction, next) = chunk.read(offset);
+        check_operand(function, instruction, depth).map_err(|kind| error(offset, kind))?;
+
+        let (pops, pushes) = stack_effect(instruction);
+        // The function's own slot stays until it returns
+        if depth <= pops {
+            return Err(error(offset, ErrorKind::StackUnderflow));
+        }
+        let depth = depth - pops + pushes;
+
+        let target = match instruction {
+            OpCode::JUMP(jump)
+            | OpCode::JUMP_IF_FALSE(jump)
+            | OpCode::JUMP_IF_NOT_NULLISH(jump) => Some(next + jump),
+            OpCode::LOOP(jump) => Some(next.wrapping_sub(jump)),
+            _ => None,
+        };
+        if let Some(target) = target {
+            if !starts.get(target).copied().unwrap_or(false) {
+                return Err(error(offset, ErrorKind::InvalidJump(target)));
+            }
+            pending.push((target, depth));
+        }
+        match instruction {
+            OpCode::RETURN | OpCode::JUMP(_) | OpCode::LOOP(_) => {}
+            _ => pending.push((next, depth)),
+        }
+    }
+
+    for constant in chunk.constants.iter() {
+        if let Value::Function(function) = constant {
+            verify(function)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_operand(function: &Function, instruction: OpCode, depth: usize) -> Result<(), ErrorKind> {
+    let constants = &function.chunk.constants;
+    match instruction {
+        OpCode::CONSTANT(index) if index >= constants.len() => {
+            Err(ErrorKind::InvalidConstant(index))
+        }
+        OpCode::CLOSURE(index) => match constants.get(index) {
+            None => Err(ErrorKind::InvalidConstant(index)),
+            Some(Value::Function(closure)) => {
+                for capture in closure.captures.iter() {
+                    match capture.is_local {
+                        true if capture.index >= depth => {
+                            return Err(ErrorKind::InvalidLocal(capture.index))
+                        }
+                        false if capture.index >= function.captures.len() => {
+                            return Err(ErrorKind::InvalidUpvalue(capture.index))
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+            Some(_) => Err(ErrorKind::NotAFunction(index)),
+        },
+        OpCode::GET_LOCAL(slot) | OpCode::SET_LOCAL(slot) if slot >= depth => {
+            Err(ErrorKind::InvalidLocal(slot))
+        }
+        OpCode::GET_UPVALUE(index) | OpCode::SET_UPVALUE(index)
+            if index >= function.captures.len() =>
+        {
+            Err(ErrorKind::InvalidUpvalue(index))
+        }
+        _ => Ok(()),
+    }
+}
+
+// How many values an instruction takes off the stack, and how many it leaves in their place.
+// Calls count as done, the called function's result being what they leave
+fn stack_effect(instruction: OpCode) -> (usize, usize) {
+    match instruction {
+        OpCode::NULL
+        | OpCode::UNDEFINED
+        | OpCode::CONSTANT(_)
+        | OpCode::TRUE
+        | OpCode::FALSE
+        | OpCode::GET_GLOBAL(_)
+        | OpCode::GET_LOCAL(_)
+        | OpCode::GET_UPVALUE(_)
+        | OpCode::CLOSURE(_)
+        | OpCode::CLASS(_)
+        | OpCode::CALLEE
+        | OpCode::OBJECT => (0, 1),
+        OpCode::JUMP(_) | OpCode::LOOP(_) => (0, 0),
+        OpCode::POP
+        | OpCode::PRINT
+        | OpCode::DEFINE_GLOBAL(_)
+        | OpCode::DEFINE_CONST(_)
+        | OpCode::CLOSE_UPVALUE
+        | OpCode::RETURN => (1, 0),
+        OpCode::NEGATE
+        | OpCode::NOT
+        | OpCode::JUMP_IF_FALSE(_)
+        | OpCode::JUMP_IF_NOT_NULLISH(_)
+        | OpCode::SET_GLOBAL(_)
+        | OpCode::SET_LOCAL(_)
+        | OpCode::SET_UPVALUE(_)
+        | OpCode::ARRAY_HOLE
+        | OpCode::GET_PROPERTY(_) => (1, 1),
+        OpCode::ADD
+        | OpCode::SUBTRACT
+        | OpCode::MULTIPLY
+        | OpCode::DIVIDE
+        | OpCode::EQUAL
+        | OpCode::GREATER
+        | OpCode::LESS
+        | OpCode::CALL_SPREAD
+        | OpCode::NEW_SPREAD
+        | OpCode::ARRAY_PUSH
+        | OpCode::ARRAY_SPREAD
+        | OpCode::METHOD(_)
+        | OpCode::GETTER(_)
+        | OpCode::SETTER(_)
+        | OpCode::STATIC_METHOD(_)
+        | OpCode::STATIC_GETTER(_)
+        | OpCode::STATIC_SETTER(_)
+        | OpCode::CONSTRUCTOR
+        | OpCode::FIELDS
+        | OpCode::INIT_FIELDS
+        | OpCode::SET_PROPERTY(_)
+        | OpCode::GET_PRIVATE(_)
+        | OpCode::GET_INDEX
+        | OpCode::OBJECT_SPREAD
+        | OpCode::PROTOTYPE
+        | OpCode::GET_SUPER(_) => (2, 1),
+        OpCode::GET_METHOD(_) => (1, 2),
+        OpCode::STATIC_INIT | OpCode::INHERIT | OpCode::GET_INDEX_METHOD => (2, 2),
+        OpCode::SET_INDEX
+        | OpCode::INIT_PROPERTY
+        | OpCode::SUPER_CALL_SPREAD
+        | OpCode::INVOKE_SPREAD
+        | OpCode::SET_PRIVATE(_) => (3, 1),
+        OpCode::CALL(arg_count) | OpCode::NEW(arg_count) => (arg_count + 1, 1),
+        OpCode::SUPER_CALL(arg_count) | OpCode::INVOKE(arg_count) => (arg_count + 2, 1),
+        OpCode::ARRAY(count) => (count, 1),
+        OpCode::DUPLICATE(depth) => (depth + 1, depth + 2),
+        OpCode::POP_BELOW(count) => (count + 1, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::chunk::Position;
+    use crate::language::object::Capture;
+    use std::rc::Rc;
+
+    const POSITION: Position = Position {
+        line: 1,
+        column: 1,
+        start: 0,
+        length: 1,
+    };
+
+    fn function(code: &[OpCode]) -> Function {
+        let mut function = Function::new(None);
+        for instruction in code {
+            function.chunk.add_op_code(*instruction, POSITION);
+        }
+        function
+    }
+
+    fn error(function: &Function) -> (usize, ErrorKind) {
+        let error = verify(function).unwrap_err();
+        (error.offset, error.kind)
+    }
+
+    #[test]
+    fn accepts_balanced_code() {
+        let code = [
+            OpCode::TRUE,
+            OpCode::JUMP_IF_FALSE(2),
+            OpCode::NULL,
+            OpCode::PRINT,
+            OpCode::POP,
+            OpCode::NULL,
+            OpCode::RETURN,
+        ];
+        assert_eq!(verify(&function(&code)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_instructions() {
+        let mut function = function(&[OpCode::NULL, OpCode::RETURN]);
+        function.chunk.code.insert(1, 0xff);
+        assert_eq!(error(&function), (1, ErrorKind::InvalidInstruction));
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        let code = [OpCode::POP, OpCode::NULL, OpCode::RETURN];
+        assert_eq!(error(&function(&code)), (0, ErrorKind::StackUnderflow));
+    }
+
+    #[test]
+    fn rejects_paths_meeting_at_different_depths() {
+        // The jump skips the NULL, so the RETURN is reached with one value less
+        let code = [
+            OpCode::NULL,
+            OpCode::JUMP_IF_FALSE(1),
+            OpCode::NULL,
+            OpCode::RETURN,
+        ];
+        let mismatch = ErrorKind::StackMismatch {
+            expected: 3,
+            found: 2,
+        };
+        assert_eq!(error(&function(&code)), (5, mismatch));
+    }
+
+    #[test]
+    fn rejects_jumps_out_of_the_code() {
+        let code = [OpCode::JUMP(100), OpCode::NULL, OpCode::RETURN];
+        assert_eq!(error(&function(&code)), (0, ErrorKind::InvalidJump(103)));
+        let code = [OpCode::LOOP(100), OpCode::NULL, OpCode::RETURN];
+        assert!(matches!(
+            error(&function(&code)).1,
+            ErrorKind::InvalidJump(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_running_past_the_end() {
+        let code = [OpCode::NULL, OpCode::POP];
+        assert_eq!(error(&function(&code)), (2, ErrorKind::MissingReturn));
+    }
+
+    #[test]
+    fn rejects_operands_out_of_range() {
+        let code = [OpCode::CONSTANT(3), OpCode::RETURN];
+        assert_eq!(error(&function(&code)), (0, ErrorKind::InvalidConstant(3)));
+        let code = [OpCode::GET_LOCAL(1), OpCode::RETURN];
+        assert_eq!(error(&function(&code)), (0, ErrorKind::InvalidLocal(1)));
+        let code = [OpCode::GET_UPVALUE(0), OpCode::RETURN];
+        assert_eq!(error(&function(&code)), (0, ErrorKind::InvalidUpvalue(0)));
+
+        let mut number = function(&[OpCode::CLOSURE(0), OpCode::RETURN]);
+        number.chunk.add_constant(Value::ValNumber(1.0));
+        assert_eq!(error(&number), (0, ErrorKind::NotAFunction(0)));
+    }
+
+    #[test]
+    fn rejects_captures_of_locals_not_on_the_stack() {
+        let mut closure = function(&[OpCode::NULL, OpCode::RETURN]);
+        closure.captures.push(Capture {
+            is_local: true,
+            index: 1,
+        });
+        let mut script = function(&[OpCode::CLOSURE(0), OpCode::RETURN]);
+        script.chunk.add_constant(Value::Function(Rc::new(closure)));
+        assert_eq!(error(&script), (0, ErrorKind::InvalidLocal(1)));
+    }
+
+    #[test]
+    fn checks_nested_functions() {
+        let nested = function(&[OpCode::POP, OpCode::RETURN]);
+        let mut script = function(&[OpCode::CLOSURE(0), OpCode::RETURN]);
+        script.chunk.add_constant(Value::Function(Rc::new(nested)));
+        assert_eq!(error(&script), (0, ErrorKind::StackUnderflow));
+    }
+}
@@ -1,35 +1,233 @@
-use super::chunk::Chunk;
-use super::common::{intern, to_str, OpCode, StrId};
+use super::bytecode::{self, LoadError};
+use super::chunk::op;
+use super::common::MutRc;
+use super::common::{intern, intern_string, string, to_str, StrId};
+use super::compiler::VarKind;
 #[cfg(feature = "log_level_debug")]
 use super::debug::Debug;
+use super::diagnostic::{Diagnostic, ErrorCode, InterpretError, Span, TraceFrame};
+use super::gc::{as_trace, GcStats, Heap, Trace};
+use super::interner::{self, Interner, Marker};
+use super::native;
+use super::object::{
+    Array, BoundMethod, Class, Closure, Function, Instance, Member, Native, Object, PropertyKey,
+    Upvalue,
+};
 use super::value::Value;
 use crate::Compiler;
+use rustc_hash::FxHashMap;
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+    slice,
+    time::Duration,
+};
 
-pub enum Failure {
-    CompileError,
-    RuntimeError,
+const FRAMES_MAX: usize = 1024;
+// Different calls listed in a stack trace at most, innermost first
+const TRACE_MAX: usize = 64;
+
+type Res = Result<(), InterpretError>;
+
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    // Index of the stack slot holding the called function, locals follow it
+    slots: usize,
+    // Setters are called for their effect, an assignment evaluates to the assigned value
+    discard_result: bool,
+}
+
+struct Global {
+    value: Value,
+    // Declared with `const`, so it can't be assigned to
+    constant: bool,
+}
+
+impl Global {
+    fn new(value: Value) -> Global {
+        Global {
+            value,
+            constant: false,
+        }
+    }
 }
-type Res = Result<(), Failure>;
 
 pub struct VM {
-    ip: usize,
-    pub chunk: Option<Chunk>,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    globals: FxHashMap<StrId, Global>,
+    // The globals every script run so far defined, so later ones can't redeclare them,
+    // or assign to the constant ones
+    declarations: FxHashMap<StrId, VarKind>,
+    // Globals declared by compiled scripts, committed to `declarations` once defined
+    pending_declarations: FxHashMap<StrId, VarKind>,
+    // Upvalues still pointing into the stack, ordered by slot
+    open_upvalues: Vec<MutRc<Upvalue>>,
+    // Where arrays find their methods
+    array_prototype: MutRc<Object>,
+    // Scripts handed out by `compile` and `load`, whose strings must outlive a reclaim
+    // even before they run
+    scripts: Vec<Weak<Function>>,
+    // The brand of the class evaluated last, each evaluation takes the next one
+    last_brand: usize,
+    heap: Heap,
+    // Strings interned by this VM, shared with the compiler while it runs
+    strings: MutRc<Interner>,
+    // The runtime error being unwound, until `run` returns it
+    error: Option<InterpretError>,
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM {
-            chunk: Some(Chunk::new()),
-            ip: 0,
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
             stack: Vec::<Value>::new(),
+            globals: FxHashMap::default(),
+            declarations: FxHashMap::default(),
+            pending_declarations: FxHashMap::default(),
+            open_upvalues: Vec::new(),
+            array_prototype: Rc::new(RefCell::new(Object::default())),
+            scripts: Vec::new(),
+            last_brand: 0,
+            heap: Heap::new(),
+            strings: Rc::new(RefCell::new(Interner::new())),
+            error: None,
+        };
+        let _strings = interner::enter(&vm.strings);
+        vm.define_natives();
+        vm
+    }
+
+    fn define_natives(&mut self) {
+        self.globals.insert(
+            intern("Symbol"),
+            Global::new(native_value("Symbol", native::symbol)),
+        );
+
+        let mut prototype = self.array_prototype.borrow_mut();
+        for (name, function) in native::ARRAY_METHODS {
+            prototype
+                .properties
+                .set(intern(name).into(), native_value(name, function));
+        }
+        let mut array = Object::default();
+        array.properties.set(
+            intern("prototype").into(),
+            Value::Object(self.array_prototype.clone()),
+        );
+        array.properties.set(
+            intern("isArray").into(),
+            native_value("isArray", native::is_array),
+        );
+        self.globals.insert(
+            intern("Array"),
+            Global::new(Value::Object(Rc::new(RefCell::new(array)))),
+        );
+    }
+
+    /// Move a new object to the heap, where the collector can find it once it's unreachable.
+    /// Allocating is also what drives the collector forward
+    pub fn allocate<T: Trace + 'static>(&mut self, object: T) -> Rc<T> {
+        let object = Rc::new(object);
+        if self.heap.should_start() {
+            let roots = self.roots();
+            self.heap.start(roots);
+        }
+        if !self.heap.is_collecting() {
+            self.heap.track(&object);
+            return object;
+        }
+        // Tracing reads property names, which callers outside `execute` haven't entered
+        let _strings = interner::enter(&self.strings);
+        // Tracked before the step, as it may hold the only references to what it's made of
+        self.heap.track(&object);
+        let roots = if self.heap.needs_roots() {
+            Some(self.roots())
+        } else {
+            None
+        };
+        if let Some(_freed) = self.heap.step(roots) {
+            #[cfg(feature = "log_level_debug")]
+            println!("-- gc freed {} objects", _freed);
+        }
+        object
+    }
+
+    /// Let the collector know `object` is about to change, taking values out of it or
+    /// storing `values` in it
+    pub fn write_barrier<T: Trace + ?Sized>(&mut self, object: &Rc<T>, values: &[Value]) {
+        self.heap.write_barrier(object, values);
+    }
+
+    /// Free the objects only kept alive by reference cycles, returning how many there were
+    pub fn collect_garbage(&mut self) -> usize {
+        let _strings = interner::enter(&self.strings);
+        let roots = self.roots();
+        let freed = self.heap.collect(roots);
+        #[cfg(feature = "log_level_debug")]
+        println!("-- gc freed {} objects", freed);
+        freed
+    }
+
+    /// Longest the collector may hold up the program at a time, outside of `collect_garbage`
+    pub fn set_gc_pause_budget(&mut self, budget: Duration) {
+        self.heap.set_pause_budget(budget);
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap.stats()
+    }
+
+    fn roots(&self) -> Vec<Rc<dyn Trace>> {
+        let mut roots: Vec<Rc<dyn Trace>> = Vec::new();
+        roots.extend(self.stack.iter().filter_map(as_trace));
+        roots.extend(
+            self.globals
+                .values()
+                .filter_map(|global| as_trace(&global.value)),
+        );
+        for frame in self.frames.iter() {
+            roots.push(frame.closure.clone());
         }
+        for upvalue in self.open_upvalues.iter() {
+            roots.push(upvalue.clone());
+        }
+        roots.push(self.array_prototype.clone());
+        roots
     }
 
+    /// Compile and run `source`, not running any of it if it doesn't compile
     pub fn interpret(&mut self, source: &str) -> Res {
-        let mut compiler: Compiler = Compiler::new(source);
-        compiler.compile(self.instruction_chunk());
-        // let cls = compiler.compile().ok_or(Failure::CompileError)?;
+        let function = self.compile(source)?;
+        self.execute(function)
+    }
+
+    /// Like `interpret`, with errors pointing into the file at `path`
+    pub fn interpret_file(&mut self, path: &str, source: &str) -> Res {
+        self.interpret(source).map_err(|mut error| {
+            error.set_file(path);
+            error
+        })
+    }
+
+    /// Compile `source` without running it
+    pub fn compile(&mut self, source: &str) -> Result<Rc<Function>, InterpretError> {
+        let _strings = interner::enter(&self.strings);
+        let mut compiler: Compiler = Compiler::new(source, self.declarations.clone());
+        let function = compiler.compile().map_err(InterpretError::compile)?;
+        for (name, kind) in compiler.into_globals() {
+            if !self.declarations.contains_key(&name) {
+                self.pending_declarations.insert(name, kind);
+            }
+        }
+        self.hand_out(&function);
+        Ok(function)
+    }
+
+    /// Run a script compiled by this VM, or loaded by it
+    pub fn execute(&mut self, function: Rc<Function>) -> Res {
+        let _strings = interner::enter(&self.strings);
 
         #[cfg(feature = "log_level_debug")]
         {
@@ -38,141 +236,1648 @@ impl VM {
                 print!("[ {:04} ]", value);
             }
             println!("");
-            self.instruction_chunk()
-                .disassemble(&Some(intern("vm_code")));
+            function.chunk.disassemble(&Some(intern("vm_code")));
             // disassemble_chunk(&self.instruction_chunk(), &Some(intern("to be changed")));
             // disassemble_instruction(&self.instruction_chunk(), &current_instruction, 0);
         }
 
-        // self.define_natives();
-        // let cls = cls.to_obj();
-        // self.stack.push(Value::Closure(cls.clone()));
+        let closure = Rc::new(Closure {
+            function,
+            upvalues: Vec::new(),
+        });
+        self.push(Value::Closure(closure.clone()));
+        self.call(closure, 0);
 
-        self.run()
+        println!();
+        let result = self.run(0);
+        if result.is_err() {
+            self.reset_stack();
+        }
+        if self.strings.borrow().should_reclaim() {
+            self.reclaim_strings();
+        }
+        result
     }
 
-    fn instruction_chunk(&mut self) -> &mut Chunk {
-        self.chunk.as_mut().unwrap()
+    /// The contents of a `.tsbc` file holding a script compiled by this VM
+    pub fn serialize(&self, function: &Function) -> Vec<u8> {
+        let _strings = interner::enter(&self.strings);
+        bytecode::serialize(function)
     }
 
-    fn run(&mut self) -> Res {
-        println!();
-        if self.instruction_chunk().code.is_empty() {
-            return Ok(());
+    /// Load the script saved in a `.tsbc` file, to be run with `execute`
+    pub fn load(&mut self, bytes: &[u8]) -> Result<Rc<Function>, LoadError> {
+        let _strings = interner::enter(&self.strings);
+        let function = bytecode::load(bytes)?;
+        self.hand_out(&function);
+        Ok(function)
+    }
+
+    fn hand_out(&mut self, function: &Rc<Function>) {
+        self.scripts.retain(|script| script.strong_count() > 0);
+        self.scripts.push(Rc::downgrade(function));
+    }
+
+    /// Free the interned strings no live code or value refers to anymore,
+    /// returning how many there were
+    pub fn reclaim_strings(&mut self) -> usize {
+        let _strings = interner::enter(&self.strings);
+        let mut marker = Marker::default();
+        for value in self.stack.iter() {
+            marker.mark_value(value);
+        }
+        for (name, global) in self.globals.iter() {
+            marker.mark(*name);
+            marker.mark_value(&global.value);
         }
+        for name in self
+            .declarations
+            .keys()
+            .chain(self.pending_declarations.keys())
+        {
+            marker.mark(*name);
+        }
+        for frame in self.frames.iter() {
+            marker.mark_value(&Value::Closure(frame.closure.clone()));
+        }
+        for upvalue in self.open_upvalues.iter() {
+            marker.mark_upvalue(upvalue);
+        }
+        marker.mark_value(&Value::Object(self.array_prototype.clone()));
+        self.scripts.retain(|script| match script.upgrade() {
+            Some(function) => {
+                marker.mark_value(&Value::Function(function));
+                true
+            }
+            None => false,
+        });
+        let live = marker.finish();
+        let freed = self.strings.borrow_mut().reclaim(&live);
+        #[cfg(feature = "log_level_debug")]
+        println!("-- reclaimed {} strings", freed);
+        freed
+    }
 
-        loop {
-            let ip: usize = self.ip;
-            let current_instruction = self.instruction_chunk().code[ip];
-            self.ip += 1;
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    // Read from the running code, moving the frame past what was read
 
-            match current_instruction {
-                OpCode::NULL => self.stack.push(Value::ValNull),
-                OpCode::BOOL(val) => self.stack.push(Value::ValBool(val)),
-                OpCode::NUMBER(val) => self.stack.push(Value::ValNumber(val)),
-                OpCode::STRING(str) => self.stack.push(Value::ConstString(str)),
+    #[inline(always)]
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().unwrap();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    #[inline(always)]
+    fn read_operand(&mut self) -> usize {
+        let frame = self.frames.last_mut().unwrap();
+        let chunk = &frame.closure.function.chunk;
+        chunk.read_varint(&mut frame.ip).expect("invalid bytecode")
+    }
+
+    #[inline(always)]
+    fn read_jump(&mut self) -> usize {
+        let frame = self.frames.last_mut().unwrap();
+        let chunk = &frame.closure.function.chunk;
+        chunk.read_u16(&mut frame.ip).expect("invalid bytecode")
+    }
 
-                OpCode::POP => {
+    fn read_name(&mut self) -> StrId {
+        let frame = self.frames.last_mut().unwrap();
+        let chunk = &frame.closure.function.chunk;
+        chunk.read_name(&mut frame.ip).expect("invalid bytecode")
+    }
+
+    #[inline(always)]
+    fn read_constant(&mut self, long: bool) -> Value {
+        let frame = self.frames.last_mut().unwrap();
+        let chunk = &frame.closure.function.chunk;
+        let constant = chunk.read_constant(&mut frame.ip, long);
+        chunk.constants[constant.expect("invalid bytecode")].clone()
+    }
+
+    /// The stack slot of the local the operand is the index of
+    #[inline(always)]
+    fn read_slot(&mut self) -> usize {
+        let frame = self.frames.last_mut().unwrap();
+        let chunk = &frame.closure.function.chunk;
+        frame.slots + chunk.read_varint(&mut frame.ip).expect("invalid bytecode")
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    /// Run until the frame count drops back to `depth`, leaving the last result on the stack
+    fn run(&mut self, depth: usize) -> Res {
+        loop {
+            // Operands are read as they're needed, straight from the code
+            let instruction = self.read_byte();
+            match instruction {
+                op::NULL => self.stack.push(Value::ValNull),
+                op::UNDEFINED => self.stack.push(Value::ValUndefined),
+                op::CONSTANT | op::CONSTANT_LONG => {
+                    let value = self.read_constant(instruction == op::CONSTANT_LONG);
+                    self.stack.push(value);
+                }
+
+                op::POP => {
                     self.pop();
                 }
+                op::DUPLICATE => {
+                    let depth = self.read_operand();
+                    self.push(self.peek(depth).clone());
+                }
+                op::POP_BELOW => {
+                    let count = self.read_operand();
+                    let top = self.pop();
+                    self.stack.truncate(self.stack.len() - count);
+                    self.push(top);
+                }
 
-                OpCode::TRUE => self.stack.push(Value::ValBool(true)),
-                OpCode::FALSE => self.stack.push(Value::ValBool(false)),
-                OpCode::ADD
-                | OpCode::SUBTRACT
-                | OpCode::MULTIPLY
-                | OpCode::DIVIDE
-                | OpCode::EQUAL
-                | OpCode::GREATER
-                | OpCode::LESS => {
-                    let result = self.binary_operation_values(&current_instruction);
+                op::TRUE => self.stack.push(Value::ValBool(true)),
+                op::FALSE => self.stack.push(Value::ValBool(false)),
+                op::ADD
+                | op::SUBTRACT
+                | op::MULTIPLY
+                | op::DIVIDE
+                | op::EQUAL
+                | op::GREATER
+                | op::LESS => {
+                    let result = self.binary_operation_values(instruction);
                     if let Some(result) = result {
                         self.stack.push(result)
                     } else {
                         // let line = &self.instruction_chunk().code[(frame.ip) as usize].line;
                         // let line = &self.instruction_chunk().lines.pop().unwrap();
-                        self.print_error("Binary operation had invalid operands!");
+                        self.runtime_error("Binary operation had invalid operands!");
                         break;
                     }
                 }
-                OpCode::NEGATE | OpCode::NOT => {
-                    let result = self.unary_instruction(&current_instruction);
+                op::NEGATE | op::NOT => {
+                    let result = self.unary_instruction(instruction);
                     if let Some(result) = result {
                         self.stack.push(result)
                     } else {
                         // let line = current_func.chunk.code[(frame.ip) as usize].line;
                         // let line = &self.instruction_chunk().lines.pop().unwrap();
-                        self.print_error("Unary operation had an invalid operand!");
+                        self.runtime_error("Unary operation had an invalid operand!");
                         break;
                     }
                 }
-                OpCode::PRINT => println!("{}", self.pop()),
-                OpCode::RETURN => {
-                    if self.stack.len() > 0 {
-                        println!("[vm][OpCode::RETURN] {}", self.pop());
-                    } else {
-                        println!("[vm][OpCode::RETURN] end prog");
+                op::PRINT => println!("{}", self.pop()),
+                op::JUMP => {
+                    let offset = self.read_jump();
+                    self.frame_mut().ip += offset;
+                }
+                op::JUMP_IF_NOT_NULLISH => {
+                    let offset = self.read_jump();
+                    if !self.peek(0).is_nullish() {
+                        self.frame_mut().ip += offset;
                     }
-                    return Ok(());
                 }
+                op::LOOP => {
+                    let offset = self.read_jump();
+                    self.frame_mut().ip -= offset;
+                }
+                op::JUMP_IF_FALSE => {
+                    let offset = self.read_jump();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                op::CLOSURE => {
+                    let constant = self.read_operand();
+                    let function = match &self.frame().closure.function.chunk.constants[constant] {
+                        Value::Function(function) => function.clone(),
+                        _ => {
+                            self.runtime_error("Closure constant is not a function");
+                            break;
+                        }
+                    };
+                    let upvalues = function
+                        .captures
+                        .iter()
+                        .map(|capture| {
+                            if capture.is_local {
+                                self.capture_upvalue(self.frame().slots + capture.index)
+                            } else {
+                                self.frame().closure.upvalues[capture.index].clone()
+                            }
+                        })
+                        .collect();
+                    let closure = self.allocate(Closure { function, upvalues });
+                    self.push(Value::Closure(closure));
+                }
+                op::GET_UPVALUE => {
+                    let index = self.read_operand();
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.push(value);
+                }
+                op::SET_UPVALUE => {
+                    let index = self.read_operand();
+                    let value = self.peek(0).clone();
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    self.heap.write_barrier(&upvalue, slice::from_ref(&value));
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                op::CALLEE => self.push(Value::Closure(self.frame().closure.clone())),
+                op::CLOSE_UPVALUE => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                op::CALL => {
+                    let arg_count = self.read_operand();
+                    if let Err(message) = self.call_value(self.peek(arg_count).clone(), arg_count) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::CALL_SPREAD => {
+                    let arguments = match self.pop() {
+                        Value::Array(array) => array.borrow().values(),
+                        _ => {
+                            self.runtime_error("Spread arguments are not an array");
+                            break;
+                        }
+                    };
+                    let arg_count = arguments.len();
+                    self.stack.extend(arguments);
+                    if let Err(message) = self.call_value(self.peek(arg_count).clone(), arg_count) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::INVOKE => {
+                    let arg_count = self.read_operand();
+                    if let Err(message) = self.invoke(arg_count) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::INVOKE_SPREAD => {
+                    let arguments = match self.pop() {
+                        Value::Array(array) => array.borrow().values(),
+                        _ => {
+                            self.runtime_error("Spread arguments are not an array");
+                            break;
+                        }
+                    };
+                    let arg_count = arguments.len();
+                    self.stack.extend(arguments);
+                    if let Err(message) = self.invoke(arg_count) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::ARRAY => {
+                    let count = self.read_operand();
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    let array = self.allocate(RefCell::new(Array::new(elements)));
+                    self.push(Value::Array(array));
+                }
+                op::ARRAY_PUSH => {
+                    let value = self.pop();
+                    if let Value::Array(array) = self.peek(0).clone() {
+                        self.heap.write_barrier(&array, slice::from_ref(&value));
+                        array.borrow_mut().elements.push(Some(value));
+                    }
+                }
+                op::ARRAY_HOLE => {
+                    if let Value::Array(array) = self.peek(0) {
+                        array.borrow_mut().elements.push(None);
+                    }
+                }
+                op::ARRAY_SPREAD => {
+                    let iterable = self.pop();
+                    let values: Vec<Value> = match &iterable {
+                        Value::Array(array) => array.borrow().values(),
+                        Value::String(string) => string
+                            .chars()
+                            .map(|char| Value::String(char.to_string().into()))
+                            .collect(),
+                        _ => {
+                            self.runtime_error(&format!("TypeError: {} is not iterable", iterable));
+                            break;
+                        }
+                    };
+                    if let Value::Array(array) = self.peek(0).clone() {
+                        self.heap.write_barrier(&array, &values);
+                        array
+                            .borrow_mut()
+                            .elements
+                            .extend(values.into_iter().map(Some));
+                    }
+                }
+                op::CLASS => {
+                    let name = self.read_name();
+                    self.last_brand += 1;
+                    let class = Class::new(name, self.last_brand);
+                    let class = self.allocate(RefCell::new(class));
+                    self.push(Value::Class(class));
+                }
+                op::METHOD
+                | op::GETTER
+                | op::SETTER
+                | op::STATIC_METHOD
+                | op::STATIC_GETTER
+                | op::STATIC_SETTER
+                | op::CONSTRUCTOR
+                | op::FIELDS => {
+                    let name = match instruction {
+                        op::CONSTRUCTOR | op::FIELDS => None,
+                        _ => Some(self.read_name()),
+                    };
+                    let method = match self.pop() {
+                        Value::Closure(closure) => closure,
+                        _ => {
+                            self.runtime_error("Class member is not a closure");
+                            break;
+                        }
+                    };
+                    if let Value::Class(class) = self.peek(0).clone() {
+                        self.heap
+                            .write_barrier(&class, &[Value::Closure(method.clone())]);
+                        let mut class = class.borrow_mut();
+                        match (instruction, name.map(|name| class.member_key(name))) {
+                            (op::METHOD, Some(name)) => class.add_method(name, method, false),
+                            (op::GETTER, Some(name)) => {
+                                class.add_accessor(name, method, false, false)
+                            }
+                            (op::SETTER, Some(name)) => {
+                                class.add_accessor(name, method, true, false)
+                            }
+                            (op::STATIC_METHOD, Some(name)) => class.add_method(name, method, true),
+                            (op::STATIC_GETTER, Some(name)) => {
+                                class.add_accessor(name, method, false, true)
+                            }
+                            (op::STATIC_SETTER, Some(name)) => {
+                                class.add_accessor(name, method, true, true)
+                            }
+                            (op::CONSTRUCTOR, _) => class.constructor = Some(method),
+                            _ => class.initializer = Some(method),
+                        }
+                    }
+                }
+                op::STATIC_INIT => {
+                    let initializer = match self.pop() {
+                        Value::Closure(closure) => closure,
+                        _ => {
+                            self.runtime_error("Static initializer is not a closure");
+                            break;
+                        }
+                    };
+                    self.push(self.peek(0).clone());
+                    if let Err(message) = self.call_closure(initializer, 0) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::INIT_FIELDS => {
+                    let class = match self.stack.remove(self.stack.len() - 2) {
+                        Value::Class(class) => class,
+                        _ => {
+                            self.runtime_error("Fields initialized without a class");
+                            break;
+                        }
+                    };
+                    let initializer = class.borrow().initializer.clone();
+                    if let Some(initializer) = initializer {
+                        self.call(initializer, 0);
+                    }
+                }
+                op::GET_PROPERTY => {
+                    let name = self.read_name();
+                    let object = self.pop();
+                    if let Err(message) = self.get_property(object, name.into()) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::GET_METHOD => {
+                    let name = self.read_name();
+                    let object = self.peek(0).clone();
+                    if let Err(message) = self.get_property(object, name.into()) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::SET_PROPERTY => {
+                    let name = self.read_name();
+                    let value = self.pop();
+                    let object = self.pop();
+                    if let Err(message) = self.set_property(object, name.into(), value) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::GET_PRIVATE => {
+                    let name = self.read_name();
+                    let class = self.pop();
+                    let object = self.pop();
+                    let result = match private_key(&object, &class, name) {
+                        Some(key) => self.get_property(object, key.into()),
+                        None => Err(format!(
+                            "TypeError: Cannot read private member {} from an object whose class did not declare it",
+                            to_str(name)
+                        )),
+                    };
+                    if let Err(message) = result {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::SET_PRIVATE => {
+                    let name = self.read_name();
+                    let class = self.pop();
+                    let value = self.pop();
+                    let object = self.pop();
+                    let result = match private_key(&object, &class, name) {
+                        Some(key) => self.set_property(object, key.into(), value),
+                        None => Err(format!(
+                            "TypeError: Cannot write private member {} to an object whose class did not declare it",
+                            to_str(name)
+                        )),
+                    };
+                    if let Err(message) = result {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::GET_INDEX => {
+                    let key = to_property_key(&self.pop());
+                    let object = self.pop();
+                    if let Err(message) = self.get_property(object, key) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::GET_INDEX_METHOD => {
+                    let key = to_property_key(&self.pop());
+                    let object = self.peek(0).clone();
+                    if let Err(message) = self.get_property(object, key) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::SET_INDEX => {
+                    let value = self.pop();
+                    let key = to_property_key(&self.pop());
+                    let object = self.pop();
+                    if let Err(message) = self.set_property(object, key, value) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::OBJECT => {
+                    let object = self.allocate(RefCell::new(Object::default()));
+                    self.push(Value::Object(object));
+                }
+                op::INIT_PROPERTY => {
+                    let value = self.pop();
+                    let key = to_property_key(&self.pop());
+                    match self.peek(0).clone() {
+                        Value::Object(object) => {
+                            self.heap.write_barrier(&object, slice::from_ref(&value));
+                            object.borrow_mut().properties.set(key, value)
+                        }
+                        _ => {
+                            self.runtime_error("Property defined outside an object literal");
+                            break;
+                        }
+                    }
+                }
+                op::OBJECT_SPREAD => {
+                    let source = self.pop();
+                    let object = match self.peek(0) {
+                        Value::Object(object) => object.clone(),
+                        _ => {
+                            self.runtime_error("Spread outside an object literal");
+                            break;
+                        }
+                    };
+                    // What it copies is kept along with the source
+                    self.heap.write_barrier(&object, slice::from_ref(&source));
+                    let mut object = object.borrow_mut();
+                    for (key, value) in own_properties(&source) {
+                        object.properties.set(key, value);
+                    }
+                }
+                op::PROTOTYPE => {
+                    let prototype = self.pop();
+                    let object = match self.peek(0) {
+                        Value::Object(object) => object.clone(),
+                        _ => {
+                            self.runtime_error("Prototype set outside an object literal");
+                            break;
+                        }
+                    };
+                    self.heap
+                        .write_barrier(&object, slice::from_ref(&prototype));
+                    // Anything other than an object or null is ignored, as in JavaScript
+                    match prototype {
+                        Value::Object(prototype) => object.borrow_mut().prototype = Some(prototype),
+                        Value::ValNull => object.borrow_mut().prototype = None,
+                        _ => (),
+                    }
+                }
+                op::NEW => {
+                    let arg_count = self.read_operand();
+                    if let Err(message) = self.construct(arg_count) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::NEW_SPREAD => {
+                    let arguments = match self.pop() {
+                        Value::Array(array) => array.borrow().values(),
+                        _ => {
+                            self.runtime_error("Spread arguments are not an array");
+                            break;
+                        }
+                    };
+                    let arg_count = arguments.len();
+                    self.stack.extend(arguments);
+                    if let Err(message) = self.construct(arg_count) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::INHERIT => {
+                    let class = match self.peek(0) {
+                        Value::Class(class) => class.clone(),
+                        _ => {
+                            self.runtime_error("Inheriting into a non-class");
+                            break;
+                        }
+                    };
+                    match self.peek(1).clone() {
+                        Value::Class(superclass) => {
+                            self.heap
+                                .write_barrier(&class, &[Value::Class(superclass.clone())]);
+                            class.borrow_mut().superclass = Some(superclass)
+                        }
+                        // Nothing to inherit, though constructing one still calls `super`
+                        Value::ValNull => (),
+                        superclass => {
+                            self.runtime_error(&format!(
+                                "TypeError: Class extends value {} is not a constructor or null",
+                                superclass
+                            ));
+                            break;
+                        }
+                    }
+                }
+                op::GET_SUPER => {
+                    let name = self.read_name();
+                    let superclass = self.pop();
+                    let receiver = self.pop();
+                    // In a static method `this` is the class, so `super` means its static side
+                    let is_static = matches!(receiver, Value::Class(_));
+                    let member = match superclass {
+                        Value::Class(superclass) => {
+                            superclass.borrow().find_member(name, is_static)
+                        }
+                        // `extends null` leaves nothing to look members up in
+                        Value::ValNull => None,
+                        _ => {
+                            self.runtime_error("Superclass is not a class");
+                            break;
+                        }
+                    };
+                    if let Err(message) = self.push_member(receiver, member) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::SUPER_CALL => {
+                    let arg_count = self.read_operand();
+                    if let Err(message) = self.super_call(arg_count) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::SUPER_CALL_SPREAD => {
+                    let arguments = match self.pop() {
+                        Value::Array(array) => array.borrow().values(),
+                        _ => {
+                            self.runtime_error("Spread arguments are not an array");
+                            break;
+                        }
+                    };
+                    let arg_count = arguments.len();
+                    self.stack.extend(arguments);
+                    if let Err(message) = self.super_call(arg_count) {
+                        self.runtime_error(&message);
+                        break;
+                    }
+                }
+                op::DEFINE_GLOBAL | op::DEFINE_CONST => {
+                    let name = self.read_name();
+                    let value = self.pop();
+                    let constant = instruction == op::DEFINE_CONST;
+                    self.globals.insert(name, Global { value, constant });
+                    // Loaded scripts declare nothing up front, their globals are taken
+                    // as they're defined
+                    let kind = self
+                        .pending_declarations
+                        .remove(&name)
+                        .unwrap_or(if constant {
+                            VarKind::Const
+                        } else {
+                            VarKind::Var
+                        });
+                    self.declarations.entry(name).or_insert(kind);
+                }
+                op::GET_GLOBAL => {
+                    let name = self.read_name();
+                    match self.globals.get(&name) {
+                        Some(global) => {
+                            let value = global.value.clone();
+                            self.push(value);
+                        }
+                        None => {
+                            self.runtime_error(&format!(
+                                "ReferenceError: {} is not defined",
+                                to_str(name)
+                            ));
+                            break;
+                        }
+                    };
+                }
+                op::GET_LOCAL => {
+                    let slot = self.read_slot();
+                    let value = self.stack[slot].clone();
+                    self.push(value);
+                }
+                op::SET_LOCAL => {
+                    let slot = self.read_slot();
+                    self.stack[slot] = self.peek(0).clone();
+                }
+                op::SET_GLOBAL => {
+                    let name = self.read_name();
+                    // Assignment is an expression, so the value stays on the stack
+                    let value = self.peek(0).clone();
+                    match self.globals.get_mut(&name) {
+                        Some(global) if global.constant => {
+                            self.runtime_error("TypeError: Assignment to constant variable.");
+                            break;
+                        }
+                        Some(global) => global.value = value,
+                        None => {
+                            self.runtime_error(&format!(
+                                "ReferenceError: {} is not defined",
+                                to_str(name)
+                            ));
+                            break;
+                        }
+                    }
+                }
+                op::RETURN => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    // Discard the called function along with its arguments and locals
+                    self.stack.truncate(frame.slots);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    if !frame.discard_result {
+                        self.push(result);
+                    }
+                    if self.frames.len() == depth {
+                        return Ok(());
+                    }
+                }
+                // Code is verified before it runs
+                _ => unreachable!("invalid bytecode"),
             }
         }
         // All terminations of this loop are to be interpreted as an error,
         // return will return directly and prevent hitting this
-        Err(Failure::RuntimeError)
+        Err(self.error.take().expect("runtime error was not reported"))
     }
 
-    fn print_error(&mut self, message: &str) {
-        let mut lines = self.chunk.as_ref().unwrap().lines.clone();
-        let line = lines.pop().unwrap();
-        println!("[Line {}] Runtime error: {}", line, message);
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), String> {
+        match callee {
+            Value::Closure(closure) => {
+                // Called on its own, a function's `this` is undefined
+                if closure.function.binds_this {
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = Value::ValUndefined;
+                }
+                self.call_closure(closure, arg_count)
+            }
+            Value::BoundMethod(bound) => {
+                // The receiver takes the callee's slot, where the method expects `this`
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = bound.receiver.clone();
+                match &bound.method {
+                    Value::Closure(method) => self.call_closure(method.clone(), arg_count),
+                    Value::Native(native) => {
+                        self.call_native(native, bound.receiver.clone(), arg_count)
+                    }
+                    _ => unreachable!("Bound method is not a function"),
+                }
+            }
+            Value::Native(native) => self.call_native(&native, Value::ValUndefined, arg_count),
+            Value::Class(class) => Err(format!(
+                "TypeError: Class constructor {} cannot be invoked without 'new'",
+                to_str(class.borrow().name)
+            )),
+            _ => Err(format!("TypeError: {} is not a function", callee)),
+        }
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    /// Call the function below the arguments with the receiver below it as `this`
+    fn invoke(&mut self, arg_count: usize) -> Result<(), String> {
+        // The receiver stays in the callee's slot, where the method expects `this`
+        let slot = self.stack.len() - arg_count - 1;
+        let callee = self.stack.remove(slot);
+        match callee {
+            Value::Closure(closure) if closure.function.binds_this => {
+                self.call_closure(closure, arg_count)
+            }
+            Value::Native(native) => {
+                let receiver = self.stack[slot - 1].clone();
+                self.call_native(&native, receiver, arg_count)
+            }
+            callee => {
+                self.stack[slot - 1] = callee.clone();
+                self.call_value(callee, arg_count)
+            }
+        }
     }
 
-    fn pop(&mut self) -> Value {
-        match self.stack.pop() {
-            Some(value) => return value,
+    /// Call a function from Rust and run it to completion, for natives taking callbacks
+    pub fn call_function(&mut self, callee: &Value, args: &[Value]) -> Result<Value, String> {
+        let _strings = interner::enter(&self.strings);
+        let depth = self.frames.len();
+        self.push(callee.clone());
+        self.stack.extend(args.iter().cloned());
+        self.call_value(callee.clone(), args.len())?;
+        if self.frames.len() > depth {
+            if let Err(error) = self.run(depth) {
+                // Already reported where it happened, keep it for the caller's frame to return
+                self.error = Some(error);
+                return Err(String::new());
+            }
+        }
+        let result = self.pop();
+        self.heap.hold(&result);
+        Ok(result)
+    }
+
+    fn call_native(
+        &mut self,
+        native: &Native,
+        this: Value,
+        arg_count: usize,
+    ) -> Result<(), String> {
+        // Left on the stack for the collector to find while the native runs
+        let callee = self.stack.len() - arg_count - 1;
+        let args = self.stack[callee + 1..].to_vec();
+        let result = (native.function)(self, &this, &args);
+        self.stack.truncate(callee);
+        self.push(result?);
+        Ok(())
+    }
+
+    fn call_closure(&mut self, closure: Rc<Closure>, arg_count: usize) -> Result<(), String> {
+        if self.frames.len() == FRAMES_MAX {
+            return Err(String::from("RangeError: Maximum call stack size exceeded"));
+        }
+        let arg_count = self.adjust_arguments(&closure, arg_count);
+        self.call(closure, arg_count);
+        Ok(())
+    }
+
+    /// Replace the class below the arguments with a new instance and run its constructor
+    fn construct(&mut self, arg_count: usize) -> Result<(), String> {
+        let slot = self.stack.len() - arg_count - 1;
+        let class = match &self.stack[slot] {
+            Value::Class(class) => class.clone(),
+            callee => return Err(format!("TypeError: {} is not a constructor", callee)),
+        };
+        let instance = Instance::new(class.clone());
+        self.stack[slot] = Value::Instance(self.allocate(RefCell::new(instance)));
+        self.run_constructor(class, arg_count)
+    }
+
+    /// Run the superclass constructor on the `this` below the arguments,
+    /// with the superclass itself just below that
+    fn super_call(&mut self, arg_count: usize) -> Result<(), String> {
+        let superclass = match self.stack.remove(self.stack.len() - arg_count - 2) {
+            Value::Class(superclass) => superclass,
+            superclass => {
+                return Err(format!(
+                    "TypeError: Super constructor {} is not a constructor",
+                    superclass
+                ))
+            }
+        };
+        self.run_constructor(superclass, arg_count)
+    }
+
+    fn run_constructor(&mut self, class: MutRc<Class>, arg_count: usize) -> Result<(), String> {
+        let slot = self.stack.len() - arg_count - 1;
+        let class = class.borrow();
+        match (&class.constructor, &class.initializer) {
+            (Some(constructor), _) => self.call_closure(constructor.clone(), arg_count),
+            (None, Some(initializer)) => {
+                self.stack.truncate(slot + 1);
+                self.call_closure(initializer.clone(), 0)
+            }
+            (None, None) => {
+                self.stack.truncate(slot + 1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Push the value of a property, or start a call to its getter
+    fn get_property(&mut self, object: Value, key: PropertyKey) -> Result<(), String> {
+        // Methods and accessors are only ever named by strings
+        let name = match &key {
+            PropertyKey::String(name) => Some(*name),
+            PropertyKey::Symbol(_) => None,
+        };
+        let member = match &object {
+            Value::Array(array) => {
+                let value = match key.as_index() {
+                    Some(index) => array.borrow().get(index as usize),
+                    None if name.is_some_and(|name| to_str(name) == "length") => {
+                        Value::ValNumber(array.borrow().elements.len() as f64)
+                    }
+                    None => {
+                        let value = self.array_prototype.borrow().get(&key);
+                        value.unwrap_or(Value::ValUndefined)
+                    }
+                };
+                self.push(value);
+                return Ok(());
+            }
+            Value::Object(object_ref) => {
+                let value = object_ref.borrow().get(&key);
+                self.push(value.unwrap_or(Value::ValUndefined));
+                return Ok(());
+            }
+            Value::Instance(instance) => {
+                let instance = instance.borrow();
+                if let Some(value) = instance.fields.get(&key) {
+                    let value = value.clone();
+                    self.push(value);
+                    return Ok(());
+                }
+                let member = name.and_then(|name| instance.class.borrow().find_member(name, false));
+                member
+            }
+            Value::Class(class) => {
+                let class = class.borrow();
+                if let Some(value) = class.find_field(&key) {
+                    self.push(value);
+                    return Ok(());
+                }
+                match name.and_then(|name| class.find_member(name, true)) {
+                    None if name.is_some_and(|name| to_str(name) == "name") => {
+                        self.push(Value::String(string(class.name)));
+                        return Ok(());
+                    }
+                    member => member,
+                }
+            }
+            Value::ValNull | Value::ValUndefined => {
+                return Err(format!(
+                    "TypeError: Cannot read properties of {} (reading '{}')",
+                    object,
+                    key_name(&key)
+                ))
+            }
+            _ => None,
+        };
+        self.push_member(object, member)
+    }
+
+    /// Push a method bound to `receiver`, or start a call to the getter of an accessor
+    fn push_member(&mut self, receiver: Value, member: Option<Member>) -> Result<(), String> {
+        match member {
+            Some(Member::Method(method)) => {
+                let bound = self.allocate(BoundMethod {
+                    receiver,
+                    method: Value::Closure(method),
+                });
+                self.push(Value::BoundMethod(bound));
+                Ok(())
+            }
+            Some(Member::Accessor {
+                getter: Some(getter),
+                ..
+            }) => {
+                self.push(receiver);
+                self.call_closure(getter, 0)
+            }
             _ => {
-                println!("VM tried to get value from empty stack");
-                Value::ConstString(intern("unknown"))
+                self.push(Value::ValUndefined);
+                Ok(())
             }
         }
     }
 
-    // fn peek(&mut self, distance: i32) -> Value {
-    // return self.stackTop[-1 - distance];
-    // }
+    /// Assign a property, or start a call to its setter, leaving the value on the stack
+    fn set_property(
+        &mut self,
+        object: Value,
+        key: PropertyKey,
+        value: Value,
+    ) -> Result<(), String> {
+        let name = match &key {
+            PropertyKey::String(name) => Some(*name),
+            PropertyKey::Symbol(_) => None,
+        };
+        if let Some(container) = as_trace(&object) {
+            self.heap.write_barrier(&container, slice::from_ref(&value));
+        }
+        // Own properties take precedence over accessors further up
+        let member = match &object {
+            Value::Array(array) => {
+                match key.as_index() {
+                    Some(index) => array.borrow_mut().set(index as usize, value.clone()),
+                    None if name.is_some_and(|name| to_str(name) == "length") => match value {
+                        Value::ValNumber(length)
+                            if length >= 0.0
+                                && length.fract() == 0.0
+                                && length < u32::MAX as f64 =>
+                        {
+                            array.borrow_mut().set_length(length as usize)
+                        }
+                        _ => return Err(String::from("RangeError: Invalid array length")),
+                    },
+                    // Arrays only keep their elements
+                    None => (),
+                }
+                self.push(value);
+                return Ok(());
+            }
+            Value::Object(_) => None,
+            Value::Instance(instance) => {
+                let instance = instance.borrow();
+                match instance.fields.get(&key) {
+                    Some(_) => None,
+                    None => name.and_then(|name| instance.class.borrow().find_member(name, false)),
+                }
+            }
+            Value::Class(class) => {
+                let class = class.borrow();
+                match class.fields.get(&key) {
+                    Some(_) => None,
+                    None => name.and_then(|name| class.find_member(name, true)),
+                }
+            }
+            Value::ValNull | Value::ValUndefined => {
+                return Err(format!(
+                    "TypeError: Cannot set properties of {} (setting '{}')",
+                    object,
+                    key_name(&key)
+                ))
+            }
+            // Primitives have nowhere to keep the property, so it's dropped
+            _ => {
+                self.push(value);
+                return Ok(());
+            }
+        };
 
-    fn unary_instruction(&mut self, opcode: &OpCode) -> Option<Value> {
-        match opcode {
-            OpCode::NEGATE => {
+        match member {
+            Some(Member::Accessor {
+                setter: Some(setter),
+                ..
+            }) => {
+                self.push(value.clone());
+                self.push(object);
+                self.push(value);
+                self.call_closure(setter, 1)?;
+                self.frame_mut().discard_result = true;
+            }
+            Some(Member::Accessor { setter: None, .. }) => {
+                let class = match &object {
+                    Value::Instance(instance) => instance.borrow().class.borrow().name,
+                    Value::Class(class) => class.borrow().name,
+                    _ => unreachable!(),
+                };
+                return Err(format!(
+                    "TypeError: Cannot set property {} of #<{}> which has only a getter",
+                    key_name(&key),
+                    to_str(class)
+                ));
+            }
+            _ => {
+                match &object {
+                    Value::Object(object) => object.borrow_mut().properties.set(key, value.clone()),
+                    Value::Instance(instance) => {
+                        instance.borrow_mut().fields.set(key, value.clone())
+                    }
+                    Value::Class(class) => class.borrow_mut().fields.set(key, value.clone()),
+                    _ => unreachable!(),
+                }
+                self.push(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pad missing arguments with undefined and drop or collect the extra ones,
+    /// returning how many slots the arguments now take
+    fn adjust_arguments(&mut self, closure: &Closure, arg_count: usize) -> usize {
+        let arity = closure.function.arity;
+        if arg_count < arity {
+            self.stack
+                .extend((arg_count..arity).map(|_| Value::ValUndefined));
+        }
+        let extra = self
+            .stack
+            .split_off(self.stack.len() - arg_count.saturating_sub(arity));
+        if closure.function.has_rest {
+            let rest = self.allocate(RefCell::new(Array::new(extra)));
+            self.push(Value::Array(rest));
+            arity + 1
+        } else {
+            arity
+        }
+    }
+
+    fn call(&mut self, closure: Rc<Closure>, arg_count: usize) {
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+            discard_result: false,
+        });
+    }
+
+    /// Reuse the open upvalue for `slot` if a closure already captured it
+    fn capture_upvalue(&mut self, slot: usize) -> MutRc<Upvalue> {
+        let position = self
+            .open_upvalues
+            .iter()
+            .rposition(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(open) if open <= slot));
+        if let Some(position) = position {
+            let upvalue = &self.open_upvalues[position];
+            if matches!(*upvalue.borrow(), Upvalue::Open(open) if open == slot) {
+                return upvalue.clone();
+            }
+        }
+
+        let upvalue = self.allocate(RefCell::new(Upvalue::Open(slot)));
+        let index = position.map_or(0, |position| position + 1);
+        self.open_upvalues.insert(index, upvalue.clone());
+        upvalue
+    }
+
+    /// Move every upvalue pointing at `last_slot` or above off the stack
+    fn close_upvalues(&mut self, last_slot: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) => slot,
+                Upvalue::Closed(_) => unreachable!("Closed upvalue left in the open list"),
+            };
+            if slot < last_slot {
+                break;
+            }
+            self.heap
+                .write_barrier(upvalue, slice::from_ref(&self.stack[slot]));
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot].clone());
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn runtime_error(&mut self, message: &str) {
+        // An error inside a callback run by a native has been reported already
+        if message.is_empty() {
+            return;
+        }
+        let code = ErrorCode::of_runtime_error(message);
+        // The failing instruction is the one last read in each frame
+        let frames = self.frames.iter().enumerate().rev().map(|(depth, frame)| {
+            let function = &frame.closure.function;
+            let position = function.chunk.positions.get(frame.ip.saturating_sub(1));
+            let name = match function.name {
+                Some(name) => to_str(name).to_string(),
+                None if depth == 0 => String::from("<script>"),
+                None => String::from("<anonymous>"),
+            };
+            TraceFrame {
+                function: name,
+                span: Span::from(position),
+                repeated: 0,
+            }
+        });
+        // Recursion repeats the same call, which is listed once
+        let mut stack_trace: Vec<TraceFrame> = Vec::new();
+        for frame in frames {
+            if let Some(last) = stack_trace.last_mut() {
+                if last.function == frame.function && last.span == frame.span {
+                    last.repeated += 1;
+                    continue;
+                }
+            }
+            if stack_trace.len() == TRACE_MAX {
+                break;
+            }
+            stack_trace.push(frame);
+        }
+        let span = stack_trace[0].span.clone();
+        let diagnostic = Diagnostic::error(code, message.to_string(), span);
+        self.error = Some(InterpretError::runtime(diagnostic, stack_trace));
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        // Verified code never takes more off the stack than it put there
+        self.stack.pop().expect("stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn unary_instruction(&mut self, instruction: u8) -> Option<Value> {
+        match instruction {
+            op::NEGATE => {
                 let operand = self.pop();
-                if !operand.same_type_as(&Value::ValNumber(0.1)) {
-                    return operand.neg();
+                if operand.same_type_as(&Value::ValNumber(0.1)) {
+                    operand.neg()
                 } else {
-                    panic!("Operand must be a number."); // runtime error!
+                    None
                 }
             }
-            OpCode::NOT => Some(self.pop().not()),
+            op::NOT => Some(self.pop().not()),
             _ => panic!("unknown opcode"),
         }
     }
 
-    fn binary_operation_values(&mut self, operation: &OpCode) -> Option<Value> {
-        let a = self.pop();
+    fn binary_operation_values(&mut self, operation: u8) -> Option<Value> {
         let b = self.pop();
+        let a = self.pop();
         match operation {
-            OpCode::ADD => Some(a.add(b)),
-            OpCode::SUBTRACT => a.sub(b),
-            OpCode::DIVIDE => a.div(b),
-            OpCode::MULTIPLY => a.mul(b),
-            OpCode::EQUAL => Some(Value::ValBool(a == b)),
-            OpCode::GREATER => a.greater(b),
-            OpCode::LESS => a.less(b),
+            op::ADD => a.add(b),
+            op::SUBTRACT => a.sub(b),
+            op::DIVIDE => a.div(b),
+            op::MULTIPLY => a.mul(b),
+            op::EQUAL => Some(Value::ValBool(a == b)),
+            op::GREATER => a.greater(b),
+            op::LESS => a.less(b),
             _ => panic!("unknown opcode"),
         }
     }
 }
+
+/// The key `name` is stored under on `object`, if `class`, the class declaring it, set
+/// the object up
+fn private_key(object: &Value, class: &Value, name: StrId) -> Option<StrId> {
+    match class {
+        Value::Class(class) if is_branded(object, class) => {
+            Some(class.borrow_mut().private_key(name))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `class` has set up `object`, so it holds the private names the class declared
+fn is_branded(object: &Value, class: &MutRc<Class>) -> bool {
+    match object {
+        Value::Instance(instance) => {
+            let mut current = Some(instance.borrow().class.clone());
+            while let Some(ancestor) = current {
+                if Rc::ptr_eq(&ancestor, class) {
+                    return true;
+                }
+                current = ancestor.borrow().superclass.clone();
+            }
+            false
+        }
+        Value::Class(object) => Rc::ptr_eq(object, class),
+        _ => false,
+    }
+}
+
+// Private keys carry the brand of their class after an '@'
+fn key_name(key: &PropertyKey) -> String {
+    match key {
+        PropertyKey::String(name) => {
+            let name = to_str(*name);
+            name.split('@').next().unwrap_or_default().to_string()
+        }
+        PropertyKey::Symbol(symbol) => symbol.to_string(),
+    }
+}
+
+/// Symbols are keys as they are, anything else is keyed by its string form
+fn to_property_key(value: &Value) -> PropertyKey {
+    match value {
+        Value::Symbol(symbol) => PropertyKey::Symbol(symbol.clone()),
+        Value::String(name) => PropertyKey::String(intern_string(name)),
+        value => PropertyKey::String(intern(value.to_string())),
+    }
+}
+
+fn native_value(name: &str, function: native::NativeFn) -> Value {
+    let name = intern(name);
+    Value::Native(Rc::new(Native { name, function }))
+}
+
+/// The enumerable own properties copied by a spread into an object literal
+fn own_properties(value: &Value) -> Vec<(PropertyKey, Value)> {
+    let public = |properties: &super::object::Properties| {
+        properties
+            .iter()
+            .filter(|(key, _)| !matches!(key, PropertyKey::String(name) if to_str(*name).starts_with('#')))
+            .cloned()
+            .collect()
+    };
+    match value {
+        Value::Object(object) => public(&object.borrow().properties),
+        Value::Instance(instance) => public(&instance.borrow().fields),
+        Value::Array(array) => array
+            .borrow()
+            .elements
+            .iter()
+            .enumerate()
+            .filter_map(|(index, element)| {
+                Some((intern(index.to_string()).into(), element.clone()?))
+            })
+            .collect(),
+        Value::String(string) => string
+            .chars()
+            .enumerate()
+            .map(|(index, c)| {
+                let c = Value::String(c.to_string().into());
+                (intern(index.to_string()).into(), c)
+            })
+            .collect(),
+        // Spreading null, undefined or other primitives adds nothing
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::language::chunk::Position;
+    use crate::language::common::OpCode;
+    use crate::language::diagnostic::Stage;
+    use crate::language::verifier::verify;
+
+    #[test]
+    fn collect_garbage_frees_unreachable_cycles() {
+        let mut vm = VM::new();
+        let source = "
+            function f() {
+                const a = [];
+                const b = [a];
+                a.push(b);
+                const o = {};
+                o.self = o;
+            }
+            f();
+        ";
+        vm.interpret(source).unwrap();
+        assert_eq!(vm.collect_garbage(), 3);
+        assert_eq!(vm.collect_garbage(), 0);
+    }
+
+    #[test]
+    fn collect_garbage_keeps_cycles_while_reachable() {
+        let mut vm = VM::new();
+        vm.interpret("let a = []; a.push(a);").unwrap();
+        assert_eq!(vm.collect_garbage(), 0);
+        vm.interpret("a = null;").unwrap();
+        assert_eq!(vm.collect_garbage(), 1);
+    }
+
+    #[test]
+    fn frees_long_lists_within_the_pause_budget() {
+        let mut vm = VM::new();
+        vm.set_gc_pause_budget(Duration::from_micros(500));
+        let source = "
+            function list(length) {
+                const head = {previous: null, next: null};
+                let node = head;
+                for (let i = 1; i < length; i = i + 1) {
+                    node.next = {previous: node, next: null};
+                    node = node.next;
+                }
+            }
+            for (let i = 0; i < 4; i = i + 1) {
+                list(20000);
+            }
+        ";
+        vm.interpret(source).unwrap();
+        let stats = vm.gc_stats();
+        assert!(stats.cycles > 0);
+        // Leaves room for a busy machine, but checking such a list in one go takes longer
+        assert!(stats.longest_pause < Duration::from_millis(50));
+        vm.collect_garbage();
+        assert_eq!(vm.gc_stats().objects_freed, 80000);
+    }
+
+    fn global(vm: &VM, name: &str) -> Value {
+        let _strings = interner::enter(&vm.strings);
+        vm.globals[&intern(name)].value.clone()
+    }
+
+    #[test]
+    fn binds_this_where_a_function_is_called() {
+        let mut vm = VM::new();
+        let source = "
+            let o = {v: 3, f: function() { return this; }};
+            let p = {v: 9, g: o.f};
+            let key = 'g';
+            const own = o.f();
+            const moved = p.g();
+            const indexed = p[key]();
+            const g = o.f;
+            const plain = g();
+            class C { m() { return this; } }
+            const c = new C();
+            const m = c.m;
+            const method = m();
+        ";
+        vm.interpret(source).unwrap();
+        assert_eq!(global(&vm, "own"), global(&vm, "o"));
+        assert_eq!(global(&vm, "moved"), global(&vm, "p"));
+        assert_eq!(global(&vm, "indexed"), global(&vm, "p"));
+        assert_eq!(global(&vm, "plain"), Value::ValUndefined);
+        // Class methods stay bound to the instance they were read from
+        assert_eq!(global(&vm, "method"), global(&vm, "c"));
+    }
+
+    #[test]
+    fn declares_only_the_globals_a_script_got_to_define() {
+        let mut vm = VM::new();
+        assert!(vm.interpret("print y; let x = 1;").is_err());
+        vm.compile("const z = 1;").unwrap();
+        vm.interpret("let x = 2; x = 3; let z = x;").unwrap();
+        assert_eq!(global(&vm, "x"), Value::ValNumber(3.0));
+        assert_eq!(global(&vm, "z"), Value::ValNumber(3.0));
+        let error = vm.interpret("let x = 4;").unwrap_err();
+        assert_eq!(
+            error.diagnostics[0].message,
+            "Cannot redeclare block-scoped variable 'x'."
+        );
+    }
+
+    #[test]
+    fn assigns_logically_to_properties_evaluating_them_once() {
+        let mut vm = VM::new();
+        let source = "
+            let reads = 0;
+            const o = {a: 0, b: null, k: 1};
+            function object() { reads = reads + 1; return o; }
+            function key() { reads = reads + 1; return 'k'; }
+            object().a ||= 5;
+            object().b ??= 6;
+            object()[key()] &&= 7;
+            object()[key()] ??= 8;
+            const a = o.a;
+            const b = o.b;
+            const k = o.k;
+        ";
+        vm.interpret(source).unwrap();
+        assert_eq!(global(&vm, "a"), Value::ValNumber(5.0));
+        assert_eq!(global(&vm, "b"), Value::ValNumber(6.0));
+        assert_eq!(global(&vm, "k"), Value::ValNumber(7.0));
+        assert_eq!(global(&vm, "reads"), Value::ValNumber(6.0));
+
+        let error = vm.interpret("print 1 || 2 ?? 3;").unwrap_err();
+        assert_eq!(
+            error.diagnostics[0].message,
+            "'||' and '??' operations cannot be mixed without parentheses."
+        );
+        assert!(vm.interpret("print 1 ?? 2 && 3;").is_err());
+        vm.interpret("print (1 || 2) ?? 3;").unwrap();
+    }
+
+    #[test]
+    fn brands_private_names_per_class_evaluation() {
+        let mut vm = VM::new();
+        let source = "
+            function make() {
+                class C {
+                    #x = 1;
+                    static get(o) { return o.#x; }
+                }
+                return C;
+            }
+            const C1 = make();
+            const C2 = make();
+            const x = C2.get(new C2());
+        ";
+        vm.interpret(source).unwrap();
+        assert_eq!(global(&vm, "x"), Value::ValNumber(1.0));
+        let error = vm.interpret("C2.get(new C1());").unwrap_err();
+        assert_eq!(
+            error.diagnostics[0].message,
+            "TypeError: Cannot read private member #x from an object whose class did not declare it"
+        );
+
+        let error = vm.interpret("class A { #x; #x; }").unwrap_err();
+        assert_eq!(error.diagnostics[0].message, "Duplicate identifier '#x'.");
+        vm.interpret("class B { get #y() { return 1; } set #y(y) {} }")
+            .unwrap();
+    }
+
+    #[test]
+    fn keeps_the_strings_of_scripts_not_run_yet() {
+        let mut vm = VM::new();
+        let script = vm.compile("print zz;").unwrap();
+        vm.interpret("const o = {}; for (let i = 0; i < 2000; i = i + 1) { o['k' + i] = i; }")
+            .unwrap();
+        vm.interpret("let yy = 7;").unwrap();
+        let error = vm.execute(script).unwrap_err();
+        assert_eq!(
+            error.diagnostics[0].message,
+            "ReferenceError: zz is not defined"
+        );
+    }
+
+    #[test]
+    fn hoists_the_declarations_of_nested_blocks() {
+        let mut vm = VM::new();
+        let source = "
+            function parity(n) {
+                if (n > 0) {
+                    { var found = isEven(n); }
+                }
+                function isEven(n) { return n == 0 ? true : isOdd(n - 1); }
+                function isOdd(n) { return n == 0 ? false : isEven(n - 1); }
+                return found;
+            }
+            const even = parity(7);
+        ";
+        vm.interpret(source).unwrap();
+        assert_eq!(global(&vm, "even"), Value::ValBool(false));
+
+        let depth = 200;
+        let mut source = String::from("let total = 0;");
+        for i in 0..depth {
+            source.push_str(&format!("{{ let a{} = {}; total = total + a{};", i, i, i));
+        }
+        source.push_str(&"}".repeat(depth));
+        vm.interpret(&source).unwrap();
+        assert_eq!(global(&vm, "total"), Value::ValNumber(19900.0));
+    }
+
+    #[test]
+    fn adds_as_numbers_unless_a_side_converts_to_a_string() {
+        let mut vm = VM::new();
+        let source = "
+            const a = 1 + undefined;
+            const b = null + 1;
+            const c = true + 1;
+            const d = true + 'x';
+            const e = [1, 2] + 1;
+            const f = {} + null;
+        ";
+        vm.interpret(source).unwrap();
+        assert!(matches!(global(&vm, "a"), Value::ValNumber(a) if a.is_nan()));
+        assert_eq!(global(&vm, "b"), Value::ValNumber(1.0));
+        assert_eq!(global(&vm, "c"), Value::ValNumber(2.0));
+        assert_eq!(global(&vm, "d"), Value::String("truex".into()));
+        assert_eq!(global(&vm, "e"), Value::String("1,21".into()));
+        assert_eq!(
+            global(&vm, "f"),
+            Value::String("[object Object]null".into())
+        );
+        assert!(vm.interpret("print Symbol() + 1;").is_err());
+    }
+
+    #[test]
+    fn reports_runtime_errors_with_the_calls_they_happened_in() {
+        let mut vm = VM::new();
+        let source = "function f(n) {
+  if (n == 0) return missing;
+  return f(n - 1);
+}
+f(3);";
+        let error = vm.interpret(source).unwrap_err();
+        assert_eq!(error.stage, Stage::Runtime);
+        assert_eq!(error.diagnostics.len(), 1);
+        let diagnostic = &error.diagnostics[0];
+        assert_eq!(diagnostic.code, ErrorCode::ReferenceError);
+        assert_eq!(diagnostic.message, "ReferenceError: missing is not defined");
+        assert_eq!((diagnostic.span.start, diagnostic.span.end), (37, 44));
+        let frames: Vec<(&str, u32, u32, usize)> = error
+            .stack_trace
+            .iter()
+            .map(|frame| {
+                let span = &frame.span;
+                (
+                    frame.function.as_str(),
+                    span.line,
+                    span.column,
+                    frame.repeated,
+                )
+            })
+            .collect();
+        // The recursive calls are made from the same place, so they're listed once
+        assert_eq!(
+            frames,
+            [("f", 2, 22, 0), ("f", 3, 11, 2), ("<script>", 5, 2, 0)]
+        );
+        assert_eq!(error.stack_trace[0].span, diagnostic.span);
+
+        let source = "
+            function ping(n) { return n == 0 ? null.x : pong(n - 1); }
+            function pong(n) { return ping(n); }
+            ping(100);
+        ";
+        let error = vm.interpret(source).unwrap_err();
+        assert_eq!(error.diagnostics[0].code, ErrorCode::TypeError);
+        assert_eq!(error.stack_trace.len(), TRACE_MAX);
+        assert!(error.stack_trace.iter().all(|frame| frame.repeated == 0));
+
+        let error = vm.interpret("let = 1;\nprint );").unwrap_err();
+        assert_eq!(error.stage, Stage::Compile);
+        assert!(error.stack_trace.is_empty());
+        let diagnostics: Vec<(ErrorCode, u32, &str)> = error
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.span.line, &*diagnostic.message))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                (ErrorCode::ExpectedToken, 1, "Expected variable name."),
+                (ErrorCode::InvalidSyntax, 2, "Expected expression.")
+            ]
+        );
+    }
+
+    // Code the verifier accepts, as it only counts values, but that has the wrong ones
+    fn run_code(code: &[OpCode]) -> String {
+        let position = Position {
+            line: 1,
+            column: 1,
+            start: 0,
+            length: 1,
+        };
+        let mut function = Function::new(None);
+        for instruction in code {
+            function.chunk.add_op_code(*instruction, position);
+        }
+        assert_eq!(verify(&function), Ok(()));
+        let error = VM::new().execute(Rc::new(function)).unwrap_err();
+        error.diagnostics[0].message.clone()
+    }
+
+    #[test]
+    fn reports_operands_of_the_wrong_kind() {
+        let code = [
+            OpCode::NULL,
+            OpCode::NULL,
+            OpCode::CALL_SPREAD,
+            OpCode::RETURN,
+        ];
+        assert_eq!(run_code(&code), "Spread arguments are not an array");
+        let code = [
+            OpCode::NULL,
+            OpCode::NULL,
+            OpCode::INHERIT,
+            OpCode::POP,
+            OpCode::RETURN,
+        ];
+        assert_eq!(run_code(&code), "Inheriting into a non-class");
+        let code = [
+            OpCode::NULL,
+            OpCode::NULL,
+            OpCode::NULL,
+            OpCode::INIT_PROPERTY,
+            OpCode::RETURN,
+        ];
+        assert_eq!(
+            run_code(&code),
+            "Property defined outside an object literal"
+        );
+    }
+}
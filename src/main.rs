@@ -5,55 +5,183 @@ mod language;
 use crate::language::chunk::Chunk;
 #[cfg(feature = "log_level_debug")]
 use crate::language::debug::Debug;
+use crate::language::diagnostic::{InterpretError, Stage};
+use crate::language::report;
 use crate::language::vm::VM;
 
 use crate::language::compiler::Compiler;
 
 use std::{
     env, fs,
-    io::{self, Write},
+    io::{self, IsTerminal, Write},
+    path::Path,
     process,
+    time::Duration,
 };
 
 // https://github.com/felipesabino/lox-rust/blob/master/src/main.rs
 fn main() {
     welcome_message();
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
-        _ => {
-            println!("Usage: cargo run [path]");
-            process::exit(64);
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+
+    let mut vm = VM::new();
+    let mut gc_stats = false;
+    let mut errors = Errors {
+        json: false,
+        color: io::stderr().is_terminal(),
+    };
+    for flag in flags.iter() {
+        match flag.split_once('=') {
+            None if flag == "--gc-stats" => gc_stats = true,
+            Some(("--gc-pause", micros)) => match micros.parse() {
+                Ok(micros) => vm.set_gc_pause_budget(Duration::from_micros(micros)),
+                Err(_) => usage(),
+            },
+            Some(("--error-format", "human")) => errors.json = false,
+            Some(("--error-format", "json")) => errors.json = true,
+            Some(("--color", "auto")) => errors.color = io::stderr().is_terminal(),
+            Some(("--color", "always")) => errors.color = true,
+            Some(("--color", "never")) => errors.color = false,
+            _ => usage(),
+        }
+    }
+
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => repl(vm, errors),
+        ["compile", path] => {
+            compile_file(vm, path, &Path::new(path).with_extension("tsbc"), errors)
         }
+        ["compile", path, output] => compile_file(vm, path, Path::new(output), errors),
+        ["run-bytecode", path] => run_bytecode(vm, path, gc_stats, errors),
+        [path] => run_file(vm, path, gc_stats, errors),
+        _ => usage(),
     }
 }
 
-fn repl() {
-    let mut vm = VM::new();
+fn usage() {
+    println!("Usage: cargo run [options] [path]");
+    println!("       cargo run [options] compile <path> [output.tsbc]");
+    println!("       cargo run [options] run-bytecode <path.tsbc>");
+    println!();
+    println!("Options: --gc-stats --gc-pause=<microseconds>");
+    println!("         --error-format=human|json --color=auto|always|never");
+    process::exit(64);
+}
+
+/// How errors are printed
+#[derive(Copy, Clone)]
+struct Errors {
+    json: bool,
+    color: bool,
+}
+
+impl Errors {
+    /// Print `error` to stderr, quoting `source` if it's the code the error is in
+    fn print(self, error: &InterpretError, source: Option<&str>) {
+        if self.json {
+            eprint!("{}", report::to_json(error));
+        } else {
+            eprint!("{}", report::render(error, source, self.color));
+        }
+    }
+}
+
+fn repl(mut vm: VM, errors: Errors) {
     let mut input = String::new();
     loop {
         print!("> ");
         io::stdout().flush().expect("Failed to flush stdout!");
 
+        input.clear();
         io::stdin()
             .read_line(&mut input)
             .expect("Failed to read line!");
 
-        vm.interpret(&input).ok();
+        if let Err(error) = vm.interpret(&input) {
+            errors.print(&error, Some(&input));
+        }
     }
 }
 
-fn run_file(path: &str) {
-    let mut vm = VM::new();
-    let file = fs::read_to_string(path);
-    match file {
-        Ok(input) => vm.interpret(&input).ok(),
-        Err(_) => {
-            println!("Failed to read file.");
-            process::exit(74);
+fn run_file(mut vm: VM, path: &str, gc_stats: bool, errors: Errors) {
+    let input = fs::read_to_string(path).unwrap_or_else(|_| {
+        println!("Failed to read file.");
+        process::exit(74);
+    });
+    let result = vm.interpret_file(path, &input);
+    finish(vm, result, Some(&input), gc_stats, errors);
+}
+
+fn compile_file(mut vm: VM, path: &str, output: &Path, errors: Errors) {
+    let input = fs::read_to_string(path).unwrap_or_else(|_| {
+        println!("Failed to read file.");
+        process::exit(74);
+    });
+    let function = vm.compile(&input).unwrap_or_else(|mut error| {
+        error.set_file(path);
+        errors.print(&error, Some(&input));
+        process::exit(65);
+    });
+    if fs::write(output, vm.serialize(&function)).is_err() {
+        println!("Failed to write {}.", output.display());
+        process::exit(74);
+    }
+}
+
+fn run_bytecode(mut vm: VM, path: &str, gc_stats: bool, errors: Errors) {
+    let bytes = fs::read(path).unwrap_or_else(|_| {
+        println!("Failed to read file.");
+        process::exit(74);
+    });
+    let function = vm.load(&bytes).unwrap_or_else(|error| {
+        println!("Failed to load {}: {}.", path, error);
+        process::exit(65);
+    });
+    let result = vm.execute(function);
+    finish(vm, result, None, gc_stats, errors);
+}
+
+/// Report the error the script stopped at, if any, exiting with the status for it
+fn finish(
+    mut vm: VM,
+    result: Result<(), InterpretError>,
+    source: Option<&str>,
+    gc_stats: bool,
+    errors: Errors,
+) {
+    if let Err(error) = &result {
+        errors.print(error, source);
+    }
+    if gc_stats {
+        vm.collect_garbage();
+        print_gc_stats(&vm);
+    }
+    if let Err(error) = result {
+        process::exit(match error.stage {
+            Stage::Compile => 65,
+            Stage::Runtime => 70,
+        });
+    }
+}
+
+fn print_gc_stats(vm: &VM) {
+    let stats = vm.gc_stats();
+    println!("== GC ==");
+    println!("cycles:          {}", stats.cycles);
+    println!("bytes allocated: {}", stats.bytes_allocated);
+    println!("bytes freed:     {}", stats.bytes_freed);
+    println!("objects freed:   {}", stats.objects_freed);
+    println!("longest pause:   {:?}", stats.longest_pause);
+    println!("pauses:");
+    let last = stats.pause_histogram.len() - 1;
+    for (bucket, count) in stats.pause_histogram.iter().enumerate() {
+        match *count {
+            0 => (),
+            count if bucket == last => println!(" >= {:>6}us  {}", 1u64 << (last - 1), count),
+            count => println!("  < {:>6}us  {}", 1u64 << bucket, count),
         }
-    };
+    }
 }
 
 fn welcome_message() {